@@ -0,0 +1,271 @@
+use crate::config::{self, Config, Routing};
+use crate::history::HISTORY;
+use crate::json;
+use crate::logger;
+use crate::summary::{health, read_head_line, request_reader, respond, respond_with, SUMMARY};
+use std::{
+    io::{self, prelude::*},
+    net::{IpAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+const MAX_BODY: usize = 4096;
+const RECENT_ERRORS: usize = 50;
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Answer the admin API on `config.admin`:
+///
+/// - `GET /` a dashboard using the API, it asks for the token itself
+/// - `GET /healthz` whether it is up, for probes without the token
+/// - `GET /connections` the open connections
+/// - `DELETE /connections/<id>` close one of them
+/// - `POST /capture/<id>` write what it relays to the capture file
+/// - `GET /errors` the connections that failed lately
+/// - `GET /stats` the statistics since startup
+/// - `GET /stats/text` the same as plain text, for `multi3 stats`
+/// - `GET /hosts` the statistics per destination host
+/// - `GET /groups` the statistics per group
+/// - `GET /binds` the statistics per source address
+/// - `GET /egress` the members of every `[pools]` alias for X-Multi3-Egress
+/// - `POST /egress/<name>` add the address in the body to an alias
+/// - `DELETE /egress/<name>/<ip>` remove an address from an alias
+/// - `GET /pools` the listeners and `pool` of every `[[routing]]`, in the
+///   order of the file
+/// - `POST /pools/<routing>/<ip>` add an address to the pool of the
+///   `[[routing]]` at that position, counted from 0
+/// - `DELETE /pools/<routing>/<ip>` remove an address from it
+///
+/// The aliases are all the egress endpoints change. The pool endpoints change
+/// the `pool` of a `[[routing]]` until the next reload, the pools of its rules
+/// come from the file only.
+/// - `POST /reload` reload the rules from `file_name`
+pub fn serve(config: &'static Config, file_name: &'static Path, routings: Vec<Arc<Routing>>) {
+    let Some(admin) = &config.admin else {
+        return;
+    };
+    let listener = match TcpListener::bind(admin.addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            logger::log(
+                logger::Level::Error,
+                "failed to bind admin",
+                &[("addr", &admin.addr), ("error", &e)],
+            );
+            return;
+        }
+    };
+    let routings: Arc<[Arc<Routing>]> = routings.into();
+    for stream in listener.incoming().flatten() {
+        let routings = routings.clone();
+        thread::spawn(move || answer(stream, config, file_name, &routings));
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: String,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = request_reader(stream)?;
+    let mut line = String::new();
+    read_head_line(&mut reader, &mut line)?;
+    let mut parts = line.split_ascii_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut token = None;
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if read_head_line(&mut reader, &mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Authorization") {
+            token = value.strip_prefix("Bearer ").map(str::to_owned);
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            length = value.parse().unwrap_or(0);
+        }
+    }
+    reader.get_mut().set_limit(MAX_BODY as u64);
+    let mut body = vec![0u8; length.min(MAX_BODY)];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        token,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Takes as long wherever they differ, so the token can't be found byte by byte.
+fn same_token(given: Option<&str>, token: &str) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    let diff = given
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    std::hint::black_box(diff) == 0 && given.len() == token.len()
+}
+
+fn error(message: &str) -> String {
+    format!("{{{}:{}}}", json::string("error"), json::string(message))
+}
+
+/// `items` as a JSON array of strings.
+fn strings(items: &[impl std::fmt::Display]) -> String {
+    let items: Vec<_> = items.iter().map(|x| json::string(&x.to_string())).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn answer(
+    mut stream: TcpStream,
+    config: &Config,
+    file_name: &Path,
+    routings: &[Arc<Routing>],
+) -> io::Result<()> {
+    let request = read_request(&stream)?;
+    if request.method == "GET" && request.path == "/" {
+        return respond_with(&mut stream, "200 OK", "text/html; charset=utf-8", DASHBOARD);
+    }
+    if request.method == "GET" && request.path == "/healthz" {
+        let (status, body) = health();
+        return respond(&mut stream, status, &body);
+    }
+    let admin = config.admin.as_ref().unwrap();
+    if !same_token(request.token.as_deref(), &admin.token) {
+        return respond(&mut stream, "401 Unauthorized", &error("bad token"));
+    }
+    let path: Vec<_> = request.path.trim_matches('/').split('/').collect();
+    let (status, body) = match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["connections"]) => ("200 OK", HISTORY.lock().unwrap().open_json()),
+        ("DELETE", ["connections", id]) => match id.parse() {
+            Ok(id) if crate::handle::kill(id) => {
+                logger::info("killed connection", &[("id", &id)]);
+                ("200 OK", "{}".to_owned())
+            }
+            _ => ("404 Not Found", error("no such connection")),
+        },
+        ("GET", ["errors"]) => ("200 OK", HISTORY.lock().unwrap().errors_json(RECENT_ERRORS)),
+        ("POST", ["capture", id]) => match id.parse() {
+            Ok(id) if crate::handle::start_capture(id) => {
+                logger::info("capturing connection", &[("id", &id)]);
+                ("200 OK", "{}".to_owned())
+            }
+            _ => (
+                "404 Not Found",
+                error("no such connection or no capture file"),
+            ),
+        },
+        ("GET", ["stats"]) => ("200 OK", SUMMARY.lock().unwrap().to_json()),
+        ("GET", ["stats", "text"]) => {
+            let text = SUMMARY.lock().unwrap().to_text();
+            return respond_with(&mut stream, "200 OK", "text/plain; charset=utf-8", &text);
+        }
+        ("GET", ["hosts"]) => ("200 OK", SUMMARY.lock().unwrap().hosts_json()),
+        ("GET", ["groups"]) => ("200 OK", SUMMARY.lock().unwrap().groups_json()),
+        ("GET", ["binds"]) => ("200 OK", SUMMARY.lock().unwrap().binds_json()),
+        ("GET", ["egress"]) => {
+            let pools: Vec<_> = config
+                .pools
+                .iter()
+                .map(|(name, pool)| {
+                    let members: Vec<_> = pool
+                        .members()
+                        .iter()
+                        .map(|ip| json::string(&ip.to_string()))
+                        .collect();
+                    format!("{}:[{}]", json::string(name), members.join(","))
+                })
+                .collect();
+            ("200 OK", format!("{{{}}}", pools.join(",")))
+        }
+        (method @ ("POST" | "DELETE"), ["egress", name, ip @ ..]) => {
+            let ip = match (method, ip) {
+                ("POST", []) => request.body.trim(),
+                ("DELETE", [ip]) => ip,
+                _ => "",
+            };
+            match (config.pools.get(*name), ip.parse::<IpAddr>()) {
+                (None, _) => ("404 Not Found", error("no such egress alias")),
+                (Some(_), Err(_)) => ("400 Bad Request", error("bad address")),
+                (Some(pool), Ok(ip)) => {
+                    let changed = match method {
+                        "POST" => pool.insert(ip),
+                        _ => pool.remove(ip),
+                    };
+                    if changed {
+                        logger::info("changed egress alias", &[("alias", name), ("ip", &ip)]);
+                    }
+                    (
+                        "200 OK",
+                        format!("{{{}:{}}}", json::string("changed"), changed),
+                    )
+                }
+            }
+        }
+        ("GET", ["pools"]) => {
+            let pools: Vec<_> = routings
+                .iter()
+                .map(|routing| {
+                    let members = routing
+                        .pool()
+                        .map(|pool| pool.members())
+                        .unwrap_or_default();
+                    format!(
+                        "{{{}:{},{}:{}}}",
+                        json::string("host"),
+                        strings(&routing.host),
+                        json::string("pool"),
+                        strings(&members),
+                    )
+                })
+                .collect();
+            ("200 OK", format!("[{}]", pools.join(",")))
+        }
+        (method @ ("POST" | "DELETE"), ["pools", routing, ip]) => {
+            let pool = routing
+                .parse::<usize>()
+                .ok()
+                .and_then(|x| routings.get(x))
+                .and_then(|x| x.pool());
+            match (pool, ip.parse::<IpAddr>()) {
+                (None, _) => ("404 Not Found", error("no such routing")),
+                (Some(_), Err(_)) => ("400 Bad Request", error("bad address")),
+                (Some(pool), Ok(ip)) => {
+                    let changed = match method {
+                        "POST" => pool.insert(ip),
+                        _ => pool.remove(ip),
+                    };
+                    if changed {
+                        logger::info("changed pool", &[("routing", routing), ("ip", &ip)]);
+                    }
+                    (
+                        "200 OK",
+                        format!("{{{}:{}}}", json::string("changed"), changed),
+                    )
+                }
+            }
+        }
+        ("POST", ["reload"]) => match config::reload_rules(file_name, routings) {
+            Ok(()) => {
+                logger::info("reloaded rules", &[("file", &file_name.display())]);
+                ("200 OK", "{}".to_owned())
+            }
+            Err(e) => ("500 Internal Server Error", error(&e.to_string())),
+        },
+        ("GET" | "POST" | "DELETE", _) => ("404 Not Found", error("not found")),
+        _ => ("405 Method Not Allowed", error("method not allowed")),
+    };
+    respond(&mut stream, status, &body)
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// pcapng blocks, see https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-05.html
+const SECTION_HEADER: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+/// packets start with the IP header
+const LINKTYPE_RAW: u16 = 101;
+
+const SYN: u8 = 0x02;
+const FIN: u8 = 0x01;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+static FLOWS: Mutex<BTreeMap<usize, Flow>> = Mutex::new(BTreeMap::new());
+/// so the relays don't take the lock when nothing is captured
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// One connection as a made up TCP stream between the client and the remote.
+struct Flow {
+    client: SocketAddr,
+    remote: SocketAddr,
+    /// the next sequence number of the client and of the remote
+    seq: [u32; 2],
+}
+impl Flow {
+    /// An IP packet with a TCP segment carrying `data`, checksums are left out.
+    fn packet(&mut self, up: bool, flags: u8, data: &[u8]) -> Vec<u8> {
+        let (from, to, i) = match up {
+            true => (self.client, self.remote, 0),
+            false => (self.remote, self.client, 1),
+        };
+        let mut tcp = Vec::with_capacity(20 + data.len());
+        tcp.extend(from.port().to_be_bytes());
+        tcp.extend(to.port().to_be_bytes());
+        tcp.extend(self.seq[i].to_be_bytes());
+        tcp.extend(self.seq[1 - i].to_be_bytes());
+        tcp.extend([5 << 4, flags]);
+        tcp.extend(u16::MAX.to_be_bytes());
+        tcp.extend([0; 4]);
+        tcp.extend(data);
+        // SYN and FIN count as one byte
+        let len = data.len() as u32 + (flags & (SYN | FIN) != 0) as u32;
+        self.seq[i] = self.seq[i].wrapping_add(len);
+
+        let mut ip = Vec::with_capacity(40 + tcp.len());
+        match (from.ip(), to.ip()) {
+            (IpAddr::V4(from), IpAddr::V4(to)) => {
+                ip.extend([0x45, 0]);
+                ip.extend((20 + tcp.len() as u16).to_be_bytes());
+                ip.extend([0, 0, 0x40, 0, 64, 6, 0, 0]);
+                ip.extend(from.octets());
+                ip.extend(to.octets());
+                let sum = checksum(&ip);
+                ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            (from, to) => {
+                let v6 = |ip: IpAddr| match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                ip.extend([0x60, 0, 0, 0]);
+                ip.extend((tcp.len() as u16).to_be_bytes());
+                ip.extend([6, 64]);
+                ip.extend(v6(from).octets());
+                ip.extend(v6(to).octets());
+            }
+        }
+        ip.extend(tcp);
+        ip
+    }
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend(kind.to_le_bytes());
+    block.extend(len.to_le_bytes());
+    block.extend(body);
+    block.extend(std::iter::repeat_n(0, padding));
+    block.extend(len.to_le_bytes());
+    block
+}
+
+fn write(packet: &[u8]) {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend(0u32.to_le_bytes());
+    body.extend(((micros >> 32) as u32).to_le_bytes());
+    body.extend((micros as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend(packet);
+    if let Some(file) = FILE.lock().unwrap().as_mut() {
+        let _ = file.write_all(&block(ENHANCED_PACKET, &body));
+    }
+}
+
+/// Start a new capture file, connections are only captured after this.
+pub fn open(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut section = Vec::new();
+    section.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+    section.extend(1u16.to_le_bytes());
+    section.extend(0u16.to_le_bytes());
+    // the section length isn't known
+    section.extend((-1i64).to_le_bytes());
+    file.write_all(&block(SECTION_HEADER, &section))?;
+    let mut interface = Vec::new();
+    interface.extend(LINKTYPE_RAW.to_le_bytes());
+    interface.extend(0u16.to_le_bytes());
+    interface.extend(0u32.to_le_bytes());
+    file.write_all(&block(INTERFACE_DESCRIPTION, &interface))?;
+    *FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Capture connection `id` from now on, false without a capture file.
+pub fn start(id: usize, client: SocketAddr, remote: SocketAddr) -> bool {
+    if FILE.lock().unwrap().is_none() {
+        return false;
+    }
+    let mut flows = FLOWS.lock().unwrap();
+    if flows.contains_key(&id) {
+        return true;
+    }
+    let mut flow = Flow {
+        client,
+        remote,
+        seq: [1, 1],
+    };
+    write(&flow.packet(true, SYN, &[]));
+    write(&flow.packet(false, SYN | ACK, &[]));
+    write(&flow.packet(true, ACK, &[]));
+    flows.insert(id, flow);
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Whether connection `id` is captured, its relay has to look at the bytes then.
+pub fn capturing(id: usize) -> bool {
+    ACTIVE.load(Ordering::Relaxed) != 0 && FLOWS.lock().unwrap().contains_key(&id)
+}
+
+/// Bytes relayed by connection `id`, `up` from the client to the remote.
+pub fn record(id: usize, up: bool, data: &[u8]) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Some(flow) = FLOWS.lock().unwrap().get_mut(&id) {
+        write(&flow.packet(up, PSH | ACK, data));
+    }
+}
+
+pub fn stop(id: usize) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Some(mut flow) = FLOWS.lock().unwrap().remove(&id) {
+        write(&flow.packet(true, FIN | ACK, &[]));
+        write(&flow.packet(false, FIN | ACK, &[]));
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
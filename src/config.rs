@@ -1,45 +1,375 @@
-use crate::Result;
+use crate::geoip::{self, GeoIp};
+use crate::http;
+use crate::logger;
+use crate::matcher::{Cidr, DomainMatcher};
+use crate::systemd;
+use crate::upstream::Upstreams;
+use crate::{Error, Result};
 use std::{
+    collections::{HashMap, HashSet},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Mutex,
-    time::Duration,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 // pub type Error = Box<dyn std::error::Error>;
 
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct Routing {
     pub host: Box<[SocketAddr]>,
-    pub pool: IpPool,
+    rules: Mutex<Arc<Rules>>,
+}
+impl Routing {
+    /// A consistent snapshot of the rules, a reload won't change it.
+    pub fn rules(&self) -> Arc<Rules> {
+        self.rules.lock().unwrap().clone()
+    }
+    pub fn replace_rules(&self, rules: Rules) {
+        *self.rules.lock().unwrap() = Arc::new(rules);
+    }
+    /// The `pool` of the `[[routing]]`, what connections no rule matches bind to.
+    pub fn pool(&self) -> Option<Arc<IpPool>> {
+        match &self.rules().default {
+            Action::Pool(pool) => Some(pool.clone()),
+            _ => None,
+        }
+    }
+}
+
+pub struct Rules {
+    pub default: Action,
+    pub rule: Box<[Rule]>,
+    /// when they were read from the file
+    pub loaded: SystemTime,
+}
+impl Rules {
+    pub fn pools(&self) -> impl Iterator<Item = &IpPool> {
+        std::iter::once(&self.default)
+            .chain(self.rule.iter().map(|r| &r.action))
+            .filter_map(|a| match a {
+                Action::Pool(pool) => Some(&**pool),
+                _ => None,
+            })
+    }
+
+    /// The first rule matching the connection, `Some(None)` for the default.
+    /// `None` while the remote address isn't known and a rule looking at it
+    /// comes before the one that matches.
+    pub fn matching(&self, geoip: &GeoIp, target: &Target) -> Option<Option<&Rule>> {
+        if self.rule.is_empty() {
+            return Some(None);
+        }
+        let info = target.remote.map(|ip| geoip.lookup(ip)).unwrap_or_default();
+        for rule in self.rule.iter() {
+            if rule.matches(target, &info)? {
+                return Some(Some(rule));
+            }
+        }
+        Some(None)
+    }
+}
+
+pub enum Action {
+    /// bind to the next address of the pool
+    Pool(Arc<IpPool>),
+    /// leave the source address to the OS
+    Direct,
+    /// tunnel through the first reachable proxy, or go direct if none is
+    Upstream(Upstreams),
+}
+
+/// What the rules get to look at for one connect attempt.
+pub struct Target<'a> {
+    pub client: IpAddr,
+    pub listener: SocketAddr,
+    pub host: &'a str,
+    pub port: u16,
+    /// none before the name is resolved
+    pub remote: Option<IpAddr>,
+}
+
+pub struct Rule {
+    pub client: Box<[Cidr]>,
+    pub listener: Box<[SocketAddr]>,
+    pub remote: Box<[Cidr]>,
+    pub domain: DomainMatcher,
+    pub port: Box<[RangeInclusive<u16>]>,
+    pub country: Box<[String]>,
+    pub asn: Box<[u32]>,
+    pub action: Action,
+    /// write the relayed bytes to the capture file
+    pub capture: bool,
+    /// write the events to a file of their own in the connection log directory
+    pub log: bool,
+}
+impl Rule {
+    /// None when it depends on the remote address and that isn't known yet.
+    fn matches(&self, target: &Target, info: &geoip::Info) -> Option<bool> {
+        let client =
+            self.client.is_empty() || self.client.iter().any(|c| c.contains(target.client));
+        let listener = listener_matches(&self.listener, target.listener);
+        let domain = self.domain.is_empty() || self.domain.matches(target.host);
+        let port = self.port.is_empty() || self.port.iter().any(|r| r.contains(&target.port));
+        if !(client && listener && domain && port) {
+            return Some(false);
+        }
+        let ip = match target.remote {
+            None if self.remote.is_empty() && self.country.is_empty() && self.asn.is_empty() => {
+                return Some(true)
+            }
+            None => return None,
+            Some(ip) => ip,
+        };
+        let remote = self.remote.is_empty() || self.remote.iter().any(|c| c.contains(ip));
+        let country = self.country.is_empty()
+            || info
+                .country
+                .as_ref()
+                .is_some_and(|c| self.country.iter().any(|x| x.eq_ignore_ascii_case(c)));
+        let asn = self.asn.is_empty() || info.asn.is_some_and(|a| self.asn.contains(&a));
+        Some(remote && country && asn)
+    }
+}
+
+/// A listener on 0.0.0.0 accepts on every address, compare the port only.
+fn listener_matches(listeners: &[SocketAddr], listener: SocketAddr) -> bool {
+    listeners.is_empty()
+        || listeners.iter().any(|l| {
+            l.port() == listener.port() && (l.ip().is_unspecified() || l.ip() == listener.ip())
+        })
+}
+
+/// Traffic is also counted per group, a connection belongs to the first matching one.
+pub struct Group {
+    pub name: String,
+    pub client: Box<[Cidr]>,
+    pub listener: Box<[SocketAddr]>,
+}
+impl Group {
+    fn matches(&self, client: IpAddr, listener: SocketAddr) -> bool {
+        (self.client.is_empty() || self.client.iter().any(|c| c.contains(client)))
+            && listener_matches(&self.listener, listener)
+    }
 }
 
 pub struct Config {
     pub connect_ttl: Duration,
+    /// no address is tried after one timed out past it
     pub retry_ttl: Duration,
+    /// all attempts together, unbounded unless set
+    pub total_ttl: Option<Duration>,
     pub io_ttl: Duration,
+    /// how long the open connections may take to finish when shutting down
+    pub drain_ttl: Duration,
+    /// how long resolved names are kept for the next connections whatever the
+    /// TTL of their records, zero (the default) for not at all
+    pub dns_cache: Duration,
+    /// how long remote connections of plain http wait idle for the next
+    /// request to the same place, zero (the default) for not at all
+    pub keep_alive: Duration,
     pub ipv6_first: Option<bool>,
     pub tui: bool,
+    /// between two frames of the tui
+    pub tui_refresh: Duration,
+    /// how long finished connections stay on screen
+    pub tui_keep: Duration,
+    /// the window of the moving average of the rates, zero for none
+    pub tui_smoothing: Duration,
+    /// None unless the file picks one
+    pub tui_theme: Option<Theme>,
+    pub output: Output,
+    pub geoip: GeoIp,
+    pub watch: bool,
+    pub pools: HashMap<String, Arc<IpPool>>,
+    pub egress_header: bool,
+    pub egress_token: Option<String>,
+    pub summary: Option<SocketAddr>,
+    pub admin: Option<Admin>,
+    pub otel: Option<Otel>,
+    pub webhook: Option<Webhook>,
+    pub log_format: logger::Format,
+    pub log_sink: LogSink,
+    /// directory of the files of the rules with `log = true`
+    pub connection_log: Option<std::path::PathBuf>,
+    /// also log the bytes they relay per frame
+    pub connection_log_reads: bool,
+    pub history_file: Option<std::path::PathBuf>,
+    pub history_database: Option<std::path::PathBuf>,
+    /// finished connections kept in memory for the export and the errors
+    pub history_keep: usize,
+    /// pcapng file for the connections to capture
+    pub capture_file: Option<std::path::PathBuf>,
+    /// where the statistics are kept across restarts
+    pub stats_file: Option<std::path::PathBuf>,
+    pub stats_interval: Duration,
+    pub groups: Box<[Group]>,
+    /// handle the connections on a fixed number of threads, else one thread each
+    pub workers: Option<Workers>,
+    /// listening sockets per address, each with its own accepting thread
+    pub acceptors: usize,
+    /// connections handled at once, more are answered with 503
+    pub max_connections: Option<usize>,
+    /// TCP Fast Open on the listeners and the direct connections
+    pub fast_open: bool,
+    pub socket_buffers: SocketBuffers,
+    /// fork to the background at start, there is no tui then
+    pub daemon: bool,
+    /// where the process id is written, removed again on exit
+    pub pidfile: Option<std::path::PathBuf>,
+}
+/// The admin listener, every request needs `Authorization: Bearer <token>`.
+pub struct Admin {
+    pub addr: SocketAddr,
+    pub token: String,
+}
+/// SO_SNDBUF and SO_RCVBUF of both ends of the relays, else what the kernel picks.
+pub struct SocketBuffers {
+    pub send: Option<usize>,
+    pub recv: Option<usize>,
+}
+impl SocketBuffers {
+    pub fn apply(&self, socket: socket2::SockRef) -> std::io::Result<()> {
+        if let Some(size) = self.send {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+/// The threads handling the connections and how many accepted ones may
+/// wait for them before accepting blocks.
+pub struct Workers {
+    pub threads: usize,
+    pub queue: usize,
+}
+/// An OpenTelemetry collector taking OTLP over plain http.
+pub struct Otel {
+    /// below it are `/v1/traces` and `/v1/metrics`
+    pub endpoint: http::Url,
+    pub interval: Duration,
+}
+/// Where to POST when `errors` happen within `window`, or an upstream
+/// failed `upstream_failures` times in a row.
+pub struct Webhook {
+    pub url: http::Url,
+    pub errors: usize,
+    pub window: Duration,
+    pub upstream_failures: u32,
+}
+/// What goes to stdout without the tui.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Output {
+    /// the log
+    Text,
+    /// every event as a JSON object per line, the log goes to stderr
+    Jsonl,
+    /// a line per finished connection and the totals now and then,
+    /// the log goes to stderr
+    Console(Duration),
+}
+/// How the tui looks.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Theme {
+    Default,
+    /// colors but no emoji, for fonts without them
+    Ascii,
+    /// neither colors nor emoji
+    Mono,
+}
+pub enum LogSink {
+    Stdout,
+    File(LogFile),
+    Syslog,
+    Journald,
+    /// the Windows event log
+    EventLog,
+}
+pub struct LogFile {
+    pub path: std::path::PathBuf,
+    pub max_size: u64,
+    pub rotate: logger::Rotate,
+    pub keep: usize,
+}
+impl Config {
+    pub fn lookup_group(&self, client: IpAddr, listener: SocketAddr) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|g| g.matches(client, listener))
+            .map(|g| g.name.as_str())
+    }
+
+    /// Resolve an `X-Multi3-Egress` value: a pool alias, or an address
+    /// that is already in one of the configured pools.
+    pub fn egress_pool(&self, name: &str, rules: &Rules) -> Option<Arc<IpPool>> {
+        if let Some(pool) = self.pools.get(name) {
+            return Some(pool.clone());
+        }
+        let ip: IpAddr = name.parse().ok()?;
+        let known =
+            self.pools.values().any(|p| p.contains(ip)) || rules.pools().any(|p| p.contains(ip));
+        known.then(|| Arc::new(IpPool::new(vec![ip])))
+    }
 }
 pub struct Pool<T: Clone> {
-    pool: Box<[T]>,
-    index: Mutex<usize>,
+    /// written only when addresses are added or removed
+    pool: RwLock<Vec<T>>,
+    index: AtomicUsize,
 }
 impl<T: Clone> Pool<T> {
     pub fn new(pool: Box<[T]>) -> Self {
         Self {
-            pool,
-            index: Mutex::new(0),
+            pool: RwLock::new(pool.into_vec()),
+            index: AtomicUsize::new(0),
         }
     }
+    pub fn contains(&self, item: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        self.pool.read().unwrap().contains(item)
+    }
+    pub fn len(&self) -> usize {
+        self.pool.read().unwrap().len()
+    }
     pub fn next(&self) -> Option<T> {
-        if self.pool.is_empty() {
+        let pool = self.pool.read().unwrap();
+        if pool.is_empty() {
             return None;
         }
-        let mut index = self.index.lock().unwrap();
-        let item = unsafe { self.pool.get_unchecked(*index) };
-        *index += 1;
-        if *index >= self.pool.len() {
-            *index = 0;
+        // taken modulo the current length, the pool may have changed since
+        let index = self.index.fetch_add(1, Ordering::Relaxed);
+        Some(pool[index % pool.len()].to_owned())
+    }
+    pub fn items(&self) -> Vec<T> {
+        self.pool.read().unwrap().clone()
+    }
+    /// Add `item` unless it is already there.
+    pub fn insert(&self, item: T) -> bool
+    where
+        T: PartialEq,
+    {
+        let mut pool = self.pool.write().unwrap();
+        if pool.contains(&item) {
+            return false;
         }
-        Some(item.to_owned())
+        pool.push(item);
+        true
+    }
+    pub fn remove(&self, item: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        let mut pool = self.pool.write().unwrap();
+        let len = pool.len();
+        pool.retain(|x| x != item);
+        pool.len() != len
     }
 }
 pub struct IpPool {
@@ -47,6 +377,29 @@ pub struct IpPool {
     pub pool_v6: Pool<Ipv6Addr>,
 }
 impl IpPool {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.pool_v4.contains(&ip),
+            IpAddr::V6(ip) => self.pool_v6.contains(&ip),
+        }
+    }
+    pub fn members(&self) -> Vec<IpAddr> {
+        let v4 = self.pool_v4.items().into_iter().map(IpAddr::V4);
+        let v6 = self.pool_v6.items().into_iter().map(IpAddr::V6);
+        v4.chain(v6).collect()
+    }
+    pub fn insert(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.pool_v4.insert(ip),
+            IpAddr::V6(ip) => self.pool_v6.insert(ip),
+        }
+    }
+    pub fn remove(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.pool_v4.remove(&ip),
+            IpAddr::V6(ip) => self.pool_v6.remove(&ip),
+        }
+    }
     pub fn new(pool: Vec<IpAddr>) -> Self {
         let mut v4 = Vec::new();
         let mut v6 = Vec::new();
@@ -63,37 +416,405 @@ impl IpPool {
     }
 }
 
-pub fn read_config(file_name: &str) -> Result<(Config, Vec<Routing>)> {
+fn read_file(file_name: &Path) -> Result<toml_file::Config> {
     use std::{fs::File, io::prelude::*};
     let mut buf = String::new();
     let _ = File::open(file_name)?.read_to_string(&mut buf)?;
-    let res: toml_file::Config = toml::from_str(&buf)?;
+    Ok(toml::from_str(&buf)?)
+}
+
+pub fn read_config(file_name: &Path) -> Result<(Config, Vec<Routing>)> {
+    let res = read_file(file_name)?;
     let config = Config {
         connect_ttl: Duration::from_millis(res.timeout.connect),
         retry_ttl: Duration::from_millis(res.timeout.retry),
+        total_ttl: res.timeout.total.map(Duration::from_millis),
         io_ttl: Duration::from_millis(res.timeout.io),
+        drain_ttl: Duration::from_millis(res.timeout.drain.unwrap_or(10000)),
+        dns_cache: Duration::from_millis(res.timeout.dns_cache.unwrap_or(0)),
+        keep_alive: Duration::from_millis(res.timeout.keep_alive.unwrap_or(0)),
         ipv6_first: res.ipv6_first,
-        tui: res.tui,
+        tui: res.tui && !res.daemon,
+        tui_refresh: Duration::from_millis(res.tui_refresh.unwrap_or(200)),
+        tui_keep: Duration::from_millis(res.tui_keep.unwrap_or(2000)),
+        tui_smoothing: Duration::from_millis(res.tui_smoothing.unwrap_or(1000)),
+        tui_theme: match res.tui_theme.as_deref() {
+            None => None,
+            Some("default") => Some(Theme::Default),
+            Some("ascii") => Some(Theme::Ascii),
+            Some("mono") => Some(Theme::Mono),
+            Some(x) => return Err(Error::ConfigError(format!("unknown tui theme {}", x))),
+        },
+        output: match res.output.as_deref() {
+            None | Some("text") => Output::Text,
+            Some("jsonl") if res.tui => {
+                return Err(Error::ConfigError("output jsonl needs tui = false".into()))
+            }
+            Some("jsonl") => Output::Jsonl,
+            Some("console") if res.tui => {
+                return Err(Error::ConfigError(
+                    "output console needs tui = false".into(),
+                ))
+            }
+            Some("console") => {
+                Output::Console(Duration::from_millis(res.console_interval.unwrap_or(60000)))
+            }
+            Some(x) => return Err(Error::ConfigError(format!("unknown output {}", x))),
+        },
+        geoip: GeoIp::open(&res.geoip)?,
+        watch: res.watch,
+        pools: res
+            .pools
+            .into_iter()
+            .map(|(name, pool)| (name, Arc::new(IpPool::new(pool))))
+            .collect(),
+        egress_header: res.egress_header,
+        egress_token: res.egress_token,
+        summary: res.summary,
+        admin: res.admin.map(|x| Admin {
+            addr: x.addr,
+            token: x.token,
+        }),
+        otel: match res.otel {
+            None => None,
+            Some(x) => Some(Otel {
+                endpoint: x.endpoint.parse()?,
+                interval: Duration::from_millis(x.interval.unwrap_or(10000)),
+            }),
+        },
+        acceptors: match res.acceptors {
+            None => 1,
+            Some(0) => return Err(Error::ConfigError("acceptors must be at least 1".into())),
+            Some(x) if x > 1 && cfg!(not(unix)) => {
+                return Err(Error::ConfigError(
+                    "acceptors need SO_REUSEPORT, only on unix".into(),
+                ))
+            }
+            Some(x) => x,
+        },
+        max_connections: res.max_connections,
+        socket_buffers: SocketBuffers {
+            send: res.socket.send_buffer,
+            recv: res.socket.recv_buffer,
+        },
+        daemon: match res.daemon {
+            true if cfg!(not(unix)) => {
+                return Err(Error::ConfigError("daemon is only on unix".into()))
+            }
+            x => x,
+        },
+        pidfile: res.pidfile.map(Into::into),
+        fast_open: match res.fast_open {
+            true if cfg!(not(target_os = "linux")) => {
+                return Err(Error::ConfigError("fast_open is only on linux".into()))
+            }
+            x => x,
+        },
+        workers: match res.workers {
+            None => None,
+            Some(x) if x.threads == 0 => {
+                return Err(Error::ConfigError(
+                    "workers need at least one thread".into(),
+                ))
+            }
+            Some(x) => Some(Workers {
+                threads: x.threads,
+                queue: x.queue.unwrap_or(1024),
+            }),
+        },
+        webhook: match res.webhook {
+            None => None,
+            Some(x) => Some(Webhook {
+                url: x.url.parse()?,
+                errors: x.errors.unwrap_or(10),
+                window: Duration::from_millis(x.window.unwrap_or(60000)),
+                upstream_failures: x.upstream_failures.unwrap_or(3),
+            }),
+        },
+        log_format: match res.log.format.as_deref() {
+            None | Some("text") => logger::Format::Text,
+            Some("json") => logger::Format::Json,
+            Some(x) => return Err(Error::ConfigError(format!("unknown log format {}", x))),
+        },
+        log_sink: match (res.log.sink.as_deref(), res.log.file) {
+            (None | Some("stdout"), None) => LogSink::Stdout,
+            (None | Some("file"), Some(path)) => LogSink::File(LogFile {
+                path: path.into(),
+                max_size: res.log.max_size.unwrap_or(0),
+                rotate: match res.log.rotate.as_deref() {
+                    None | Some("never") => logger::Rotate::Never,
+                    Some("hourly") => logger::Rotate::Hourly,
+                    Some("daily") => logger::Rotate::Daily,
+                    Some(x) => return Err(Error::ConfigError(format!("unknown rotate {}", x))),
+                },
+                keep: res.log.keep.unwrap_or(7),
+            }),
+            (Some("syslog"), None) => LogSink::Syslog,
+            (Some("journald"), None) => LogSink::Journald,
+            (Some("eventlog"), None) if cfg!(not(windows)) => {
+                return Err(Error::ConfigError("eventlog is only on windows".into()))
+            }
+            (Some("eventlog"), None) => LogSink::EventLog,
+            (Some(x), _) => {
+                return Err(Error::ConfigError(format!(
+                    "log sink {} doesn't fit the log file setting",
+                    x
+                )))
+            }
+        },
+        connection_log: res.log.connections.map(Into::into),
+        connection_log_reads: res.log.reads,
+        history_file: res.history.file.map(Into::into),
+        history_database: res.history.database.map(Into::into),
+        history_keep: res.history.keep.unwrap_or(crate::history::DEFAULT_KEEP),
+        capture_file: res.capture.file.map(Into::into),
+        stats_file: res.stats.file.map(Into::into),
+        stats_interval: Duration::from_millis(res.stats.interval.unwrap_or(60000)),
+        groups: res
+            .group
+            .into_iter()
+            .map(|g| {
+                Ok(Group {
+                    name: g.name.to_string(),
+                    client: g.client.iter().map(|c| c.parse()).collect::<Result<_>>()?,
+                    listener: g.listener.into_boxed_slice(),
+                })
+            })
+            .collect::<Result<_>>()?,
     };
     let routing = res
         .routing
         .into_iter()
-        .map(|r| Routing {
-            host: r.host.into_boxed_slice(),
-            pool: IpPool::new(r.pool),
+        .map(|r| {
+            Ok(Routing {
+                host: r.host.clone().into_boxed_slice(),
+                rules: Mutex::new(Arc::new(read_rules(r)?)),
+            })
         })
-        .collect::<Vec<_>>();
-    return Ok((config, routing));
+        .collect::<Result<Vec<_>>>()?;
+    Ok((config, routing))
+}
+
+/// Warn about what would otherwise only show as failing connections: pool
+/// addresses this host doesn't have, listeners inside a pool, zero timeouts.
+pub fn check(config: &Config, routings: &[Arc<Routing>]) {
+    let timeouts = [
+        ("connect", Some(config.connect_ttl)),
+        ("total", config.total_ttl),
+        ("io", Some(config.io_ttl)),
+    ];
+    for (name, ttl) in timeouts {
+        if ttl.is_some_and(|ttl| ttl.is_zero()) {
+            logger::warn(
+                "zero timeout, every connection fails",
+                &[("timeout", &name)],
+            );
+        }
+    }
+    let mut checked = HashSet::new();
+    for routing in routings {
+        let rules = routing.rules();
+        let pools = rules.pools().chain(config.pools.values().map(|p| &**p));
+        for ip in pools.flat_map(IpPool::members) {
+            // only an address of this host can be bound
+            if checked.insert(ip) && std::net::UdpSocket::bind((ip, 0)).is_err() {
+                logger::warn(
+                    "pool address not on this host, connections skip it",
+                    &[("addr", &ip)],
+                );
+            }
+        }
+        for listener in routing.host.iter() {
+            if rules.pools().any(|p| p.contains(listener.ip())) {
+                logger::warn(
+                    "listener address in its own pool, it also connects from it",
+                    &[("addr", listener)],
+                );
+            }
+        }
+    }
+}
+
+/// Swap in the rules of every routing from the file, matched by their `host` list.
+/// Either all of them are replaced or, on any error, none is.
+pub fn reload_rules(file_name: &Path, routings: &[Arc<Routing>]) -> Result<()> {
+    systemd::notify("RELOADING=1");
+    let res = reload(file_name, routings);
+    systemd::notify("READY=1");
+    res
+}
+
+fn reload(file_name: &Path, routings: &[Arc<Routing>]) -> Result<()> {
+    let res = read_file(file_name)?;
+    let mut updates = Vec::new();
+    for r in res.routing {
+        let routing = routings
+            .iter()
+            .find(|x| *x.host == *r.host)
+            .ok_or_else(|| Error::ConfigError("listeners can't be changed on reload".into()))?;
+        updates.push((routing, read_rules(r)?));
+    }
+    for (routing, rules) in updates {
+        routing.replace_rules(rules);
+    }
+    Ok(())
+}
+
+/// Reload the rules whenever the file is modified.
+pub fn watch(file_name: &Path, routings: &[Arc<Routing>]) {
+    let modified = || -> Option<SystemTime> { std::fs::metadata(file_name).ok()?.modified().ok() };
+    let mut last = modified();
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        let now = modified();
+        if now == last {
+            continue;
+        }
+        last = now;
+        match reload_rules(file_name, routings) {
+            Ok(()) => logger::info("reloaded rules", &[("file", &file_name.display())]),
+            Err(e) => logger::warn(
+                "failed to reload",
+                &[("file", &file_name.display()), ("error", &e)],
+            ),
+        }
+    }
+}
+
+fn read_rules(r: toml_file::Routing) -> Result<Rules> {
+    Ok(Rules {
+        default: Action::Pool(Arc::new(IpPool::new(r.pool))),
+        rule: r.rule.into_iter().map(read_rule).collect::<Result<_>>()?,
+        loaded: SystemTime::now(),
+    })
+}
+fn read_rule(r: toml_file::Rule) -> Result<Rule> {
+    let action = match (r.direct, r.pool.is_empty(), r.upstream.is_empty()) {
+        (false, _, true) => Action::Pool(Arc::new(IpPool::new(r.pool))),
+        (true, true, true) => Action::Direct,
+        (false, true, false) => Action::Upstream(Upstreams::new(
+            r.upstream
+                .iter()
+                .map(|u| u.parse())
+                .collect::<Result<_>>()?,
+            r.balance.as_deref().unwrap_or("failover").parse()?,
+        )),
+        _ => {
+            return Err(Error::ConfigError(
+                "a rule takes only one of pool, direct and upstream".into(),
+            ))
+        }
+    };
+    Ok(Rule {
+        client: r.client.iter().map(|c| c.parse()).collect::<Result<_>>()?,
+        listener: r.listener.into_boxed_slice(),
+        remote: r.remote.iter().map(|c| c.parse()).collect::<Result<_>>()?,
+        domain: DomainMatcher::new(&r.domain)?,
+        port: r
+            .port
+            .into_iter()
+            .map(toml_file::Port::into_range)
+            .collect::<Result<_>>()?,
+        country: r.country.into_boxed_slice(),
+        asn: r.asn.into_boxed_slice(),
+        capture: r.capture,
+        log: r.log,
+        action,
+    })
 }
 mod toml_file {
     // it sucks, but anyway it works
+    use crate::{Error, Result};
     use serde::Deserialize;
+    use std::collections::HashMap;
     use std::net::{IpAddr, SocketAddr};
 
     #[derive(Deserialize)]
     pub struct Routing {
         pub host: Vec<SocketAddr>,
         pub pool: Vec<IpAddr>,
+        #[serde(default)]
+        pub rule: Vec<Rule>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Rule {
+        #[serde(default)]
+        pub client: Vec<String>,
+        #[serde(default)]
+        pub listener: Vec<SocketAddr>,
+        #[serde(default)]
+        pub remote: Vec<String>,
+        #[serde(default)]
+        pub domain: Vec<String>,
+        #[serde(default)]
+        pub port: Vec<Port>,
+        #[serde(default)]
+        pub country: Vec<String>,
+        #[serde(default)]
+        pub asn: Vec<u32>,
+        #[serde(default)]
+        pub pool: Vec<IpAddr>,
+        #[serde(default)]
+        pub direct: bool,
+        #[serde(default)]
+        pub upstream: Vec<String>,
+        pub balance: Option<String>,
+        #[serde(default)]
+        pub capture: bool,
+        #[serde(default)]
+        pub log: bool,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Group {
+        pub name: GroupName,
+        #[serde(default)]
+        pub client: Vec<String>,
+        #[serde(default)]
+        pub listener: Vec<SocketAddr>,
+    }
+
+    /// `"office"` or `7`
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    pub enum GroupName {
+        Name(String),
+        Id(u32),
+    }
+    impl std::fmt::Display for GroupName {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                GroupName::Name(x) => x.fmt(f),
+                GroupName::Id(x) => x.fmt(f),
+            }
+        }
+    }
+
+    /// `25` or `"8000-8100"`
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    pub enum Port {
+        Single(u16),
+        Range(String),
+    }
+    impl Port {
+        pub fn into_range(self) -> Result<std::ops::RangeInclusive<u16>> {
+            match self {
+                Port::Single(x) => Ok(x..=x),
+                Port::Range(s) => {
+                    let parse = |x: &str| x.trim().parse::<u16>().ok();
+                    let range = match s.split_once('-') {
+                        Some((a, b)) => parse(a).zip(parse(b)),
+                        None => parse(&s).map(|x| (x, x)),
+                    };
+                    range
+                        .filter(|(a, b)| a <= b)
+                        .map(|(a, b)| a..=b)
+                        .ok_or_else(|| Error::ConfigError(format!("bad port range {}", s)))
+                }
+            }
+        }
     }
 
     #[derive(Deserialize)]
@@ -101,13 +822,157 @@ mod toml_file {
         pub routing: Vec<Routing>,
         pub timeout: Timeout,
         pub tui: bool,
+        pub tui_refresh: Option<u64>,
+        pub tui_keep: Option<u64>,
+        pub tui_smoothing: Option<u64>,
+        pub tui_theme: Option<String>,
+        pub console_interval: Option<u64>,
+        pub output: Option<String>,
         pub ipv6_first: Option<bool>,
+        #[serde(default)]
+        pub geoip: Vec<String>,
+        #[serde(default)]
+        pub watch: bool,
+        #[serde(default)]
+        pub pools: HashMap<String, Vec<IpAddr>>,
+        #[serde(default)]
+        pub egress_header: bool,
+        pub egress_token: Option<String>,
+        pub summary: Option<SocketAddr>,
+        pub admin: Option<Admin>,
+        pub otel: Option<Otel>,
+        pub webhook: Option<Webhook>,
+        pub workers: Option<Workers>,
+        pub acceptors: Option<usize>,
+        pub max_connections: Option<usize>,
+        #[serde(default)]
+        pub fast_open: bool,
+        #[serde(default)]
+        pub daemon: bool,
+        pub pidfile: Option<String>,
+        #[serde(default)]
+        pub socket: Socket,
+        #[serde(default)]
+        pub log: Log,
+        #[serde(default)]
+        pub history: History,
+        #[serde(default)]
+        pub stats: Stats,
+        #[serde(default)]
+        pub capture: Capture,
+        #[serde(default)]
+        pub group: Vec<Group>,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct Capture {
+        pub file: Option<String>,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct Stats {
+        pub file: Option<String>,
+        pub interval: Option<u64>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Admin {
+        pub addr: SocketAddr,
+        pub token: String,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct Socket {
+        pub send_buffer: Option<usize>,
+        pub recv_buffer: Option<usize>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Workers {
+        pub threads: usize,
+        pub queue: Option<usize>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Otel {
+        pub endpoint: String,
+        pub interval: Option<u64>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Webhook {
+        pub url: String,
+        pub errors: Option<usize>,
+        pub window: Option<u64>,
+        pub upstream_failures: Option<u32>,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct History {
+        pub file: Option<String>,
+        pub database: Option<String>,
+        pub keep: Option<usize>,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct Log {
+        pub format: Option<String>,
+        pub sink: Option<String>,
+        pub file: Option<String>,
+        pub max_size: Option<u64>,
+        pub rotate: Option<String>,
+        pub keep: Option<usize>,
+        pub connections: Option<String>,
+        #[serde(default)]
+        pub reads: bool,
     }
 
     #[derive(Deserialize)]
     pub struct Timeout {
         pub connect: u64,
         pub retry: u64,
+        pub total: Option<u64>,
         pub io: u64,
+        pub drain: Option<u64>,
+        pub dns_cache: Option<u64>,
+        pub keep_alive: Option<u64>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::toml_file::Port;
+
+    fn range(s: &str) -> Option<(u16, u16)> {
+        let range = Port::Range(s.into()).into_range().ok()?;
+        Some((*range.start(), *range.end()))
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(Port::Single(443).into_range().unwrap(), 443..=443);
+        assert_eq!(range("8000-8100"), Some((8000, 8100)));
+        assert_eq!(range(" 8000 - 8100 "), Some((8000, 8100)));
+        assert_eq!(range("8080"), Some((8080, 8080)));
+        assert_eq!(range("8080-8080"), Some((8080, 8080)));
+        assert_eq!(range("0-65535"), Some((0, 65535)));
+    }
+
+    #[test]
+    fn bad_port_ranges() {
+        for s in [
+            "8100-8000",
+            "",
+            "-",
+            "8000-",
+            "-8000",
+            "80-90-100",
+            "65536",
+            "1-65536",
+            "-1",
+            "http",
+        ] {
+            assert_eq!(range(s), None, "{s:?}");
+        }
     }
 }
@@ -0,0 +1,39 @@
+use crate::{config, http, Error, Result};
+use std::path::Path;
+
+/// What `multi3 <command>` asks the admin listener of the running instance.
+const USAGE: &str = "commands: status, stats, connections, kill <id>, reload, \
+                     egress list, egress add <alias> <ip>, egress remove <alias> <ip>, \
+                     pool list, pool add <routing> <ip>, pool remove <routing> <ip>";
+
+/// Run `args`, a command for the instance using `file_name`, and print the answer.
+pub fn run(file_name: &Path, args: &[String]) -> Result<()> {
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+    let (method, path, body) = match args.as_slice() {
+        ["status"] => ("GET", "/stats".to_owned(), ""),
+        ["stats"] => ("GET", "/stats/text".to_owned(), ""),
+        ["connections"] => ("GET", "/connections".to_owned(), ""),
+        ["kill", id] => ("DELETE", format!("/connections/{}", id), ""),
+        ["reload"] => ("POST", "/reload".to_owned(), ""),
+        ["egress", "list"] => ("GET", "/egress".to_owned(), ""),
+        ["egress", "add", name, ip] => ("POST", format!("/egress/{}", name), *ip),
+        ["egress", "remove", name, ip] => ("DELETE", format!("/egress/{}/{}", name, ip), ""),
+        ["pool", "list"] => ("GET", "/pools".to_owned(), ""),
+        ["pool", "add", routing, ip] => ("POST", format!("/pools/{}/{}", routing, ip), ""),
+        ["pool", "remove", routing, ip] => ("DELETE", format!("/pools/{}/{}", routing, ip), ""),
+        _ => return Err(Error::ConfigError(USAGE.into())),
+    };
+    let (config, _) = config::read_config(file_name)?;
+    let admin = config.admin.ok_or_else(|| {
+        Error::ConfigError(format!(
+            "no admin listener in {} to talk to",
+            file_name.display()
+        ))
+    })?;
+    let answer = http::call(&admin.addr.to_string(), &admin.token, method, &path, body)?;
+    print!("{}", answer);
+    if !answer.ends_with('\n') {
+        println!();
+    }
+    Ok(())
+}
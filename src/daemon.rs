@@ -0,0 +1,43 @@
+use std::{fs, io, path::Path, process};
+
+/// Go on in a child in a session of its own while the caller exits, with
+/// stdin, stdout and stderr on /dev/null. Only before any thread is started.
+#[cfg(unix)]
+pub fn detach() -> io::Result<()> {
+    use std::{ffi::c_int, os::unix::io::AsRawFd};
+    extern "C" {
+        fn fork() -> c_int;
+        fn setsid() -> c_int;
+        fn dup2(old: c_int, new: c_int) -> c_int;
+    }
+    // SAFETY: there is no other thread to leave behind in a bad state
+    match unsafe { fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => process::exit(0),
+    }
+    // SAFETY: plain syscalls on our own process and fds
+    if unsafe { setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Write the id of this process to `path` for rc scripts to signal it.
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+}
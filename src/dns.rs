@@ -0,0 +1,97 @@
+use crate::{handle::split_host_port, idn};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// Names kept at most, past it the expired ones go and then the oldest.
+const CAPACITY: usize = 4096;
+/// Names dropped at least when the cache is full, so the next ones fit
+/// without going through all of it again.
+const EVICT: usize = CAPACITY / 8;
+
+/// The addresses of every name resolved lately, shared by all connections.
+static CACHE: RwLock<Option<HashMap<String, Entry>>> = RwLock::new(None);
+
+struct Entry {
+    resolved: Instant,
+    ips: Vec<IpAddr>,
+}
+
+/// The addresses of `uri`, `host:port`, from the cache while they are younger than `ttl`.
+/// The resolver doesn't tell the TTL of the records, `ttl` is all there is.
+pub fn lookup(uri: &str, ttl: Duration) -> io::Result<Vec<SocketAddr>> {
+    let (host, port) = split_host_port(uri);
+    let port = match port.map(str::parse::<u16>) {
+        Some(Ok(port)) => port,
+        _ => return Ok(uri.to_socket_addrs()?.collect()),
+    };
+    // the resolver knows names with more than ASCII only in punycode
+    let host = &*idn::to_ascii(host);
+    // addresses and zero ttl need no cache
+    if ttl.is_zero() || host.parse::<IpAddr>().is_ok() {
+        return Ok((host, port).to_socket_addrs()?.collect());
+    }
+    let with_port =
+        |ips: &[IpAddr]| -> Vec<SocketAddr> { ips.iter().map(|&ip| (ip, port).into()).collect() };
+    if let Some(cache) = CACHE.read().unwrap().as_ref() {
+        if let Some(entry) = cache.get(host) {
+            if entry.resolved.elapsed() < ttl {
+                return Ok(with_port(&entry.ips));
+            }
+        }
+    }
+    let ips: Vec<_> = (host, port).to_socket_addrs()?.map(|x| x.ip()).collect();
+    let mut cache = CACHE.write().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    if cache.len() >= CAPACITY && !cache.contains_key(host) {
+        make_room(cache, ttl);
+    }
+    cache.insert(
+        host.to_owned(),
+        Entry {
+            resolved: Instant::now(),
+            ips: ips.clone(),
+        },
+    );
+    Ok(with_port(&ips))
+}
+
+/// Drop the expired names, and the oldest ones too while fewer than `EVICT`
+/// of them were.
+fn make_room(cache: &mut HashMap<String, Entry>, ttl: Duration) {
+    cache.retain(|_, entry| entry.resolved.elapsed() < ttl);
+    let keep = CAPACITY - EVICT;
+    if cache.len() <= keep {
+        return;
+    }
+    let mut resolved: Vec<_> = cache.values().map(|entry| entry.resolved).collect();
+    let (_, &mut newest_dropped, _) = resolved.select_nth_unstable(cache.len() - keep - 1);
+    cache.retain(|_, entry| entry.resolved > newest_dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_cache_drops_the_oldest() {
+        let start = Instant::now();
+        let mut cache: HashMap<_, _> = (0..CAPACITY)
+            .map(|n| {
+                let entry = Entry {
+                    resolved: start + Duration::from_millis(n as u64),
+                    ips: Vec::new(),
+                };
+                (n.to_string(), entry)
+            })
+            .collect();
+        make_room(&mut cache, Duration::from_secs(60));
+        assert_eq!(cache.len(), CAPACITY - EVICT);
+        assert!(!cache.contains_key(&(EVICT - 1).to_string()));
+        assert!(cache.contains_key(&EVICT.to_string()));
+    }
+}
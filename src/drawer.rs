@@ -3,17 +3,165 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
-use ratatui::{prelude::*, widgets::Paragraph};
-use std::collections::BTreeMap;
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, Paragraph, Sparkline, Tabs, Wrap},
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::{io::stdout, time::Instant};
-use std::{net::IpAddr, sync::mpsc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+use unicode_width::UnicodeWidthStr;
+
+use super::config::{Action, Config, IpPool, Routing, Theme};
+use super::event::{ErrorKind, Event};
+use super::handle::host_name;
+use super::summary::SUMMARY;
+use super::{history, logger};
 
-use super::event::Event;
+/// ms between two frames, `+` and `-` change it at runtime
+static FRAME_INTERVAL: AtomicU64 = AtomicU64::new(200);
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_FRAME_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_KEEP: Duration = Duration::from_secs(3600);
+const POOL_PANEL_WIDTH: u16 = 48;
+/// the columns of an exported view
+const CONNECTION_FIELDS: [&str; 12] = [
+    "id",
+    "client",
+    "destination",
+    "state",
+    "bind",
+    "remote",
+    "age_s",
+    "upload",
+    "download",
+    "upload_kbps",
+    "download_kbps",
+    "error",
+];
+const HOST_FIELDS: [&str; 7] = [
+    "host",
+    "connections",
+    "upload",
+    "download",
+    "upload_kbps",
+    "download_kbps",
+    "errors",
+];
+/// errors kept for the error pane
+const ERRORS_LEN: usize = 100;
+const ERROR_PANE_HEIGHT: u16 = 8;
+/// finished connections kept for the history view
+const FINISHED_LEN: usize = 1000;
+/// plain ASCII instead of emoji and arrows
+static ASCII: AtomicBool = AtomicBool::new(false);
 
-pub const FRAME_INTERVAL: Duration = Duration::from_millis(200);
+/// The symbols drawn, all of one width so the columns line up.
+struct Icons {
+    waiting: &'static str,
+    connected: &'static str,
+    done: &'static str,
+    error: &'static str,
+    title: &'static str,
+    retry: &'static str,
+    killed: &'static str,
+    up: &'static str,
+    down: &'static str,
+    ascending: &'static str,
+    descending: &'static str,
+    typing: &'static str,
+    divider: &'static str,
+    border: symbols::border::Set<'static>,
+    bars: symbols::bar::Set<'static>,
+}
+const EMOJI_ICONS: Icons = Icons {
+    waiting: "⏳",
+    connected: "🔗",
+    done: "✅",
+    error: "❎",
+    title: "🔰",
+    retry: "↻×",
+    killed: "🔪killed ",
+    up: "⇧",
+    down: "⇩",
+    ascending: "▲",
+    descending: "▼",
+    typing: "▏",
+    divider: symbols::line::VERTICAL,
+    border: symbols::border::PLAIN,
+    bars: symbols::bar::NINE_LEVELS,
+};
+const ASCII_ICONS: Icons = Icons {
+    waiting: "..",
+    connected: "<>",
+    done: "ok",
+    error: "!!",
+    title: "  ",
+    retry: "Rx",
+    killed: "killed ",
+    up: "^",
+    down: "v",
+    ascending: "^",
+    descending: "v",
+    typing: "_",
+    divider: "|",
+    border: symbols::border::Set {
+        top_left: "+",
+        top_right: "+",
+        bottom_left: "+",
+        bottom_right: "+",
+        vertical_left: "|",
+        vertical_right: "|",
+        horizontal_top: "-",
+        horizontal_bottom: "-",
+    },
+    bars: symbols::bar::Set {
+        full: "#",
+        seven_eighths: "#",
+        three_quarters: "=",
+        five_eighths: "=",
+        half: "-",
+        three_eighths: "-",
+        one_quarter: ".",
+        one_eighth: ".",
+        empty: " ",
+    },
+};
+
+fn icons() -> &'static Icons {
+    match ASCII.load(Ordering::Relaxed) {
+        true => &ASCII_ICONS,
+        false => &EMOJI_ICONS,
+    }
+}
+
+/// Use `theme`, or without one mono when NO_COLOR is set (no-color.org) and
+/// the default otherwise. Colors are forced only for a theme that was picked,
+/// left alone crossterm decides from the environment.
+pub fn set_theme(theme: Option<Theme>) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+    let picked = match theme {
+        Some(theme) => theme,
+        None if no_color => Theme::Mono,
+        None => Theme::Default,
+    };
+    ASCII.store(picked != Theme::Default, Ordering::Relaxed);
+    if theme.is_some() || picked == Theme::Mono {
+        crossterm::style::force_color_output(picked != Theme::Mono);
+    }
+}
 const WIDGETS_TIME_LEN: usize = 5;
 const WIDGETS_SPEED_LEN: usize = 10;
-const KEEP_AFTER_DONE: Duration = Duration::from_secs(2);
+/// columns of the state icon, the emoji ones are double-width
+const WIDGETS_STATE_LEN: usize = 2;
+const TOP_HOSTS: usize = 5;
+/// seconds of throughput in the sparklines
+const THROUGHPUT_LEN: usize = 60;
 
 #[derive(Clone, Copy)]
 enum State {
@@ -25,40 +173,263 @@ enum State {
 impl From<State> for &str {
     fn from(val: State) -> Self {
         match val {
-            State::Waiting => "⏳",
-            State::Connected => "🔗",
-            State::Done(_) => "✅",
-            State::Error(_) => "❎",
+            State::Waiting => icons().waiting,
+            State::Connected => icons().connected,
+            State::Done(_) => icons().done,
+            State::Error(_) => icons().error,
+        }
+    }
+}
+
+impl State {
+    /// waiting first, errors last
+    fn rank(self) -> u8 {
+        match self {
+            State::Waiting => 0,
+            State::Connected => 1,
+            State::Done(_) => 2,
+            State::Error(_) => 3,
+        }
+    }
+}
+
+/// The column the connections are sorted by, `s` goes to the next one.
+#[derive(Clone, Copy, Default)]
+enum SortBy {
+    #[default]
+    Start,
+    Upload,
+    Download,
+    Host,
+    State,
+}
+impl SortBy {
+    fn next(self) -> Self {
+        match self {
+            SortBy::Start => SortBy::Upload,
+            SortBy::Upload => SortBy::Download,
+            SortBy::Download => SortBy::Host,
+            SortBy::Host => SortBy::State,
+            SortBy::State => SortBy::Start,
+        }
+    }
+    fn name(self) -> &'static str {
+        match self {
+            SortBy::Start => "start",
+            SortBy::Upload => "upload",
+            SortBy::Download => "download",
+            SortBy::Host => "host",
+            SortBy::State => "state",
+        }
+    }
+}
+
+/// The pages of the tui, switched with the number keys.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Tab {
+    #[default]
+    Connections,
+    Hosts,
+    Errors,
+    Pool,
+    Config,
+}
+impl Tab {
+    const ALL: [Tab; 5] = [
+        Tab::Connections,
+        Tab::Hosts,
+        Tab::Errors,
+        Tab::Pool,
+        Tab::Config,
+    ];
+    fn from_key(c: char) -> Option<Self> {
+        let i = c.to_digit(10)?.checked_sub(1)?;
+        Self::ALL.get(i as usize).copied()
+    }
+    fn name(self) -> &'static str {
+        match self {
+            Tab::Connections => "connections",
+            Tab::Hosts => "hosts",
+            Tab::Errors => "errors",
+            Tab::Pool => "pool",
+            Tab::Config => "config",
+        }
+    }
+}
+
+/// The states shown, `f` goes to the next choice.
+#[derive(Clone, Copy, Default)]
+enum Only {
+    #[default]
+    All,
+    Active,
+    Errors,
+}
+impl Only {
+    fn next(self) -> Self {
+        match self {
+            Only::All => Only::Active,
+            Only::Active => Only::Errors,
+            Only::Errors => Only::All,
         }
     }
 }
 
+/// A line of text typed in, Enter keeps it and Esc drops it.
+#[derive(Default)]
+struct Prompt {
+    text: String,
+    typing: bool,
+}
+impl Prompt {
+    /// Edit the text while typing, false if not typing.
+    fn key(&mut self, code: KeyCode) -> bool {
+        if !self.typing {
+            return false;
+        }
+        match code {
+            KeyCode::Char(c) => self.text.push(c),
+            KeyCode::Backspace => {
+                self.text.pop();
+            }
+            KeyCode::Enter => self.typing = false,
+            KeyCode::Esc => {
+                self.text.clear();
+                self.typing = false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// ` label text` with a cursor while typing, nothing if there is no text.
+    fn describe(&self, label: &str) -> String {
+        let mut res = String::new();
+        if self.typing || !self.text.is_empty() {
+            res += &format!(" {}{}", label, self.text);
+        }
+        if self.typing {
+            res += icons().typing;
+        }
+        res
+    }
+}
+
+/// What the list is restricted to, the summary still counts everything.
+#[derive(Default)]
+struct Filter {
+    only: Only,
+    /// part of the host to look for, `h` starts typing it
+    host: Prompt,
+}
+impl Filter {
+    fn matches(&self, content: &Content) -> bool {
+        let state = match self.only {
+            Only::All => true,
+            Only::Active => matches!(content.state, State::Waiting | State::Connected),
+            Only::Errors => matches!(content.state, State::Error(_)),
+        };
+        state
+            && (self.host.text.is_empty()
+                || content
+                    .uri
+                    .as_ref()
+                    .is_some_and(|uri| uri.contains(&self.host.text)))
+    }
+
+    fn describe(&self) -> String {
+        let res = match self.only {
+            Only::All => "",
+            Only::Active => " only active",
+            Only::Errors => " only errors",
+        };
+        res.to_owned() + &self.host.describe("host~")
+    }
+}
+
+/// Rows whose host or client has the text of the `/` prompt, `n` and `N` jump between them.
+#[derive(Default)]
+struct Search {
+    prompt: Prompt,
+}
+impl Search {
+    fn matches(&self, content: &Content) -> bool {
+        content
+            .uri
+            .as_ref()
+            .is_some_and(|uri| self.matches_text(uri))
+            || self.matches_text(&content.client.to_string())
+    }
+
+    fn matches_text(&self, text: &str) -> bool {
+        !self.prompt.text.is_empty() && text.contains(&self.prompt.text)
+    }
+
+    /// The index of the first matching row from `from` on, or backwards,
+    /// starting over at the other end.
+    fn find(&self, rows: &[(usize, &Content)], from: usize, back: bool) -> Option<usize> {
+        let len = rows.len();
+        let from = from % len.max(1);
+        (0..len)
+            .map(|i| match back {
+                false => (from + i) % len,
+                true => (from + len - i) % len,
+            })
+            .find(|&i| self.matches(rows[i].1))
+    }
+}
+
 struct Content {
     time_start: Instant,
-    local: IpAddr,
+    client: SocketAddr,
+    group: Option<String>,
     bind: Option<IpAddr>,
     remote: Option<IpAddr>,
     uri: Option<String>,
+    sni: Option<String>,
+    /// the addresses that failed before the one connected to
+    tried: Vec<SocketAddr>,
+    /// since the start, when the addresses were known, the remote connected and it ended
+    looked_up: Option<Duration>,
+    connected: Option<Duration>,
+    finished: Option<Duration>,
+    /// the whole message, the addon may be cut off on screen
+    error: Option<String>,
     state: State,
     upload: usize,
     download: usize,
+    /// totals at the last tick
+    counted: (usize, usize),
+    /// KB/s, averaged over the smoothing window
+    rates: (f32, f32),
     addon: String,
+    failure: Option<ErrorKind>,
 }
 impl Content {
-    fn new(local: IpAddr) -> Self {
+    fn new(client: SocketAddr) -> Self {
         Self {
             time_start: Instant::now(),
-            local,
+            client,
+            group: None,
             bind: None,
             remote: None,
             uri: None,
+            sni: None,
+            tried: Vec::new(),
+            looked_up: None,
+            connected: None,
+            finished: None,
+            error: None,
             state: State::Waiting,
             upload: 0,
             download: 0,
+            counted: (0, 0),
+            rates: (0.0, 0.0),
             addon: String::new(),
+            failure: None,
         }
     }
-    fn to_line(&self) -> Line {
+    fn to_line(&self, totals: bool) -> Line<'_> {
         let mut res = Vec::with_capacity(6);
         res.push(
             Span::raw(format!(
@@ -68,19 +439,22 @@ impl Content {
             ))
             .cyan(),
         );
+        let (up, down) = match totals {
+            true => (self.upload as f32 / 1024f32, self.download as f32 / 1024f32),
+            false => self.rates,
+        };
         res.push(
-            //🔼🔽
             Span::raw(format!(
                 "{:>width$.1} {:>width$.1}",
-                self.upload as f32 / 1024f32,
-                self.download as f32 / 1024f32,
+                up,
+                down,
                 width = WIDGETS_SPEED_LEN
             ))
             .light_magenta(),
         );
 
         let icon: &str = self.state.into();
-        res.push(Span::raw(icon));
+        res.push(Span::raw(align_left(icon, WIDGETS_STATE_LEN)));
 
         // res.push(Span::raw(self.local.to_string()).light_blue());
         if let Some(ip) = &self.bind {
@@ -92,31 +466,174 @@ impl Content {
         }
 
         res.push(Span::raw(" "));
-        res.push(Span::raw(&self.addon).bold());
+        if !self.tried.is_empty() {
+            res.push(Span::raw(format!("{}{} ", icons().retry, self.tried.len())).yellow());
+        }
+        let addon = Span::raw(&self.addon).bold();
+        res.push(match self.failure {
+            None => addon,
+            Some(failure) => addon.fg(failure_color(failure)),
+        });
 
         res.into()
     }
+
+    /// The CSV fields of the connection for [`export_view`].
+    fn csv_fields(&self, id: usize) -> Vec<String> {
+        let opt = |x: Option<String>| x.unwrap_or_default();
+        let state = match self.state {
+            State::Waiting => "waiting",
+            State::Connected => "connected",
+            State::Done(_) => "done",
+            State::Error(_) => "error",
+        };
+        vec![
+            id.to_string(),
+            self.client.to_string(),
+            opt(self.uri.clone()),
+            state.to_owned(),
+            opt(self.bind.map(|x| x.to_string())),
+            opt(self.remote.map(|x| x.to_string())),
+            self.time_start.elapsed().as_secs().to_string(),
+            self.upload.to_string(),
+            self.download.to_string(),
+            format!("{:.1}", self.rates.0),
+            format!("{:.1}", self.rates.1),
+            opt(self.error.clone()),
+        ]
+    }
+
+    /// The destination without the port, the TLS name if there is one.
+    fn host(&self) -> &str {
+        if let Some(sni) = &self.sni {
+            return sni;
+        }
+        match &self.uri {
+            Some(uri) => host_name(uri),
+            None => "?",
+        }
+    }
+
+    /// Everything known about the connection, for the detail pane.
+    fn detail_lines(&self) -> Vec<Line<'_>> {
+        let field = |name: &str, value: String| -> Line {
+            vec![Span::raw(format!("{:>8} ", name)).cyan(), Span::raw(value)].into()
+        };
+        let ms = |x: Duration| format!("{} ms", x.as_millis());
+        let mut lines = vec![field(
+            "client",
+            match &self.group {
+                Some(group) => format!("{} in {}", self.client, group),
+                None => self.client.to_string(),
+            },
+        )];
+        if let Some(uri) = &self.uri {
+            lines.push(field("target", uri.clone()));
+        }
+        if let Some(sni) = &self.sni {
+            lines.push(field("sni", sni.clone()));
+        }
+        if let Some(remote) = self.remote {
+            let bind = self
+                .bind
+                .map_or_else(String::new, |x| format!(" from {}", x));
+            lines.push(field("remote", format!("{}{}", remote, bind)));
+        }
+        if !self.tried.is_empty() {
+            let tried: Vec<_> = self.tried.iter().map(|x| x.to_string()).collect();
+            lines.push(field(
+                "retries",
+                format!("{}: {}", tried.len(), tried.join(", ")),
+            ));
+        }
+        let mut timing = Vec::new();
+        if let Some(looked_up) = self.looked_up {
+            timing.push(format!("dns {}", ms(looked_up)));
+        }
+        if let Some(connected) = self.connected {
+            timing.push(format!(
+                "connect {}",
+                ms(connected.saturating_sub(self.looked_up.unwrap_or_default()))
+            ));
+        }
+        let end = self.finished.unwrap_or_else(|| self.time_start.elapsed());
+        if let Some(connected) = self.connected {
+            timing.push(format!("relay {}", ms(end.saturating_sub(connected))));
+        }
+        timing.push(format!("total {}", ms(end)));
+        lines.push(field("timing", timing.join(", ")));
+        lines.push(field(
+            "traffic",
+            format!(
+                "{} {:.1} KB {} {:.1} KB",
+                icons().up,
+                self.upload as f32 / 1024f32,
+                icons().down,
+                self.download as f32 / 1024f32
+            ),
+        ));
+        if let Some(error) = &self.error {
+            let kind = self.failure.map_or("", |x| x.name());
+            lines.push(field("error", format!("{} {}", kind, error)).red());
+        }
+        lines
+    }
 }
 
 struct Summary {
     pub jobs: Option<BTreeMap<usize, Content>>,
+    last_tick: Instant,
+    /// the connection in the detail pane, it stays until the pane is closed
+    pub detail: Option<usize>,
+    /// how long finished connections stay, `>` and `<` change it
+    pub keep: Duration,
+    /// the connections gone from the list, the oldest first
+    finished: VecDeque<(usize, Content)>,
+    /// the latest errors, the oldest first
+    errors: VecDeque<ErrorLine>,
+    /// show the finished connections instead, Tab switches
+    pub history: bool,
+    /// bytes up and down of each of the last seconds, the newest last
+    throughput: VecDeque<(u64, u64)>,
+    /// bytes of the second going on
+    second: (u64, u64),
+    second_start: Instant,
+    /// KB/s of all the connections, averaged like theirs
+    rates: (f32, f32),
+    smoothing: Duration,
 }
 impl Summary {
-    pub fn new() -> Self {
+    pub fn new(keep: Duration, smoothing: Duration) -> Self {
         Self {
+            keep,
+            smoothing,
+            rates: (0.0, 0.0),
+            finished: VecDeque::new(),
+            errors: VecDeque::new(),
+            history: false,
             jobs: Some(BTreeMap::new()),
+            last_tick: Instant::now(),
+            detail: None,
+            throughput: VecDeque::with_capacity(THROUGHPUT_LEN + 1),
+            second: (0, 0),
+            second_start: Instant::now(),
         }
     }
     pub fn update(&mut self, id: usize, event: Event) {
         if id != 0 {
-            if let Event::Received(ip) = event {
-                self.jobs.as_mut().unwrap().insert(id, Content::new(ip));
+            if let Event::Received(addr) = event {
+                self.jobs.as_mut().unwrap().insert(id, Content::new(addr));
             } else {
                 let mut index = match self.jobs.as_mut().unwrap().entry(id) {
                     std::collections::btree_map::Entry::Vacant(_) => return,
                     std::collections::btree_map::Entry::Occupied(x) => x,
                 };
                 let content = index.get_mut();
+                // shown like any other error, the transfers are counted already
+                let event = match event.failure() {
+                    Some((failure, e)) => Event::Error(failure, e),
+                    None => event,
+                };
                 match event {
                     Event::Resolved(uri) => {
                         content.uri = Some(uri);
@@ -125,22 +642,56 @@ impl Summary {
                         content.bind = Some(bind);
                         content.remote = Some(remote);
                         content.state = State::Connected;
+                        content.connected = Some(content.time_start.elapsed());
                     }
-                    Event::Done() => {
+                    Event::Done { duration, .. } => {
                         content.state = State::Done(Instant::now());
+                        content.finished = Some(duration);
                     }
                     Event::Upload(n) => {
                         content.upload += n;
+                        self.second.0 += n as u64;
                     }
                     Event::Download(n) => {
                         content.download += n;
+                        self.second.1 += n as u64;
+                    }
+                    Event::Retry(addr) => {
+                        content.tried.push(addr);
+                    }
+                    Event::PoolBindFailed(ip) => {
+                        content.addon += &format!("{} unbindable ", ip);
+                    }
+                    Event::Sni(name) => {
+                        content.uri = content.uri.take().map(|uri| format!("{} ({})", name, uri));
+                        content.sni = Some(name);
+                    }
+                    Event::Grouped(group) => {
+                        content.group = Some(group);
                     }
-                    Event::Retry() => {
-                        content.addon.push('🔁');
+                    Event::LookedUp() => {
+                        content.looked_up = Some(content.time_start.elapsed());
                     }
-                    Event::Error(e) => {
+                    Event::Error(failure, e) => {
                         content.state = State::Error(Instant::now());
+                        content.failure = Some(failure);
+                        content.finished = Some(content.time_start.elapsed());
+                        // a repeated error is shown once
+                        if content.error.as_deref() == Some(&*e) {
+                            return;
+                        }
                         content.addon += &e;
+                        if self.errors.len() == ERRORS_LEN {
+                            self.errors.pop_front();
+                        }
+                        self.errors.push_back(ErrorLine {
+                            time: logger::timestamp(),
+                            id,
+                            kind: failure,
+                            host: content.host().to_owned(),
+                            text: e.to_string(),
+                        });
+                        content.error = Some(e.into_owned());
                     }
                     _ => {
                         unreachable!()
@@ -148,79 +699,1121 @@ impl Summary {
                 };
             }
         } else {
-            self.jobs = Some(
-                self.jobs
-                    .take()
-                    .unwrap()
-                    .into_iter()
-                    .filter(|(_id, content)| match content.state {
-                        State::Done(t) | State::Error(t) => t.elapsed() < KEEP_AFTER_DONE,
-                        _ => true,
-                    })
-                    .collect(),
+            if self.second_start.elapsed() >= Duration::from_secs(1) {
+                self.second_start = Instant::now();
+                self.throughput.push_back(std::mem::take(&mut self.second));
+                if self.throughput.len() > THROUGHPUT_LEN {
+                    self.throughput.pop_front();
+                }
+            }
+            let elapsed = self.last_tick.elapsed().as_secs_f32().max(f32::EPSILON);
+            self.last_tick = Instant::now();
+            let window = self.smoothing.as_secs_f32();
+            let mut current = (0.0, 0.0);
+            for content in self.jobs.as_mut().unwrap().values_mut() {
+                let up = (content.upload - content.counted.0) as f32 / 1024f32 / elapsed;
+                let down = (content.download - content.counted.1) as f32 / 1024f32 / elapsed;
+                content.rates = (
+                    smooth(content.rates.0, up, elapsed, window),
+                    smooth(content.rates.1, down, elapsed, window),
+                );
+                content.counted = (content.upload, content.download);
+                current = (current.0 + up, current.1 + down);
+            }
+            self.rates = (
+                smooth(self.rates.0, current.0, elapsed, window),
+                smooth(self.rates.1, current.1, elapsed, window),
             );
+            let (jobs, expired): (BTreeMap<_, _>, BTreeMap<_, _>) = self
+                .jobs
+                .take()
+                .unwrap()
+                .into_iter()
+                .partition(|(id, content)| match content.state {
+                    _ if self.detail == Some(*id) => true,
+                    State::Done(t) | State::Error(t) => t.elapsed() < self.keep,
+                    _ => true,
+                });
+            self.jobs = Some(jobs);
+            for job in expired {
+                if self.finished.len() == FINISHED_LEN {
+                    self.finished.pop_front();
+                }
+                self.finished.push_back(job);
+            }
         }
         // self
     }
     pub fn jobs(&self) -> &BTreeMap<usize, Content> {
         self.jobs.as_ref().unwrap()
     }
+    /// The connection `id`, open or finished.
+    pub fn get(&self, id: usize) -> Option<&Content> {
+        self.jobs().get(&id).or_else(|| {
+            self.finished
+                .iter()
+                .find(|(x, _)| *x == id)
+                .map(|(_, content)| content)
+        })
+    }
+    /// Close the connection `id`, the row shows it was killed until it goes.
+    pub fn kill(&mut self, id: usize) {
+        if crate::handle::kill(id) {
+            if let Some(content) = self.jobs.as_mut().unwrap().get_mut(&id) {
+                content.addon += icons().killed;
+            }
+        }
+    }
+    /// The connections to draw in their order, ties stay in id order.
+    pub fn rows(&self, filter: &Filter, by: SortBy, descending: bool) -> Vec<(usize, &Content)> {
+        let mut jobs: Vec<_> = match self.history {
+            false => self
+                .jobs()
+                .iter()
+                .map(|(&id, x)| (id, x))
+                .collect::<Vec<_>>(),
+            true => self.finished.iter().map(|(id, x)| (*id, x)).collect(),
+        }
+        .into_iter()
+        .filter(|(_, x)| filter.matches(x))
+        .collect();
+        match by {
+            SortBy::Start => {}
+            SortBy::Upload => jobs.sort_by_key(|(_, x)| x.upload),
+            SortBy::Download => jobs.sort_by_key(|(_, x)| x.download),
+            SortBy::Host => jobs.sort_by(|(_, a), (_, b)| a.uri.cmp(&b.uri)),
+            SortBy::State => jobs.sort_by_key(|(_, x)| x.state.rank()),
+        }
+        if descending {
+            jobs.reverse();
+        }
+        jobs
+    }
 }
 
-pub fn drawer(recv: mpsc::Receiver<(usize, Event)>) -> std::io::Result<()> {
-    stdout().execute(EnterAlternateScreen)?;
-    enable_raw_mode()?;
-    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
-    terminal.clear()?;
+/// The connections of the list to one destination host, `a` switches to them.
+#[derive(Default)]
+struct HostRow {
+    host: String,
+    connections: usize,
+    upload: usize,
+    download: usize,
+    rates: (f32, f32),
+    errors: usize,
+}
+impl HostRow {
+    /// The rows by host in the order of `rows`, unless sorted by what adds up.
+    fn aggregate(rows: &[(usize, &Content)], by: SortBy, descending: bool) -> Vec<HostRow> {
+        let mut res: Vec<HostRow> = Vec::new();
+        for (_, content) in rows {
+            let host = content.host();
+            let row = match res.iter().position(|x| x.host == host) {
+                Some(i) => &mut res[i],
+                None => {
+                    res.push(HostRow {
+                        host: host.to_owned(),
+                        ..Default::default()
+                    });
+                    res.last_mut().unwrap()
+                }
+            };
+            row.connections += 1;
+            row.upload += content.upload;
+            row.download += content.download;
+            row.rates.0 += content.rates.0;
+            row.rates.1 += content.rates.1;
+            row.errors += matches!(content.state, State::Error(_)) as usize;
+        }
+        match by {
+            SortBy::Start => return res,
+            SortBy::Upload => res.sort_by_key(|x| x.upload),
+            SortBy::Download => res.sort_by_key(|x| x.download),
+            SortBy::Host => res.sort_by(|a, b| a.host.cmp(&b.host)),
+            SortBy::State => res.sort_by_key(|x| x.errors),
+        }
+        if descending {
+            res.reverse();
+        }
+        res
+    }
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.host.clone(),
+            self.connections.to_string(),
+            self.upload.to_string(),
+            self.download.to_string(),
+            format!("{:.1}", self.rates.0),
+            format!("{:.1}", self.rates.1),
+            self.errors.to_string(),
+        ]
+    }
+
+    fn to_line(&self, totals: bool) -> Line<'static> {
+        let (up, down) = match totals {
+            true => (self.upload as f32 / 1024f32, self.download as f32 / 1024f32),
+            false => self.rates,
+        };
+        let mut line = vec![
+            Span::raw(format!(
+                "{:>width$}",
+                self.connections,
+                width = WIDGETS_TIME_LEN
+            ))
+            .cyan(),
+            Span::raw(format!(
+                "{:>width$.1} {:>width$.1}",
+                up,
+                down,
+                width = WIDGETS_SPEED_LEN
+            ))
+            .light_magenta(),
+            Span::raw(" "),
+            Span::raw(self.host.clone()).blue().bold(),
+        ];
+        if self.errors > 0 {
+            line.push(Span::raw(format!(" {}{}", icons().error, self.errors)).red());
+        }
+        line.into()
+    }
+}
+
+/// An error as it happened, for the error pane.
+struct ErrorLine {
+    time: String,
+    id: usize,
+    kind: ErrorKind,
+    host: String,
+    text: String,
+}
+impl ErrorLine {
+    fn to_line(&self) -> Line<'_> {
+        vec![
+            Span::raw(&self.time).dark_gray(),
+            Span::raw(format!(" #{} ", self.id)).cyan(),
+            Span::raw(self.kind.name()).fg(failure_color(self.kind)),
+            Span::raw(" "),
+            Span::raw(&self.host).blue(),
+            Span::raw(" "),
+            Span::raw(&self.text),
+        ]
+        .into()
+    }
+}
+
+/// Write the rows as shown to a CSV file of their own and log where it went.
+fn export_view(header: &[&str], rows: Vec<Vec<String>>) {
+    let path = format!("multi3-view-{}.csv", logger::timestamp().replace(':', "-"));
+    let mut csv = header.join(",") + "\n";
+    for row in rows {
+        csv += &history::csv_line(&row);
+        csv.push('\n');
+    }
+    match std::fs::write(&path, csv) {
+        Ok(()) => logger::info("exported view", &[("file", &path)]),
+        Err(e) => logger::warn("failed to export view", &[("error", &e)]),
+    }
+}
+
+/// The selected connection and the first one on screen.
+#[derive(Default)]
+struct Cursor {
+    selected: usize,
+    offset: usize,
+    /// rows of the last frame, a page
+    height: usize,
+}
+impl Cursor {
+    /// Move on Up, Down, PageUp, PageDown, Home and End.
+    fn key(&mut self, code: KeyCode) {
+        self.selected = match code {
+            KeyCode::Up => self.selected.saturating_sub(1),
+            KeyCode::Down => self.selected.saturating_add(1),
+            KeyCode::PageUp => self.selected.saturating_sub(self.height.max(1)),
+            KeyCode::PageDown => self.selected.saturating_add(self.height.max(1)),
+            KeyCode::Home => 0,
+            KeyCode::End => usize::MAX,
+            _ => return,
+        };
+    }
 
-    let title: Line = vec![
-        Span::raw(format!("{:>width$}", "time", width = WIDGETS_TIME_LEN)).cyan(),
+    /// Keep the selection within `len` rows and on a screen of `height` rows.
+    fn fit(&mut self, len: usize, height: usize) {
+        self.height = height;
+        self.selected = self.selected.min(len.saturating_sub(1));
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        }
+        if self.selected >= self.offset + height {
+            self.offset = self.selected + 1 - height;
+        }
+        self.offset = self.offset.min(len.saturating_sub(height));
+    }
+}
+
+/// An exponential moving average: `rate` moves toward `current` by the
+/// share of the `window` that `elapsed` seconds are.
+fn smooth(rate: f32, current: f32, elapsed: f32, window: f32) -> f32 {
+    if window <= 0.0 {
+        return current;
+    }
+    rate + (1.0 - (-elapsed / window).exp()) * (current - rate)
+}
+
+/// Up and down throughput of the last minute side by side.
+fn throughput(frame: &mut Frame, area: Rect, summary: &Summary) {
+    let areas = Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).split(area);
+    let max = summary
+        .throughput
+        .iter()
+        .map(|&(up, down)| up.max(down))
+        .max()
+        .unwrap_or(0);
+    let rates = summary.rates;
+    for (i, (name, rate)) in [(icons().up, rates.0), (icons().down, rates.1)]
+        .into_iter()
+        .enumerate()
+    {
+        let data: Vec<u64> = summary
+            .throughput
+            .iter()
+            .map(|x| if i == 0 { x.0 } else { x.1 })
+            .collect();
+        // the newest at the right edge
+        let width = areas[i].width as usize;
+        let data = &data[data.len().saturating_sub(width)..];
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::new().title(format!("{} {:.1} KB/s", name, rate)))
+                .data(data)
+                .max(max.max(1))
+                .bar_set(icons().bars.clone())
+                .light_magenta(),
+            areas[i],
+        );
+    }
+}
+
+/// Failures on our side stand out from the ones of the destination.
+fn failure_color(failure: ErrorKind) -> Color {
+    match failure {
+        ErrorKind::DnsFailure => Color::Yellow,
+        ErrorKind::ConnectTimeout => Color::LightYellow,
+        ErrorKind::ConnectRefused => Color::LightRed,
+        ErrorKind::ProtocolViolation | ErrorKind::Forbidden => Color::Magenta,
+        ErrorKind::Overloaded => Color::LightBlue,
+        ErrorKind::PoolBindFailed => Color::LightMagenta,
+        ErrorKind::IoError => Color::Red,
+    }
+}
+
+/// `text` padded on the left to `width` columns of the terminal, which
+/// `format!` gets wrong for double-width glyphs.
+fn align_right(text: &str, width: usize) -> String {
+    let pad = width.saturating_sub(text.width());
+    format!("{}{}", " ".repeat(pad), text)
+}
+
+/// `text` padded on the right to `width` columns of the terminal.
+fn align_left(text: &str, width: usize) -> String {
+    let pad = width.saturating_sub(text.width());
+    format!("{}{}", text, " ".repeat(pad))
+}
+
+/// The `n` hosts with the most traffic since startup.
+fn host_lines(n: usize) -> Vec<Line<'static>> {
+    let summary = SUMMARY.lock().unwrap();
+    let mut lines: Vec<Line> = vec![vec![
+        Span::raw(format!("{:>width$}", "conn", width = WIDGETS_TIME_LEN)).cyan(),
+        Span::raw(format!(
+            "{} {}",
+            align_right(&format!("{}KB", icons().up), WIDGETS_SPEED_LEN),
+            align_right(&format!("{}KB", icons().down), WIDGETS_SPEED_LEN),
+        ))
+        .light_magenta(),
+        Span::raw(" top hosts").blue().bold(),
+    ]
+    .into()];
+    for (host, stats) in summary.top_hosts(n) {
+        let mut line = vec![
+            Span::raw(format!(
+                "{:>width$}",
+                stats.connections,
+                width = WIDGETS_TIME_LEN
+            ))
+            .cyan(),
+            Span::raw(format!(
+                "{:>width$.1} {:>width$.1}",
+                stats.upload as f32 / 1024f32,
+                stats.download as f32 / 1024f32,
+                width = WIDGETS_SPEED_LEN
+            ))
+            .light_magenta(),
+            Span::raw(" "),
+            Span::raw(host.clone()).blue().bold(),
+        ];
+        if stats.errors > 0 {
+            line.push(Span::raw(format!(" {}{}", icons().error, stats.errors)).red());
+        }
+        lines.push(line.into());
+    }
+    lines
+}
+
+/// Every pool address with its open connections, traffic and how often it failed.
+fn pool_lines(routings: &[Arc<Routing>]) -> Vec<Line<'static>> {
+    let mut members: Vec<_> = routings
+        .iter()
+        .flat_map(|r| {
+            r.rules()
+                .pools()
+                .flat_map(|p| p.members())
+                .collect::<Vec<_>>()
+        })
+        .map(|ip| ip.to_string())
+        .collect();
+    members.sort();
+    members.dedup();
+    let summary = SUMMARY.lock().unwrap();
+    let open = summary.open_binds();
+    let mut lines: Vec<Line> = vec![vec![
+        Span::raw(" pool").blue().bold(),
+        Span::raw(format!(
+            "{:>width$}{:>width$}",
+            "open",
+            "conn",
+            width = WIDGETS_TIME_LEN + 1
+        ))
+        .cyan(),
+        Span::raw(format!(
+            " {} {}",
+            align_right(&format!("{}KB", icons().up), WIDGETS_SPEED_LEN),
+            align_right(&format!("{}KB", icons().down), WIDGETS_SPEED_LEN),
+        ))
+        .light_magenta(),
+    ]
+    .into()];
+    for ip in members {
+        let stats = summary.binds.get(&ip).cloned().unwrap_or_default();
+        let health = match stats.errors * 100 / stats.connections.max(1) {
+            _ if stats.connections == 0 => Span::raw(" idle").dark_gray(),
+            0..10 => Span::raw(" ok").green(),
+            10..50 => Span::raw(" flaky").yellow(),
+            _ => Span::raw(" failing").red(),
+        };
+        lines.push(Line::from(Span::raw(format!(" {}", ip)).blue()));
+        lines.push(
+            vec![
+                Span::raw(" ".repeat(5)),
+                Span::raw(format!(
+                    "{:>width$}{:>width$}",
+                    open.get(ip.as_str()).copied().unwrap_or(0),
+                    stats.connections,
+                    width = WIDGETS_TIME_LEN + 1
+                ))
+                .cyan(),
+                Span::raw(format!(
+                    " {:>width$.1} {:>width$.1}",
+                    stats.upload as f32 / 1024f32,
+                    stats.download as f32 / 1024f32,
+                    width = WIDGETS_SPEED_LEN
+                ))
+                .light_magenta(),
+                health,
+            ]
+            .into(),
+        );
+    }
+    lines
+}
+
+/// The listeners and what their rules do with the connections.
+fn config_lines(cfg: &Config, routings: &[Arc<Routing>]) -> Vec<Line<'static>> {
+    let pool = |pool: &IpPool| {
+        let members: Vec<_> = pool.members().iter().map(|x| x.to_string()).collect();
+        format!("{} round-robin", members.join(", "))
+    };
+    let action = |action: &Action| match action {
+        Action::Pool(p) => format!("pool {}", pool(p)),
+        Action::Direct => "direct".to_owned(),
+        Action::Upstream(upstreams) => {
+            let addrs: Vec<_> = upstreams.addrs().iter().map(|x| x.to_string()).collect();
+            format!(
+                "upstream {} {}",
+                addrs.join(", "),
+                upstreams.balance().name()
+            )
+        }
+    };
+    let ipv6 = match cfg.ipv6_first {
+        None => "as resolved",
+        Some(true) => "ipv6 first",
+        Some(false) => "ipv4 first",
+    };
+    let mut lines = vec![
+        Line::from(vec![
+            Span::raw(" timeouts").blue().bold(),
+            Span::raw(format!(
+                "  connect {} ms  retry {} ms  total {}  io {} ms",
+                cfg.connect_ttl.as_millis(),
+                cfg.retry_ttl.as_millis(),
+                cfg.total_ttl
+                    .map_or("none".to_owned(), |x| format!("{} ms", x.as_millis())),
+                cfg.io_ttl.as_millis()
+            )),
+        ]),
+        Line::from(vec![
+            Span::raw(" addresses").blue().bold(),
+            Span::raw(format!("  {}", ipv6)),
+        ]),
+    ];
+    let mut pools: Vec<_> = cfg.pools.iter().collect();
+    pools.sort_by_key(|(name, _)| *name);
+    for (name, p) in pools {
+        lines.push(Line::from(vec![
+            Span::raw(format!(" pool {} ", name)).blue().bold(),
+            Span::raw(pool(p)),
+        ]));
+    }
+    for routing in routings {
+        let listeners: Vec<_> = routing.host.iter().map(|x| x.to_string()).collect();
+        let rules = routing.rules();
+        lines.push(Line::default());
+        lines.push(Line::from(vec![
+            Span::raw(" listen ").blue().bold(),
+            Span::raw(listeners.join(", ")).cyan(),
+            Span::raw(format!(
+                "  {} rules loaded {}",
+                rules.rule.len(),
+                logger::rfc3339(rules.loaded)
+            ))
+            .dark_gray(),
+        ]));
+        for (i, rule) in rules.rule.iter().enumerate() {
+            let mut flags = String::new();
+            if rule.capture {
+                flags += "  capture";
+            }
+            if rule.log {
+                flags += "  log";
+            }
+            lines.push(Line::from(vec![
+                Span::raw(format!("   rule {} ", i + 1)).dark_gray(),
+                Span::raw(action(&rule.action)),
+                Span::raw(flags).yellow(),
+            ]));
+        }
+        lines.push(Line::from(vec![
+            Span::raw("   default ").dark_gray(),
+            Span::raw(action(&rules.default)),
+        ]));
+    }
+    lines
+}
+
+/// `lines` from the cursor on, the selected one highlighted.
+fn scrolled<'a>(lines: Vec<Line<'a>>, cursor: &mut Cursor, height: u16) -> Vec<Line<'a>> {
+    cursor.fit(lines.len(), height as usize);
+    lines
+        .into_iter()
+        .enumerate()
+        .skip(cursor.offset)
+        .map(|(i, line)| match i == cursor.selected {
+            true => line.reversed(),
+            false => line,
+        })
+        .collect()
+}
+
+/// `n` bytes in the largest unit that keeps a number above 1.
+pub fn human_bytes(n: u64) -> String {
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut size = n as f64 / 1024.0;
+    for unit in ["KB", "MB", "GB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} TB", size)
+}
+
+/// What was served since startup.
+fn served() -> Line<'static> {
+    let summary = SUMMARY.lock().unwrap();
+    vec![
+        Span::raw(format!(" served {}", summary.connections)).cyan(),
+        Span::raw(format!("  open {}", summary.open())).cyan(),
         Span::raw(format!(
-            "{:>width$} {:>width$}",
-            "⇧KB",
-            "⇩KB",
-            width = WIDGETS_SPEED_LEN
+            "  {} {}  {} {}",
+            icons().up,
+            human_bytes(summary.upload),
+            icons().down,
+            human_bytes(summary.download)
         ))
         .light_magenta(),
-        Span::raw("🔰").blue().bold(),
+        Span::raw(format!("  errors {}", summary.errors)).red(),
     ]
-    .into();
+    .into()
+}
+
+/// Gauges to see trouble coming.
+fn footer(paused: bool, keep: Duration) -> Line<'static> {
+    let summary = SUMMARY.lock().unwrap();
+    let mut line = vec![
+        Span::raw(format!(" pending {}", summary.pending())).yellow(),
+        Span::raw(format!("  relays {}", crate::handle::relays())).light_magenta(),
+        Span::raw(format!(
+            "  refresh {} ms  keep {:.1} s",
+            frame_interval().as_millis(),
+            keep.as_secs_f32()
+        ))
+        .dark_gray(),
+    ];
+    if paused {
+        line.push(Span::raw("  paused, 'p' to go on").black().on_yellow());
+    }
+    line.into()
+}
+
+pub fn frame_interval() -> Duration {
+    Duration::from_millis(FRAME_INTERVAL.load(Ordering::Relaxed))
+}
+
+pub fn set_frame_interval(interval: Duration) {
+    FRAME_INTERVAL.store(interval.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Events waiting in the channel to the drawer at most, `Feed` holds back the rest.
+const FEED_SIZE: usize = 1024;
+/// Events held back at most, the oldest go first beyond that.
+const MAX_BACKLOG: usize = 16384;
+
+/// The events on their way to the drawer, held back while it doesn't keep up.
+/// Held back transfers of a connection are added up, so a stalled drawer
+/// costs one entry per connection instead of one per frame.
+pub struct Feed {
+    tx: mpsc::SyncSender<(usize, Event)>,
+    backlog: VecDeque<(usize, Event)>,
+    /// bytes up and down not sent yet, by connection
+    transfers: HashMap<usize, (usize, usize)>,
+}
+impl Feed {
+    pub fn new() -> (Self, mpsc::Receiver<(usize, Event)>) {
+        let (tx, rx) = mpsc::sync_channel(FEED_SIZE);
+        let feed = Feed {
+            tx,
+            backlog: VecDeque::new(),
+            transfers: HashMap::new(),
+        };
+        (feed, rx)
+    }
+
+    /// False once the drawer is gone.
+    pub fn send(&mut self, id: usize, event: Event) -> bool {
+        self.hold(id, event);
+        loop {
+            // transfers go once nothing else waits
+            let (next, held) = match self.backlog.pop_front() {
+                Some(next) => (next, true),
+                None => match self.take_transfer() {
+                    Some(next) => (next, false),
+                    None => break,
+                },
+            };
+            match self.tx.try_send(next) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(next)) if held => {
+                    self.backlog.push_front(next);
+                    break;
+                }
+                Err(mpsc::TrySendError::Full((id, transfer))) => {
+                    self.hold(id, transfer);
+                    break;
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        true
+    }
+
+    fn hold(&mut self, id: usize, event: Event) {
+        match event {
+            Event::Upload(n) => self.transfers.entry(id).or_default().0 += n,
+            Event::Download(n) => self.transfers.entry(id).or_default().1 += n,
+            // a frame tick is only worth something when nothing waits
+            _ if id == 0 && !self.backlog.is_empty() => {}
+            event => {
+                // what a connection moved comes before what happened to it next
+                if let Some((up, down)) = self.transfers.remove(&id) {
+                    if up > 0 {
+                        self.backlog.push_back((id, Event::Upload(up)));
+                    }
+                    if down > 0 {
+                        self.backlog.push_back((id, Event::Download(down)));
+                    }
+                }
+                self.backlog.push_back((id, event));
+                while self.backlog.len() > MAX_BACKLOG {
+                    self.backlog.pop_front();
+                }
+            }
+        }
+    }
+
+    fn take_transfer(&mut self) -> Option<(usize, Event)> {
+        let (&id, pending) = self.transfers.iter_mut().next()?;
+        let event = if pending.0 > 0 {
+            Event::Upload(std::mem::take(&mut pending.0))
+        } else {
+            Event::Download(std::mem::take(&mut pending.1))
+        };
+        if *pending == (0, 0) {
+            self.transfers.remove(&id);
+        }
+        Some((id, event))
+    }
+}
+
+/// The alternate screen in raw mode for as long as it lives, however the
+/// drawer ends.
+struct Screen;
+impl Screen {
+    fn enter() -> std::io::Result<Self> {
+        stdout().execute(EnterAlternateScreen)?;
+        let screen = Screen;
+        enable_raw_mode()?;
+        Ok(screen)
+    }
+}
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = stdout().execute(LeaveAlternateScreen);
+    }
+}
+
+pub fn drawer(
+    recv: mpsc::Receiver<(usize, Event)>,
+    cfg: &Config,
+    routings: Vec<Arc<Routing>>,
+) -> std::io::Result<()> {
+    let _screen = Screen::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    terminal.clear()?;
+
+    let title =
+        |filter: &Filter, search: &Search, by: SortBy, descending: bool, totals: bool| -> Line {
+            let unit = if totals { "KB" } else { "KB/s" };
+            vec![
+                Span::raw(format!("{:>width$}", "time", width = WIDGETS_TIME_LEN)).cyan(),
+                Span::raw(format!(
+                    "{} {}",
+                    align_right(&format!("{}{}", icons().up, unit), WIDGETS_SPEED_LEN),
+                    align_right(&format!("{}{}", icons().down, unit), WIDGETS_SPEED_LEN),
+                ))
+                .light_magenta(),
+                Span::raw(align_left(icons().title, WIDGETS_STATE_LEN))
+                    .blue()
+                    .bold(),
+                Span::raw(format!(
+                    " by {} {}",
+                    by.name(),
+                    if descending {
+                        icons().descending
+                    } else {
+                        icons().ascending
+                    }
+                ))
+                .dark_gray(),
+                Span::raw(filter.describe()).yellow(),
+                Span::raw(search.prompt.describe("/")).light_yellow(),
+            ]
+            .into()
+        };
 
     let out_layout = Layout::default()
         .direction(Direction::Vertical)
-        .constraints(vec![Constraint::Length(1), Constraint::Fill(1)]);
+        .constraints(vec![
+            Constraint::Length(1),
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Length(2),
+        ]);
 
-    let mut summary = Summary::new();
+    let mut summary = Summary::new(cfg.tui_keep, cfg.tui_smoothing);
+    let mut tab = Tab::default();
+    let mut cursor = Cursor::default();
+    // where the pages other than the connections are scrolled to
+    let mut page = Cursor::default();
+    let mut sort_by = SortBy::default();
+    let mut descending = false;
+    let mut filter = Filter::default();
+    let mut search = Search::default();
+    let mut totals = false;
+    let mut paused = false;
+    let mut show_pools = false;
+    let mut show_errors = false;
+    // a row per destination host instead of per connection
+    let mut by_host = false;
+    // the frame telling about the pause
+    let mut redraw = false;
 
     for (id, event) in recv {
         summary.update(id, event);
-        if id == 0 {
+        if id != 0 {
+            continue;
+        }
+        // paused, the screen stays as it is while the events keep being counted
+        if !paused || redraw {
+            redraw = false;
             terminal.draw(|frame| {
                 // .split(frame.size());
                 // let area = frame.size();
                 let out_layout = out_layout.split(frame.area());
-                frame.render_widget(Paragraph::new(title.clone()), out_layout[0]);
+                let selected = Tab::ALL.iter().position(|&x| x == tab).unwrap_or(0);
                 frame.render_widget(
-                    Paragraph::new(
-                        summary
-                            .jobs()
+                    Tabs::new(
+                        Tab::ALL
                             .iter()
-                            .map(|(_, x)| x.to_line())
-                            .collect::<Vec<Line>>(),
-                    ),
-                    out_layout[1],
+                            .enumerate()
+                            .map(|(i, x)| format!("{} {}", i + 1, x.name())),
+                    )
+                    .select(selected)
+                    .divider(icons().divider)
+                    .highlight_style(Style::new().reversed()),
+                    out_layout[0],
+                );
+                throughput(frame, out_layout[1], &summary);
+                let page_area = out_layout[2];
+                // the first line of the hosts and the pool stays while the rest scrolls
+                let [header_area, rows_area] =
+                    *Layout::vertical([Constraint::Length(1), Constraint::Fill(1)])
+                        .split(page_area)
+                else {
+                    unreachable!()
+                };
+                match tab {
+                    Tab::Connections => {
+                        let [title_area, middle_area, hosts_area] = *Layout::vertical([
+                            Constraint::Length(1),
+                            Constraint::Fill(1),
+                            Constraint::Length(TOP_HOSTS as u16 + 1),
+                        ])
+                        .split(page_area) else {
+                            unreachable!()
+                        };
+                        let mut title = title(&filter, &search, sort_by, descending, totals);
+                        if by_host {
+                            title.push_span(
+                                Span::raw(" per host, a for each connection").light_blue(),
+                            );
+                        }
+                        if summary.history {
+                            title.push_span(
+                                Span::raw(" finished, Tab for the open ones").light_blue(),
+                            );
+                        }
+                        frame.render_widget(Paragraph::new(title), title_area);
+                        let detail = summary
+                            .detail
+                            .and_then(|id| Some((id, summary.get(id)?.detail_lines())));
+                        let [main_area, detail_area, error_area] = *Layout::vertical([
+                            Constraint::Fill(1),
+                            Constraint::Length(
+                                detail.as_ref().map_or(0, |(_, x)| x.len() as u16 + 2),
+                            ),
+                            Constraint::Length(if show_errors { ERROR_PANE_HEIGHT } else { 0 }),
+                        ])
+                        .split(middle_area) else {
+                            unreachable!()
+                        };
+                        if show_errors {
+                            // the newest at the bottom, long ones wrap
+                            let lines: Vec<_> =
+                                summary.errors.iter().map(ErrorLine::to_line).collect();
+                            let shown = (ERROR_PANE_HEIGHT as usize - 2).min(lines.len());
+                            frame.render_widget(
+                                Paragraph::new(lines[lines.len() - shown..].to_vec())
+                                    .wrap(Wrap { trim: false })
+                                    .block(
+                                        Block::new()
+                                            .borders(Borders::TOP)
+                                            .border_set(icons().border)
+                                            .title(" errors, l to close "),
+                                    ),
+                                error_area,
+                            );
+                        }
+                        let [list_area, pool_area] = *Layout::horizontal([
+                            Constraint::Fill(1),
+                            Constraint::Length(if show_pools { POOL_PANEL_WIDTH } else { 0 }),
+                        ])
+                        .split(main_area) else {
+                            unreachable!()
+                        };
+                        if show_pools {
+                            frame.render_widget(Paragraph::new(pool_lines(&routings)), pool_area);
+                        }
+                        if let Some((id, lines)) = detail {
+                            frame.render_widget(
+                                Paragraph::new(lines).block(
+                                    Block::new()
+                                        .borders(Borders::ALL)
+                                        .border_set(icons().border)
+                                        .title(format!(" connection {}, Enter to close ", id)),
+                                ),
+                                detail_area,
+                            );
+                        }
+                        let jobs = summary.rows(&filter, sort_by, descending);
+                        let lines: Vec<_> = match by_host {
+                            false => jobs
+                                .into_iter()
+                                .map(|(_, x)| (x.to_line(totals), search.matches(x)))
+                                .collect(),
+                            true => HostRow::aggregate(&jobs, sort_by, descending)
+                                .into_iter()
+                                .map(|x| (x.to_line(totals), search.matches_text(&x.host)))
+                                .collect(),
+                        };
+                        cursor.fit(lines.len(), list_area.height as usize);
+                        frame.render_widget(
+                            Paragraph::new(
+                                lines
+                                    .into_iter()
+                                    .enumerate()
+                                    .skip(cursor.offset)
+                                    .map(|(i, (line, matched))| {
+                                        let line = match matched {
+                                            true => line.underlined(),
+                                            false => line,
+                                        };
+                                        match i == cursor.selected {
+                                            true => line.reversed(),
+                                            false => line,
+                                        }
+                                    })
+                                    .collect::<Vec<Line>>(),
+                            ),
+                            list_area,
+                        );
+                        frame.render_widget(Paragraph::new(host_lines(TOP_HOSTS)), hosts_area);
+                    }
+                    Tab::Hosts | Tab::Pool => {
+                        let mut lines = match tab {
+                            Tab::Hosts => host_lines(usize::MAX),
+                            _ => pool_lines(&routings),
+                        };
+                        let header = lines.remove(0);
+                        frame.render_widget(Paragraph::new(header), header_area);
+                        let lines = scrolled(lines, &mut page, rows_area.height);
+                        frame.render_widget(Paragraph::new(lines), rows_area);
+                    }
+                    Tab::Errors => {
+                        // the newest first
+                        let lines = summary
+                            .errors
+                            .iter()
+                            .rev()
+                            .map(ErrorLine::to_line)
+                            .collect();
+                        let lines = scrolled(lines, &mut page, page_area.height);
+                        frame.render_widget(Paragraph::new(lines), page_area);
+                    }
+                    Tab::Config => {
+                        let lines =
+                            scrolled(config_lines(cfg, &routings), &mut page, page_area.height);
+                        frame.render_widget(Paragraph::new(lines), page_area);
+                    }
+                }
+                frame.render_widget(
+                    Paragraph::new(vec![served(), footer(paused, summary.keep)]),
+                    out_layout[3],
                 );
             })?;
-            if event::poll(FRAME_INTERVAL)? {
-                if let event::Event::Key(key) = event::read()? {
-                    if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('q') {
-                        break;
+        }
+        if event::poll(frame_interval())? {
+            if let event::Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && search.prompt.key(key.code) {
+                    if by_host {
+                        continue;
+                    }
+                    // jump while typing, from the row the cursor is on
+                    let rows = summary.rows(&filter, sort_by, descending);
+                    let selected = cursor.selected.min(rows.len().saturating_sub(1));
+                    if let Some(i) = search.find(&rows, selected, false) {
+                        cursor.selected = i;
+                    }
+                } else if key.kind == KeyEventKind::Press && !filter.host.key(key.code) {
+                    match key.code {
+                        KeyCode::Char('q') => break,
+                        KeyCode::Char(c @ '1'..='9') => {
+                            if let Some(x) = Tab::from_key(c) {
+                                tab = x;
+                                page = Cursor::default();
+                            }
+                        }
+                        KeyCode::Char('e') => history::export(),
+                        KeyCode::Char('x') if tab == Tab::Connections => {
+                            let rows = summary.rows(&filter, sort_by, descending);
+                            match by_host {
+                                false => export_view(
+                                    &CONNECTION_FIELDS,
+                                    rows.iter().map(|(id, x)| x.csv_fields(*id)).collect(),
+                                ),
+                                true => export_view(
+                                    &HOST_FIELDS,
+                                    HostRow::aggregate(&rows, sort_by, descending)
+                                        .iter()
+                                        .map(HostRow::csv_fields)
+                                        .collect(),
+                                ),
+                            }
+                        }
+                        KeyCode::Char('s') => sort_by = sort_by.next(),
+                        KeyCode::Char('r') => descending = !descending,
+                        KeyCode::Char('f') => filter.only = filter.only.next(),
+                        KeyCode::Char('h') => filter.host.typing = true,
+                        KeyCode::Char('/') => search.prompt.typing = true,
+                        code @ (KeyCode::Char('n') | KeyCode::Char('N'))
+                            if tab == Tab::Connections && !by_host =>
+                        {
+                            let rows = summary.rows(&filter, sort_by, descending);
+                            let selected = cursor.selected.min(rows.len().saturating_sub(1));
+                            let found = match code == KeyCode::Char('N') {
+                                false => search.find(&rows, selected + 1, false),
+                                true => search.find(
+                                    &rows,
+                                    (selected + rows.len()).saturating_sub(1),
+                                    true,
+                                ),
+                            };
+                            if let Some(i) = found {
+                                cursor.selected = i;
+                            }
+                        }
+                        KeyCode::Char('t') => totals = !totals,
+                        // into the connections of the host
+                        KeyCode::Enter if tab == Tab::Hosts => {
+                            let stats = SUMMARY.lock().unwrap();
+                            let hosts = stats.top_hosts(usize::MAX);
+                            if let Some(&(host, _)) = hosts.get(page.selected) {
+                                filter.host.text = host.clone();
+                                tab = Tab::Connections;
+                                by_host = false;
+                                cursor.selected = 0;
+                            }
+                        }
+                        KeyCode::Enter if tab != Tab::Connections => {}
+                        KeyCode::Enter if summary.detail.is_some() => summary.detail = None,
+                        // into the connections of the host
+                        KeyCode::Enter if by_host => {
+                            let rows = summary.rows(&filter, sort_by, descending);
+                            let hosts = HostRow::aggregate(&rows, sort_by, descending);
+                            if let Some(row) = hosts.get(cursor.selected) {
+                                filter.host.text = row.host.clone();
+                                by_host = false;
+                                cursor.selected = 0;
+                            }
+                        }
+                        KeyCode::Enter => {
+                            let rows = summary.rows(&filter, sort_by, descending);
+                            summary.detail = rows.get(cursor.selected).map(|&(id, _)| id);
+                        }
+                        KeyCode::Esc => summary.detail = None,
+                        KeyCode::Char('+') => {
+                            set_frame_interval((frame_interval() / 2).max(MIN_FRAME_INTERVAL))
+                        }
+                        KeyCode::Char('-') => {
+                            set_frame_interval((frame_interval() * 2).min(MAX_FRAME_INTERVAL))
+                        }
+                        KeyCode::Char('>') => summary.keep = (summary.keep * 2).min(MAX_KEEP),
+                        KeyCode::Char('<') => summary.keep /= 2,
+                        KeyCode::Char('b') => show_pools = !show_pools,
+                        KeyCode::Char('l') => show_errors = !show_errors,
+                        KeyCode::Tab => summary.history = !summary.history,
+                        KeyCode::Char('a') => {
+                            by_host = !by_host;
+                            cursor.selected = 0;
+                        }
+                        KeyCode::Char('k') if tab == Tab::Connections && !by_host => {
+                            let rows = summary.rows(&filter, sort_by, descending);
+                            if let Some(&(id, _)) = rows.get(cursor.selected) {
+                                summary.kill(id);
+                            }
+                        }
+                        KeyCode::Char('p') => {
+                            paused = !paused;
+                            redraw = true;
+                        }
+                        code if tab == Tab::Connections => cursor.key(code),
+                        code => page.key(code),
                     }
                 }
             }
         }
     }
-    stdout().execute(LeaveAlternateScreen)?;
-    disable_raw_mode()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received() -> Event {
+        Event::Received(([127, 0, 0, 1], 1000).into())
+    }
+
+    #[test]
+    fn stalled_feed_adds_transfers_up() {
+        let (mut feed, rx) = Feed::new();
+        for id in 1..=FEED_SIZE {
+            assert!(feed.send(id, received()));
+        }
+        // the drawer reads nothing, every connection keeps moving bytes
+        for _ in 0..100 {
+            for id in 1..=10 {
+                feed.send(id, Event::Upload(1));
+                feed.send(id, Event::Download(2));
+            }
+        }
+        assert!(feed.backlog.is_empty());
+        assert_eq!(feed.transfers.len(), 10);
+        assert_eq!(feed.transfers[&1], (100, 200));
+        // what connection 1 moved arrives before it is done
+        feed.send(
+            1,
+            Event::Done {
+                bytes_up: 100,
+                bytes_down: 200,
+                duration: Duration::ZERO,
+            },
+        );
+        let held: Vec<_> = feed
+            .backlog
+            .iter()
+            .map(|(id, e)| (*id, format!("{e:?}")))
+            .collect();
+        assert_eq!(held.len(), 3);
+        assert_eq!(held[0], (1, "Upload(100)".to_owned()));
+        assert_eq!(held[1], (1, "Download(200)".to_owned()));
+        drop(rx);
+        assert!(!feed.send(2, received()));
+    }
+
+    #[test]
+    fn backlog_drops_the_oldest() {
+        let (mut feed, _rx) = Feed::new();
+        for id in 1..=FEED_SIZE + MAX_BACKLOG + 10 {
+            feed.send(id, received());
+        }
+        assert_eq!(feed.backlog.len(), MAX_BACKLOG);
+        assert_eq!(feed.backlog.front().unwrap().0, FEED_SIZE + 11);
+        // a tick is left out while events wait
+        feed.send(0, received());
+        assert_eq!(feed.backlog.back().unwrap().0, FEED_SIZE + MAX_BACKLOG + 10);
+    }
+}
@@ -1,12 +1,121 @@
-use std::{borrow::Cow, net::IpAddr};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+/// Why a connection failed, so it can be counted and shown without reading the message.
+/// Saved by its `name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    DnsFailure,
+    ConnectTimeout,
+    ConnectRefused,
+    /// the client didn't speak proper http
+    ProtocolViolation,
+    /// the client isn't allowed what it asked for
+    Forbidden,
+    /// turned away, there were `max_connections` already
+    Overloaded,
+    /// none of the pool addresses could be bound
+    PoolBindFailed,
+    IoError,
+}
+impl ErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::DnsFailure => "dns_failure",
+            ErrorKind::ConnectTimeout => "connect_timeout",
+            ErrorKind::ConnectRefused => "connect_refused",
+            ErrorKind::ProtocolViolation => "protocol_violation",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::Overloaded => "overloaded",
+            ErrorKind::PoolBindFailed => "pool_bind_failed",
+            ErrorKind::IoError => "io_error",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
-    Received(IpAddr),
+    /// the address of the client
+    Received(SocketAddr),
+    /// the accounting group of the client, if it is in one
+    Grouped(String),
     Resolved(String),
+    /// the addresses of the destination are known
+    LookedUp(),
+    /// the name the client asked TLS for, when it connected to a bare IP
+    Sni(String),
     Connected(IpAddr, IpAddr),
-    Done(),
+    /// the totals of the connection, so nobody has to add up the transfers
+    Done {
+        bytes_up: u64,
+        bytes_down: u64,
+        duration: Duration,
+    },
     Upload(usize),
     Download(usize),
-    Retry(),
-    Error(Cow<'static, str>),
+    /// connecting to this address failed, the next one is tried
+    Retry(SocketAddr),
+    /// binding this pool address failed, the next one is tried
+    PoolBindFailed(IpAddr),
+    Error(ErrorKind, Cow<'static, str>),
+    /// an io error cut the relay short in one direction, with the totals up to
+    /// then, it ends the connection like `Error` with `ErrorKind::IoError`
+    RelayFailed {
+        upload: bool,
+        error: Cow<'static, str>,
+        bytes_up: u64,
+        bytes_down: u64,
+        duration: Duration,
+    },
+}
+impl Event {
+    /// Whether this is the last event of its connection.
+    pub fn ends(&self) -> bool {
+        matches!(
+            self,
+            Event::Done { .. } | Event::Error(..) | Event::RelayFailed { .. }
+        )
+    }
+
+    /// What went wrong, for the events that end a connection in failure.
+    pub fn failure(&self) -> Option<(ErrorKind, Cow<'static, str>)> {
+        match self {
+            Event::Error(kind, e) => Some((*kind, e.clone())),
+            Event::RelayFailed { upload, error, .. } => Some((
+                ErrorKind::IoError,
+                format!("{} failed: {}", direction(*upload), error).into(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// The bytes each way, for the events that carry the totals.
+    pub fn totals(&self) -> Option<(u64, u64)> {
+        match *self {
+            Event::Done {
+                bytes_up,
+                bytes_down,
+                ..
+            }
+            | Event::RelayFailed {
+                bytes_up,
+                bytes_down,
+                ..
+            } => Some((bytes_up, bytes_down)),
+            _ => None,
+        }
+    }
+}
+
+/// The name of a relay direction.
+pub fn direction(upload: bool) -> &'static str {
+    if upload {
+        "upload"
+    } else {
+        "download"
+    }
 }
@@ -0,0 +1,60 @@
+use std::io;
+
+/// Connections accepted with data in the SYN waiting for the accept loop at most.
+#[cfg(target_os = "linux")]
+const QUEUE: i32 = 256;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        ffi::{c_int, c_void},
+        io,
+        os::unix::io::RawFd,
+    };
+
+    const IPPROTO_TCP: c_int = 6;
+    pub const TCP_FASTOPEN: c_int = 23;
+    pub const TCP_FASTOPEN_CONNECT: c_int = 30;
+
+    extern "C" {
+        fn setsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+    }
+
+    pub fn set(fd: RawFd, name: c_int, value: c_int) -> io::Result<()> {
+        let len = std::mem::size_of::<c_int>() as u32;
+        // SAFETY: `value` lives for the call and `len` is its size
+        match unsafe { setsockopt(fd, IPPROTO_TCP, name, &value as *const c_int as _, len) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Let a socket about to connect put its first write into the SYN. The
+/// connect then returns at once, a refused connection shows at the first write.
+#[cfg(target_os = "linux")]
+pub fn connect(socket: &impl std::os::unix::io::AsRawFd) -> io::Result<()> {
+    sys::set(socket.as_raw_fd(), sys::TCP_FASTOPEN_CONNECT, 1)
+}
+
+/// Take data in the SYN of the clients that offer it.
+#[cfg(target_os = "linux")]
+pub fn listen(listener: &impl std::os::unix::io::AsRawFd) -> io::Result<()> {
+    sys::set(listener.as_raw_fd(), sys::TCP_FASTOPEN, QUEUE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn connect<T>(_socket: &T) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen<T>(_listener: &T) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
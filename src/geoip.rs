@@ -0,0 +1,422 @@
+use crate::{Error, Result};
+use std::net::IpAddr;
+
+// a minimal reader of the MaxMind DB format, just enough to find
+// the country code and the autonomous system number of an address.
+// spec: https://maxmind.github.io/MaxMind-DB/
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const DATA_SEPARATOR: usize = 16;
+/// Real databases nest a few levels, deeper is a pointer loop.
+const MAX_DEPTH: usize = 32;
+/// Values decoded for one record at most, pointers could multiply them.
+const MAX_VALUES: usize = 100_000;
+
+#[derive(Default, Debug, Clone)]
+pub struct Info {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Map(Vec<(String, Value)>),
+    // floats, bytes, booleans and arrays are never looked at
+    Other,
+}
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(x) => Some(*x),
+            Value::Int(x) => u128::try_from(*x).ok(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Reader {
+    buf: Box<[u8]>,
+    node_count: usize,
+    record_size: usize,
+    data_start: usize,
+    ipv4_start: usize,
+    ipv6: bool,
+}
+impl Reader {
+    pub fn open(file_name: &str) -> Result<Self> {
+        Self::from_bytes(std::fs::read(file_name)?.into_boxed_slice())
+    }
+
+    fn from_bytes(buf: Box<[u8]>) -> Result<Self> {
+        let meta_start = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(Error::GeoIpError("metadata not found"))?
+            + METADATA_MARKER.len();
+        let (meta, _) = decode(&buf[meta_start..], 0)?;
+        let field = |key| {
+            meta.get(key)
+                .and_then(Value::as_uint)
+                .ok_or(Error::GeoIpError("bad metadata"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(Error::GeoIpError("unsupported record size"));
+        }
+        let data_start = (record_size * 2 / 8)
+            .checked_mul(node_count)
+            .and_then(|x| x.checked_add(DATA_SEPARATOR))
+            .filter(|x| *x <= meta_start)
+            .ok_or(Error::GeoIpError("truncated database"))?;
+        let mut reader = Self {
+            buf,
+            node_count,
+            record_size,
+            data_start,
+            ipv4_start: 0,
+            ipv6: ip_version == 6,
+        };
+        if reader.ipv6 {
+            // ipv4 addresses live under ::/96
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.record(node, 0);
+            }
+            reader.ipv4_start = node;
+        }
+        Ok(reader)
+    }
+
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let b = &self.buf[node * self.record_size * 2 / 8..];
+        let be = |s: &[u8]| s.iter().fold(0usize, |acc, x| acc << 8 | *x as usize);
+        match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (b[3] as usize & 0xf0) << 20 | be(&b[0..3]),
+            (28, _) => (b[3] as usize & 0x0f) << 24 | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+
+    fn find(&self, ip: IpAddr) -> Option<usize> {
+        let (bits, mut node): (Vec<u8>, usize) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => (ip.octets().to_vec(), self.ipv4_start),
+                None if self.ipv6 => (ip.octets().to_vec(), 0),
+                None => return None,
+            },
+        };
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bits[i / 8] >> (7 - i % 8) & 1);
+        }
+        // node_count means no data for this address, less is still in the tree
+        node.checked_sub(self.node_count + DATA_SEPARATOR)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Info> {
+        let offset = self.find(ip)?;
+        let (record, _) = decode(&self.buf[self.data_start..], offset).ok()?;
+        let country = record
+            .get("country")
+            .or_else(|| record.get("registered_country"))
+            .and_then(|c| c.get("iso_code"))
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+        let asn = record
+            .get("autonomous_system_number")
+            .and_then(Value::as_uint)
+            .map(|x| x as u32);
+        Some(Info { country, asn })
+    }
+}
+
+fn decode(data: &[u8], offset: usize) -> Result<(Value, usize)> {
+    let mut left = MAX_VALUES;
+    decode_nested(data, offset, 0, &mut left)
+}
+
+fn decode_nested(
+    data: &[u8],
+    offset: usize,
+    depth: usize,
+    left: &mut usize,
+) -> Result<(Value, usize)> {
+    let err = || Error::GeoIpError("corrupted data section");
+    if depth > MAX_DEPTH || *left == 0 {
+        return Err(err());
+    }
+    *left -= 1;
+    let mut decode = |offset| decode_nested(data, offset, depth + 1, left);
+    let byte = |i: usize| data.get(i).copied().ok_or_else(err);
+    let be = |from: usize, len: usize| -> Result<u128> {
+        let s = data.get(from..from + len).ok_or_else(err)?;
+        Ok(s.iter().fold(0u128, |acc, x| acc << 8 | *x as u128))
+    };
+
+    let ctrl = byte(offset)?;
+    let mut pos = offset + 1;
+    let mut kind = ctrl >> 5;
+    if kind == 1 {
+        let (ss, vvv) = ((ctrl >> 3) & 3, (ctrl & 7) as usize);
+        let target = match ss {
+            0 => vvv << 8 | be(pos, 1)? as usize,
+            1 => (vvv << 16 | be(pos, 2)? as usize) + 2048,
+            2 => (vvv << 24 | be(pos, 3)? as usize) + 526336,
+            _ => be(pos, 4)? as usize,
+        };
+        let (value, _) = decode(target)?;
+        return Ok((value, pos + ss as usize + 1));
+    }
+    if kind == 0 {
+        kind = byte(pos)?.checked_add(7).ok_or_else(err)?;
+        pos += 1;
+    }
+    let mut size = (ctrl & 0x1f) as usize;
+    match size {
+        29 => {
+            size = 29 + be(pos, 1)? as usize;
+            pos += 1;
+        }
+        30 => {
+            size = 285 + be(pos, 2)? as usize;
+            pos += 2;
+        }
+        31 => {
+            size = 65821 + be(pos, 3)? as usize;
+            pos += 3;
+        }
+        _ => {}
+    }
+    let value = match kind {
+        2 => {
+            let s = data.get(pos..pos + size).ok_or_else(err)?;
+            pos += size;
+            Value::String(String::from_utf8_lossy(s).into_owned())
+        }
+        3 => {
+            pos += 8;
+            Value::Other
+        }
+        4 => {
+            pos += size;
+            Value::Other
+        }
+        5 | 6 | 9 | 10 => {
+            let x = be(pos, size)?;
+            pos += size;
+            Value::Uint(x)
+        }
+        7 => {
+            let mut map = Vec::new();
+            for _ in 0..size {
+                let (key, next) = decode(pos)?;
+                let (value, next) = decode(next)?;
+                pos = next;
+                map.push((key.as_str().ok_or_else(err)?.to_owned(), value));
+            }
+            Value::Map(map)
+        }
+        8 => {
+            let x = be(pos, size)? as u32 as i32;
+            pos += size;
+            Value::Int(x)
+        }
+        11 => {
+            for _ in 0..size {
+                (_, pos) = decode(pos)?;
+            }
+            Value::Other
+        }
+        14 => Value::Other,
+        15 => {
+            pos += 4;
+            Value::Other
+        }
+        _ => return Err(err()),
+    };
+    Ok((value, pos))
+}
+
+#[derive(Default)]
+pub struct GeoIp {
+    readers: Vec<Reader>,
+}
+impl GeoIp {
+    pub fn open(file_names: &[String]) -> Result<Self> {
+        let readers = file_names
+            .iter()
+            .map(|f| Reader::open(f))
+            .collect::<Result<_>>()?;
+        Ok(Self { readers })
+    }
+    /// Merge what every database knows about `ip`,
+    /// e.g. a Country database and an ASN database.
+    pub fn lookup(&self, ip: IpAddr) -> Info {
+        let mut info = Info::default();
+        for reader in &self.readers {
+            if let Some(x) = reader.lookup(ip) {
+                info.country = info.country.or(x.country);
+                info.asn = info.asn.or(x.asn);
+            }
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut data = vec![0x40 | s.len() as u8];
+        data.extend(s.as_bytes());
+        data
+    }
+
+    /// A uint16, or a uint32 for 6.
+    fn uint(kind: u8, x: u16) -> Vec<u8> {
+        [vec![kind << 5 | 2], x.to_be_bytes().to_vec()].concat()
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            data.extend(string(key));
+            data.extend(value);
+        }
+        data
+    }
+
+    /// One node with 24 bit records: 0.0.0.0/1 has `data`, 128.0.0.0/1 nothing.
+    fn database(right: u32, data: &[u8], metadata: &[u8]) -> Box<[u8]> {
+        let left = 1 + DATA_SEPARATOR as u32;
+        let mut buf = [&left.to_be_bytes()[1..], &right.to_be_bytes()[1..]].concat();
+        buf.extend([0; DATA_SEPARATOR]);
+        buf.extend(data);
+        buf.extend(METADATA_MARKER);
+        buf.extend(metadata);
+        buf.into_boxed_slice()
+    }
+
+    fn metadata(node_count: u16, record_size: u16) -> Vec<u8> {
+        map(&[
+            ("node_count", uint(6, node_count)),
+            ("record_size", uint(5, record_size)),
+            ("ip_version", uint(5, 4)),
+        ])
+    }
+
+    fn record() -> Vec<u8> {
+        map(&[
+            ("country", map(&[("iso_code", string("DE"))])),
+            ("autonomous_system_number", uint(6, 3320)),
+        ])
+    }
+
+    #[test]
+    fn lookup() {
+        let reader = Reader::from_bytes(database(1, &record(), &metadata(1, 24))).unwrap();
+        let info = reader.lookup([1, 2, 3, 4].into()).unwrap();
+        assert_eq!(info.country.as_deref(), Some("DE"));
+        assert_eq!(info.asn, Some(3320));
+        let mapped: IpAddr = "::ffff:1.2.3.4".parse().unwrap();
+        assert_eq!(reader.lookup(mapped).unwrap().asn, Some(3320));
+        assert!(reader.lookup([128, 0, 0, 1].into()).is_none());
+        // an IPv4 database knows nothing of IPv6
+        assert!(reader.lookup("2001:db8::1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn bad_metadata() {
+        let bad = |buf| Reader::from_bytes(buf).is_err();
+        assert!(bad(Box::from(&b"not a database"[..])));
+        assert!(bad(database(1, &record(), &metadata(1, 20))));
+        assert!(bad(database(
+            1,
+            &record(),
+            &map(&[("node_count", uint(6, 1))])
+        )));
+        // more nodes than the file has room for
+        assert!(bad(database(1, &record(), &metadata(u16::MAX, 32))));
+        assert!(bad(database(1, &record(), &[0xe1, 0x41])));
+    }
+
+    #[test]
+    fn record_into_the_separator() {
+        let reader = Reader::from_bytes(database(5, &record(), &metadata(1, 24))).unwrap();
+        assert!(reader.lookup([128, 0, 0, 1].into()).is_none());
+    }
+
+    #[test]
+    fn corrupted_data() {
+        let bad = |data: &[u8]| decode(data, 0).is_err();
+        // truncated string, map and uint
+        assert!(bad(&[0x45, b'a']));
+        assert!(bad(&[0xe2, 0x41, b'a', 0x41, b'b']));
+        assert!(bad(&[0xc4, 0, 0]));
+        // a map key must be a string
+        assert!(bad(&[0xe1, 0xa1, 1, 0x41, b'a']));
+        // unknown and overflowing extended types
+        assert!(bad(&[0x01, 0x05]));
+        assert!(bad(&[0x01, 0xff]));
+        // a map claiming 16M entries
+        assert!(bad(&[0xff, 0xff, 0xff, 0xff]));
+        assert!(bad(&[]));
+        assert!(decode(&[0x40], 1).is_err());
+    }
+
+    #[test]
+    fn pointer_loops() {
+        // a pointer to itself
+        assert!(decode(&[0x20, 0x00], 0).is_err());
+        // a map whose value points back to the map
+        assert!(decode(&[0xe1, 0x41, b'a', 0x20, 0x00], 0).is_err());
+        // a pointer to a string is fine
+        let (value, next) = decode(&[0x20, 0x02, 0x41, b'a'], 0).unwrap();
+        assert_eq!(value.as_str(), Some("a"));
+        assert_eq!(next, 2);
+    }
+
+    #[test]
+    fn pointer_fan_out() {
+        // every level is an array of 255 pointers to the level below
+        let mut data = vec![0x41, b'a'];
+        let mut below = 0u32;
+        for _ in 0..4 {
+            let level = data.len() as u32;
+            data.extend([0x1d, 0x04, 255 - 29]);
+            for _ in 0..255 {
+                data.push(0x38);
+                data.extend(below.to_be_bytes());
+            }
+            below = level;
+        }
+        assert!(decode(&data, below as usize).is_err());
+    }
+}
@@ -1,92 +1,675 @@
+use crate::capture;
 use crate::config;
-use crate::event::Event;
+use crate::dns;
+use crate::event::{ErrorKind, Event};
+use crate::fastopen;
+use crate::idn;
+use crate::keep_alive;
+use crate::shutdown;
+use crate::timeline;
+use crate::upstream::Upstreams;
 use crate::Result;
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     io::{self, prelude::*},
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
-    sync::{mpsc, Arc},
-    thread,
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
 };
 
 const BUFFER_SIZE: usize = 40960;
 const HTTPS_HEADER: &str = "CONNECT";
+const EGRESS_HEADER: &str = "X-Multi3-Egress";
+const TOKEN_HEADER: &str = "X-Multi3-Token";
+const MAX_ERROR_LEN: usize = 200;
+/// How long an idle acceptor waits before it looks whether to stop.
+const ACCEPT_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Both ends of every open connection, so they can be closed from outside.
+static SOCKETS: Mutex<BTreeMap<usize, Vec<socket2::Socket>>> = Mutex::new(BTreeMap::new());
+
+/// Relay threads running right now.
+static RELAYS: AtomicUsize = AtomicUsize::new(0);
+/// Connections being handled, from accepting them until they are done.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn relays() -> usize {
+    RELAYS.load(Ordering::Relaxed)
+}
+
+pub fn open() -> usize {
+    OPEN.load(Ordering::Relaxed)
+}
+
+/// Counts a relay thread for as long as it lives.
+struct Relay;
+impl Relay {
+    fn start() -> Self {
+        RELAYS.fetch_add(1, Ordering::Relaxed);
+        Relay
+    }
+}
+impl Drop for Relay {
+    fn drop(&mut self) {
+        RELAYS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts an accepted connection in `open()` until it is dropped, also while
+/// it waits for a worker.
+pub struct Open;
+impl Open {
+    pub fn count() -> Self {
+        OPEN.fetch_add(1, Ordering::Relaxed);
+        Open
+    }
+}
+impl Drop for Open {
+    fn drop(&mut self) {
+        OPEN.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Buffers given back by finished connections, zeroed once when first made.
+static BUFFERS: Mutex<Vec<Box<[u8]>>> = Mutex::new(Vec::new());
+/// Idle buffers kept around, more go back to the allocator.
+const SPARE_BUFFERS: usize = 256;
+
+/// A buffer of `BUFFER_SIZE` bytes on the heap, returned to `BUFFERS` when dropped.
+struct Buffer(Box<[u8]>);
+impl Buffer {
+    fn take() -> Self {
+        let buffer = BUFFERS.lock().unwrap().pop();
+        Buffer(buffer.unwrap_or_else(|| vec![0u8; BUFFER_SIZE].into_boxed_slice()))
+    }
+}
+impl std::ops::Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+impl std::ops::DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let mut buffers = BUFFERS.lock().unwrap();
+        if buffers.len() < SPARE_BUFFERS {
+            buffers.push(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+/// Bytes relayed since they were last reported, up and down, by connection.
+static TALLIES: Mutex<BTreeMap<usize, Arc<[AtomicUsize; 2]>>> = Mutex::new(BTreeMap::new());
+
+/// Report what every relay moved since the last flush, an Upload and a Download
+/// per connection instead of one for every read.
+pub fn flush_transfers(reporter: &mpsc::Sender<(usize, Event)>) -> Result<()> {
+    // held while sending, so nothing of a connection comes after its Done
+    let tallies = TALLIES.lock().unwrap();
+    for (&id, counts) in tallies.iter() {
+        report_tally(id, counts, reporter)?;
+    }
+    Ok(())
+}
+
+fn report_tally(
+    id: usize,
+    counts: &[AtomicUsize; 2],
+    reporter: &mpsc::Sender<(usize, Event)>,
+) -> Result<()> {
+    let up = counts[0].swap(0, Ordering::Relaxed);
+    if up > 0 {
+        reporter.send((id, Event::Upload(up)))?;
+    }
+    let down = counts[1].swap(0, Ordering::Relaxed);
+    if down > 0 {
+        reporter.send((id, Event::Download(down)))?;
+    }
+    Ok(())
+}
+
+/// The bytes of one relay in `TALLIES`, the rest is reported when it ends.
+struct Tally {
+    id: usize,
+    counts: Arc<[AtomicUsize; 2]>,
+    reporter: mpsc::Sender<(usize, Event)>,
+}
+impl Tally {
+    fn start(id: usize, reporter: &mpsc::Sender<(usize, Event)>) -> Self {
+        let counts = Arc::<[AtomicUsize; 2]>::default();
+        TALLIES.lock().unwrap().insert(id, counts.clone());
+        Tally {
+            id,
+            counts,
+            reporter: reporter.clone(),
+        }
+    }
+
+    fn add(&self, upload: bool, n: usize) {
+        self.counts[usize::from(!upload)].fetch_add(n, Ordering::Relaxed);
+    }
+}
+impl Drop for Tally {
+    fn drop(&mut self) {
+        let mut tallies = TALLIES.lock().unwrap();
+        tallies.remove(&self.id);
+        let _ = report_tally(self.id, &self.counts, &self.reporter);
+    }
+}
+
+/// Tell the reporter about connection `id`, the connection goes on even
+/// when nobody listens anymore.
+fn report(reporter: &mpsc::Sender<(usize, Event)>, id: usize, event: Event) {
+    let event = match event {
+        Event::Error(kind, text) => Event::Error(kind, bounded(text)),
+        Event::RelayFailed {
+            upload,
+            error,
+            bytes_up,
+            bytes_down,
+            duration,
+        } => Event::RelayFailed {
+            upload,
+            error: bounded(error),
+            bytes_up,
+            bytes_down,
+            duration,
+        },
+        event => event,
+    };
+    let _ = reporter.send((id, event));
+}
+
+/// `text` on one line and cut at `MAX_ERROR_LEN` characters, an error may
+/// quote a whole request head.
+fn bounded(text: Cow<'static, str>) -> Cow<'static, str> {
+    if text.len() <= MAX_ERROR_LEN && !text.contains(char::is_control) {
+        return text;
+    }
+    let mut res: String = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if let Some((cut, _)) = res.char_indices().nth(MAX_ERROR_LEN) {
+        res.truncate(cut);
+        res.push('…');
+    }
+    res.into()
+}
+
+fn track(id: usize, socket: socket2::Socket) {
+    SOCKETS.lock().unwrap().entry(id).or_default().push(socket);
+}
+
+/// Write what connection `id` relays from now on to the capture file.
+pub fn start_capture(id: usize) -> bool {
+    let sockets = SOCKETS.lock().unwrap();
+    let addrs: Option<Vec<_>> = sockets.get(&id).and_then(|sockets| {
+        sockets
+            .iter()
+            .map(|x| x.peer_addr().ok()?.as_socket())
+            .collect()
+    });
+    match addrs.as_deref() {
+        Some(&[client, remote]) => capture::start(id, client, remote),
+        _ => false,
+    }
+}
+
+/// Close the connection `id`, its relay threads then finish on their own.
+pub fn kill(id: usize) -> bool {
+    match SOCKETS.lock().unwrap().remove(&id) {
+        Some(sockets) => {
+            for socket in sockets {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Close every connection, for when draining them took too long.
+pub fn kill_all() {
+    let ids: Vec<_> = SOCKETS.lock().unwrap().keys().copied().collect();
+    for id in ids {
+        kill(id);
+    }
+}
+
+/// Answer connection `id` with 503 at once instead of handling it.
+pub fn overloaded(id: usize, mut local: TcpStream, reporter: &mpsc::Sender<(usize, Event)>) {
+    if let Ok(client) = local.peer_addr() {
+        report(reporter, id, Event::Received(unmap(client)));
+    }
+    let _ = local.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+    report(
+        reporter,
+        id,
+        Event::Error(ErrorKind::Overloaded, "Too many connections".into()),
+    );
+}
+
+/// The next connection on the non-blocking `listener`, None once shutting down.
+pub fn accept(listener: &TcpListener) -> Option<io::Result<TcpStream>> {
+    loop {
+        if shutdown::requested() {
+            return None;
+        }
+        match listener.accept() {
+            // some systems hand the non-blocking mode on to it
+            Ok((stream, _)) => return Some(stream.set_nonblocking(false).map(|()| stream)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => wait_for_client(listener),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn wait_for_client(listener: &TcpListener) {
+    use std::os::unix::io::AsRawFd;
+    if poll::ready(
+        [
+            (listener.as_raw_fd(), poll::Interest::Read),
+            (-1, poll::Interest::Read),
+        ],
+        ACCEPT_POLL,
+    )
+    .is_err()
+    {
+        std::thread::sleep(ACCEPT_POLL);
+    }
+}
+
+/// Without poll(2) a short nap, it adds at most that to an idle accept.
+#[cfg(not(unix))]
+fn wait_for_client(_listener: &TcpListener) {
+    std::thread::sleep(ACCEPT_POLL / 10);
+}
+
+fn new_socket(remote: SocketAddr) -> io::Result<socket2::Socket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    Socket::new(
+        Domain::for_address(remote),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )
+}
+
+/// A socket for `remote` bound to the next address of `pool` that the system
+/// takes, each tried once and the failed ones told to `failed`. A pool without
+/// addresses of that family leaves the source to the OS, None when it has
+/// some but none could be bound.
+fn bind_pool(
+    pool: &config::IpPool,
+    remote: SocketAddr,
+    mut failed: impl FnMut(IpAddr),
+) -> io::Result<Option<socket2::Socket>> {
+    let socket = new_socket(remote)?;
+    let tries = match remote {
+        SocketAddr::V4(_) => pool.pool_v4.len(),
+        SocketAddr::V6(_) => pool.pool_v6.len(),
+    };
+    if tries == 0 {
+        return Ok(Some(socket));
+    }
+    for _ in 0..tries {
+        let ip = match remote {
+            SocketAddr::V4(_) => pool.pool_v4.next().map(IpAddr::V4),
+            SocketAddr::V6(_) => pool.pool_v6.next().map(IpAddr::V6),
+        };
+        let Some(ip) = ip else { break };
+        if socket.bind(&SocketAddr::new(ip, 0).into()).is_ok() {
+            return Ok(Some(socket));
+        }
+        // gone from the interface, or of the wrong scope
+        failed(ip);
+    }
+    Ok(None)
+}
+
+/// The egress header wins over the rules.
+fn pick<'a>(
+    egress: &'a Option<config::Action>,
+    rule: Option<&'a config::Rule>,
+    rules: &'a config::Rules,
+) -> &'a config::Action {
+    match (egress, rule) {
+        (Some(action), _) => action,
+        (None, Some(rule)) => &rule.action,
+        (None, None) => &rules.default,
+    }
+}
+
+/// Through the first proxy of the chain that takes the connection, none when
+/// all of them failed or the time is up.
+fn connect_upstream(
+    upstreams: &Upstreams,
+    uri: &str,
+    is_https: bool,
+    config: &config::Config,
+    attempts: &mut Attempts,
+    reporter: &mpsc::Sender<(usize, Event)>,
+    id: usize,
+) -> Result<Option<(socket2::Socket, SocketAddr)>> {
+    for i in upstreams.order() {
+        let Some(ttl) = attempts.next(config) else {
+            break;
+        };
+        let start = std::time::Instant::now();
+        match upstreams.get(i).connect(uri, is_https, ttl) {
+            Ok(x) => {
+                upstreams.succeeded(i, start.elapsed());
+                config.socket_buffers.apply(socket2::SockRef::from(&x))?;
+                return Ok(Some((x, upstreams.get(i).addr())));
+            }
+            Err(e) => {
+                upstreams.failed(i);
+                report(reporter, id, Event::Retry(upstreams.get(i).addr()));
+                if attempts.failed(config, &e) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The connect attempts of one connection. Each gets `timeout.connect`, none
+/// starts once `timeout.total` is used up, and a timed out one is the last
+/// once `timeout.retry` has passed.
+struct Attempts {
+    start: std::time::Instant,
+    /// gave up for the time, the client gets 504
+    out_of_time: bool,
+}
+impl Attempts {
+    fn start() -> Self {
+        Attempts {
+            start: std::time::Instant::now(),
+            out_of_time: false,
+        }
+    }
+
+    /// How long the next attempt may take, None when there is no time for one.
+    fn next(&mut self, config: &config::Config) -> Option<std::time::Duration> {
+        let Some(total) = config.total_ttl else {
+            return Some(config.connect_ttl);
+        };
+        let left = total.saturating_sub(self.start.elapsed());
+        if left.is_zero() {
+            self.out_of_time = true;
+            return None;
+        }
+        Some(config.connect_ttl.min(left))
+    }
+
+    /// Whether no more attempts are made after one failed with `e`.
+    fn failed(&mut self, config: &config::Config, e: &io::Error) -> bool {
+        // a read timeout of a proxy handshake is WouldBlock on unix
+        let timed_out = matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        );
+        let elapsed = self.start.elapsed();
+        if timed_out
+            && (elapsed > config.retry_ttl || config.total_ttl.is_some_and(|x| elapsed >= x))
+        {
+            self.out_of_time = true;
+        }
+        self.out_of_time
+    }
+}
 
 pub fn handle(
     id: usize,
     local: TcpStream,
     config: &config::Config,
-    pool: Arc<config::IpPool>,
+    routing: Arc<config::Routing>,
     reporter: mpsc::Sender<(usize, Event)>,
+    open: Open,
 ) {
-    match inner_handle(id, local, config, pool, reporter.clone()) {
-        Err(e) => {
-            let _ = reporter.send((id, Event::Error(e.to_string().into())));
-        }
-        Ok(()) => {}
+    let res = inner_handle(id, local, config, routing, reporter.clone());
+    SOCKETS.lock().unwrap().remove(&id);
+    capture::stop(id);
+    if let Err(e) = res {
+        report(
+            &reporter,
+            id,
+            Event::Error(ErrorKind::IoError, e.to_string().into()),
+        );
     }
+    // after the last event, whoever waits for the connections sees all of them
+    drop(open);
 }
 
 fn inner_handle(
     id: usize,
     mut local: TcpStream,
     config: &config::Config,
-    pool: Arc<config::IpPool>,
+    routing: Arc<config::Routing>,
     reporter: mpsc::Sender<(usize, Event)>,
 ) -> Result<()> {
-    reporter.send((id, Event::Received(local.peer_addr()?.ip())))?;
+    let start = std::time::Instant::now();
+    let client = unmap(local.peer_addr()?);
+    let listener = unmap(local.local_addr()?);
+    report(&reporter, id, Event::Received(client));
+    track(id, local.try_clone()?.into());
+    if let Some(group) = config.lookup_group(client.ip(), listener) {
+        report(&reporter, id, Event::Grouped(group.to_owned()));
+    }
     local.set_read_timeout(Some(config.io_ttl))?;
     local.set_write_timeout(Some(config.io_ttl))?;
+    config
+        .socket_buffers
+        .apply(socket2::SockRef::from(&local))?;
 
     let is_https;
+    let mut egress = None;
+    // our own headers, left out of plain http
+    let mut ours: &[&str] = &[];
+    // what the remote gets before the relay starts
+    let mut early = Vec::new();
+    // plain http over a remote that may be kept, with what is left of the
+    // request body, and whether it was a HEAD request
+    let mut framed = None;
+    let mut to_head = false;
 
     let uri = {
-        #[allow(invalid_value)]
-        let mut buffer =
-            unsafe { std::mem::MaybeUninit::<[u8; BUFFER_SIZE]>::uninit().assume_init() };
-        let n = local.peek(&mut buffer)?;
-        let request = String::from_utf8_lossy(&buffer[..n]);
+        let mut buffer = Buffer::take();
+        let Some((n, head_len)) = read_head(&mut local, &mut buffer, 0)? else {
+            report(
+                &reporter,
+                id,
+                Event::Error(
+                    ErrorKind::ProtocolViolation,
+                    "Request head too large".into(),
+                ),
+            );
+            local.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n")?;
+            return Ok(());
+        };
+        let request = String::from_utf8_lossy(&buffer[..head_len]);
         let mut request_split = request.split_ascii_whitespace();
 
         let head = request_split.next();
         let uri = request_split
             .skip_while(|x| !x.eq_ignore_ascii_case("Host:"))
             .nth(1);
-        let mut uri = match uri {
+        let (head, mut uri) = match head.zip(uri) {
             None => {
-                reporter.send((id, Event::Error(format!("No host in {}", request).into())))?;
+                report(
+                    &reporter,
+                    id,
+                    Event::Error(
+                        ErrorKind::ProtocolViolation,
+                        format!("No host in {}", request).into(),
+                    ),
+                );
                 local.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
 
                 return Ok(());
             }
-            Some(x) => x,
+            Some((head, uri)) => (head, uri.to_owned()),
+        };
+
+        if let (host, None) = split_host_port(&uri) {
+            // an IPv6 literal gets its brackets back before the port
+            uri = match host.contains(':') {
+                true => format!("[{}]:80", host),
+                false => format!("{}:80", host),
+            };
         }
-        .to_owned();
 
-        if (uri.starts_with('[') && uri.ends_with(']')) || (!uri.contains(':')) {
-            uri += ":80";
+        is_https = head.eq_ignore_ascii_case(HTTPS_HEADER);
+
+        if config.egress_header {
+            egress = header(&request, EGRESS_HEADER).map(str::to_owned);
+            // only picking the egress needs the token, plain proxying doesn't
+            if let (Some(_), Some(token)) = (&egress, &config.egress_token) {
+                if header(&request, TOKEN_HEADER) != Some(token) {
+                    report(
+                        &reporter,
+                        id,
+                        Event::Error(ErrorKind::Forbidden, "Bad egress token".into()),
+                    );
+                    local.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")?;
+                    return Ok(());
+                }
+            }
+            if !is_https && (egress.is_some() || config.egress_token.is_some()) {
+                ours = &[EGRESS_HEADER, TOKEN_HEADER];
+            }
         }
 
-        if head.unwrap().eq_ignore_ascii_case(HTTPS_HEADER) {
-            // consume the CONNECT package of https request.
-            let _ = local.read(&mut buffer)?;
-            is_https = true;
-        } else {
-            is_https = false;
+        // the CONNECT head of https is ours, plain http goes on, upgrades
+        // with the Connection header they need
+        if !is_https && header(&request, "Upgrade").is_some() {
+            early = strip_headers(&request, ours).into_bytes();
+        } else if !is_https {
+            // the next request of a kept alive client may be for another
+            // host, the client reconnects for it. Unless the remote is kept,
+            // it closes after this one.
+            let names = [ours, &["Connection", "Proxy-Connection"][..]].concat();
+            let stripped = strip_headers(&request, &names);
+            if !config.keep_alive.is_zero() {
+                framed = keep_alive::request(&request, n - head_len);
+            }
+            early = match framed {
+                Some(_) => stripped,
+                None => with_close(&stripped),
+            }
+            .into_bytes();
+            to_head = head.eq_ignore_ascii_case("HEAD");
         }
+        // whatever the client sent right behind the head
+        early.extend_from_slice(&buffer[head_len..n]);
 
         uri
     };
 
-    reporter.send((id, Event::Resolved(uri.clone())))?;
-
-    let remote = {
-        let hosts = match uri.to_socket_addrs() {
-            Ok(x) => x,
-            Err(e) => {
-                reporter.send((id, Event::Error(format!("DNS fail:{}", e).into())))?;
-                local.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n")?;
+    let rules = routing.rules();
+    let egress = match egress {
+        None => None,
+        Some(name) => match config.egress_pool(&name, &rules) {
+            Some(pool) => Some(config::Action::Pool(pool)),
+            None => {
+                report(
+                    &reporter,
+                    id,
+                    Event::Error(
+                        ErrorKind::Forbidden,
+                        format!("Unknown egress {}", name).into(),
+                    ),
+                );
+                local.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")?;
                 return Ok(());
             }
+        },
+    };
+
+    // the name as people write it, punycode is for DNS
+    let shown = match split_host_port(&uri) {
+        (host, Some(port)) if !host.contains(':') => format!("{}:{}", idn::to_unicode(host), port),
+        _ => uri.clone(),
+    };
+    report(&reporter, id, Event::Resolved(shown));
+
+    let (remote, peer, wants_capture, direct) = {
+        let mut attempts = Attempts::start();
+        let mut remote = None;
+        let mut wants_capture = false;
+        // whether the remote was bound by the OS or from a pool, None when
+        // it goes through an upstream and can't be kept
+        let mut direct = None;
+        // no address of the pool could be bound for an attempt
+        let mut bind_failed = false;
+        // why the last address tried couldn't be connected to
+        let mut connect_failed = None;
+        // rules name domains the way DNS does
+        let name = idn::to_ascii(host_name(&uri));
+        let mut target = config::Target {
+            client: client.ip(),
+            listener,
+            host: &name,
+            port: split_host_port(&uri)
+                .1
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(80),
+            remote: None,
+        };
+        // a proxy chain gets the name as it is and resolves it itself, a
+        // name only it knows must not fail here. It is tried once.
+        let mut upstream_tried = false;
+        if let Some(rule) = rules.matching(&config.geoip, &target) {
+            wants_capture = rule.is_some_and(|r| r.capture);
+            if rule.is_some_and(|r| r.log) {
+                timeline::want(id);
+            }
+            match pick(&egress, rule, &rules) {
+                config::Action::Upstream(upstreams) => {
+                    upstream_tried = true;
+                    remote = connect_upstream(
+                        upstreams,
+                        &uri,
+                        is_https,
+                        config,
+                        &mut attempts,
+                        &reporter,
+                        id,
+                    )?;
+                }
+                action if framed.is_some() => {
+                    remote = keep_alive::take(&uri, action, config.keep_alive);
+                    direct = remote
+                        .as_ref()
+                        .map(|_| matches!(action, config::Action::Direct));
+                }
+                _ => {}
+            }
+        }
+        let hosts = match remote {
+            Some(_) => Vec::new().into_iter(),
+            None => match dns::lookup(&uri, config.dns_cache) {
+                Ok(x) => {
+                    report(&reporter, id, Event::LookedUp());
+                    x.into_iter()
+                }
+                Err(e) => {
+                    report(
+                        &reporter,
+                        id,
+                        Event::Error(ErrorKind::DnsFailure, format!("DNS fail:{}", e).into()),
+                    );
+                    local.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n")?;
+                    return Ok(());
+                }
+            },
         };
         let hosts: Vec<_> = match config.ipv6_first {
             None => hosts.collect(),
@@ -98,154 +681,1088 @@ fn inner_handle(
                     SocketAddr::V6(_) => v6.push(socket),
                 });
                 if ipv6_first {
-                    v6.into_iter().chain(v4.into_iter())
+                    v6.into_iter().chain(v4)
                 } else {
-                    v4.into_iter().chain(v6.into_iter())
+                    v4.into_iter().chain(v6)
                 }
                 .collect()
             }
         };
-        let time_start = std::time::Instant::now();
-        let mut remote = None;
         for host in hosts {
-            use socket2::{Domain, Protocol, Socket, Type};
-            let local_socket: SocketAddr;
-            let builder;
-            match host {
-                SocketAddr::V4(_) => {
-                    local_socket = (pool.pool_v4.next().unwrap_or(Ipv4Addr::UNSPECIFIED), 0).into();
-                    builder = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+            if attempts.out_of_time {
+                break;
+            }
+            let Some(attempt_ttl) = attempts.next(config) else {
+                break;
+            };
+            target.remote = Some(host.ip());
+            let rule = rules.matching(&config.geoip, &target).flatten();
+            wants_capture = rule.is_some_and(|r| r.capture);
+            if rule.is_some_and(|r| r.log) {
+                timeline::want(id);
+            }
+            let action = pick(&egress, rule, &rules);
+            if let config::Action::Upstream(upstreams) = action {
+                if !upstream_tried {
+                    upstream_tried = true;
+                    remote = connect_upstream(
+                        upstreams,
+                        &uri,
+                        is_https,
+                        config,
+                        &mut attempts,
+                        &reporter,
+                        id,
+                    )?;
+                }
+                if remote.is_some() || attempts.out_of_time {
+                    break;
                 }
-                SocketAddr::V6(_) => {
-                    local_socket = (pool.pool_v6.next().unwrap_or(Ipv6Addr::UNSPECIFIED), 0).into();
-                    builder = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+            }
+            // direct connections keep the source address chosen by the OS,
+            // pool connections never do, the pool may be there to pin it
+            let builder = match action {
+                config::Action::Pool(pool) => {
+                    let failed = |ip| report(&reporter, id, Event::PoolBindFailed(ip));
+                    match bind_pool(pool, host, failed)? {
+                        Some(socket) => socket,
+                        None => {
+                            bind_failed = true;
+                            continue;
+                        }
+                    }
                 }
+                _ => new_socket(host)?,
             };
 
-            if builder.bind(&local_socket.into()).is_err() {
-                reporter.send((id, Event::Retry()))?;
-                continue;
+            // before connecting, the window scale is settled by the handshake
+            config
+                .socket_buffers
+                .apply(socket2::SockRef::from(&builder))?;
+            if config.fast_open {
+                // without it this is an ordinary connect
+                let _ = fastopen::connect(&builder);
             }
-
-            match builder.connect_timeout(&host.into(), config.connect_ttl) {
+            match builder.connect_timeout(&host.into(), attempt_ttl) {
                 Ok(()) => {
-                    remote = Some(builder);
+                    remote = Some((builder, host));
+                    direct = Some(!matches!(action, config::Action::Pool(_)));
                     break;
                 }
-                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
-                    if time_start.elapsed() > config.retry_ttl {
-                        reporter.send((id, Event::Error("Timeout".into())))?;
-                        local.write_all(b"HTTP/1.1 504 Gateway Time-out\r\n\r\n")?;
-                        return Ok(());
-                    } else {
-                        reporter.send((id, Event::Retry()))?;
+                Err(e) => {
+                    let last = attempts.failed(config, &e);
+                    connect_failed = Some(e);
+                    if last {
+                        break;
                     }
-                }
-                Err(_) => {
-                    reporter.send((id, Event::Retry()))?;
+                    report(&reporter, id, Event::Retry(host));
                 }
             }
         }
         match remote {
+            None if attempts.out_of_time => {
+                report(
+                    &reporter,
+                    id,
+                    Event::Error(ErrorKind::ConnectTimeout, "Timeout".into()),
+                );
+                local.write_all(b"HTTP/1.1 504 Gateway Time-out\r\n\r\n")?;
+                return Ok(());
+            }
+            None if bind_failed => {
+                report(
+                    &reporter,
+                    id,
+                    Event::Error(ErrorKind::PoolBindFailed, "No pool address to bind".into()),
+                );
+                local.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+                return Ok(());
+            }
             None => {
-                reporter.send((id, Event::Error("Fail to connect".into())))?;
-                local.write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")?;
+                let (kind, text) = connect_failure(connect_failed);
+                report(&reporter, id, Event::Error(kind, text));
+                local.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
                 return Ok(());
             }
-            Some(x) => x,
+            Some((x, peer)) => (x, peer, wants_capture, direct),
         }
     };
 
-    reporter.send((
+    track(id, remote.try_clone()?);
+    if wants_capture {
+        start_capture(id);
+    }
+    report(
+        &reporter,
         id,
         Event::Connected(
-            remote.local_addr().unwrap().as_socket().unwrap().ip(),
-            remote.peer_addr().unwrap().as_socket().unwrap().ip(),
+            // a fast open socket may not have its address before the first write
+            remote
+                .local_addr()
+                .ok()
+                .and_then(|x| x.as_socket())
+                .map_or(std::net::IpAddr::from(Ipv4Addr::UNSPECIFIED), |x| {
+                    x.ip().to_canonical()
+                }),
+            peer.ip().to_canonical(),
         ),
-    ))?;
+    );
+
+    // before the first write, a remote that takes nothing must not hang us
+    remote.set_read_timeout(Some(config.io_ttl))?;
+    remote.set_write_timeout(Some(config.io_ttl))?;
 
     if is_https {
         // answer to CONNECT
         local.write_all(b"HTTP/1.1 200 OK\r\n\r\n")?;
     }
+    if !early.is_empty() {
+        report(&reporter, id, Event::Upload(early.len()));
+        capture::record(id, true, &early);
+        (&remote).write_all(&early)?;
+    }
 
-    remote.set_read_timeout(Some(config.io_ttl))?;
-    remote.set_write_timeout(Some(config.io_ttl))?;
+    let relayed = match framed {
+        Some(body) => {
+            let (relayed, kept) = exchange(id, local, remote, body, to_head, &reporter);
+            if let (Some(remote), Some(direct)) = (kept, direct) {
+                keep_alive::put(&uri, direct, remote, peer);
+            }
+            relayed
+        }
+        None => {
+            let sniff = is_https && host_name(&uri).parse::<std::net::IpAddr>().is_ok();
+            relay(id, local, remote, config.io_ttl, &reporter, sniff)
+        }
+    };
+    let bytes_up = relayed.bytes_up + early.len() as u64;
+    let event = match relayed.failed {
+        // how far it got tells a cut off download from one that never started
+        Some((upload, e)) => Event::RelayFailed {
+            upload,
+            error: match e {
+                crate::Error::IoError(e) => e.to_string(),
+                e => e.to_string(),
+            }
+            .into(),
+            bytes_up,
+            bytes_down: relayed.bytes_down,
+            duration: start.elapsed(),
+        },
+        None => Event::Done {
+            bytes_up,
+            bytes_down: relayed.bytes_down,
+            duration: start.elapsed(),
+        },
+    };
+    report(&reporter, id, event);
+    Ok(())
+}
+/// The error for a destination none of whose addresses could be connected
+/// to, from why the last one failed.
+fn connect_failure(last: Option<io::Error>) -> (ErrorKind, Cow<'static, str>) {
+    match last {
+        None => (ErrorKind::ConnectRefused, "Fail to connect".into()),
+        Some(e) => (
+            match e.kind() {
+                io::ErrorKind::ConnectionRefused => ErrorKind::ConnectRefused,
+                io::ErrorKind::TimedOut => ErrorKind::ConnectTimeout,
+                _ => ErrorKind::IoError,
+            },
+            format!("Fail to connect: {}", e).into(),
+        ),
+    }
+}
 
-    {
-        let reporter_up = reporter.clone();
-        let local_ = local.try_clone()?;
-        let remote_ = remote.try_clone()?;
-        let up = thread::spawn(move || copy_up(id, local_, remote_, reporter_up));
+/// Read until the head in `buffer`, of which `n` bytes are there already, is
+/// complete, the bytes read and the length of the head, which is all of them
+/// when the other end stopped early. None when the head doesn't fit into
+/// `buffer`.
+fn read_head(
+    local: &mut impl Read,
+    buffer: &mut [u8],
+    mut n: usize,
+) -> io::Result<Option<(usize, usize)>> {
+    loop {
+        if let Some(end) = buffer[..n].windows(4).position(|x| x == b"\r\n\r\n") {
+            return Ok(Some((n, end + 4)));
+        }
+        if n == buffer.len() {
+            return Ok(None);
+        }
+        match local.read(&mut buffer[n..]) {
+            Ok(0) => return Ok(Some((n, n))),
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The value of the first header called `name` in the request or response head.
+pub fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
 
-        let reporter_down = reporter.clone();
-        let down = thread::spawn(move || copy_down(id, remote, local, reporter_down));
+fn strip_headers(request: &str, names: &[&str]) -> String {
+    let (head, body) = request.split_once("\r\n\r\n").unwrap_or((request, ""));
+    let mut res = String::with_capacity(request.len());
+    for line in head.split("\r\n") {
+        let name = line.split_once(':').map_or("", |(k, _)| k.trim());
+        if !names.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+            res += line;
+            res += "\r\n";
+        }
+    }
+    res += "\r\n";
+    res += body;
+    res
+}
 
-        match up.join().and(down.join()).unwrap() {
-            Ok(()) => reporter.send((id, Event::Done()))?,
-            Err(e) => return Err(e),
+/// `head` with `Connection: close` right after the request line.
+fn with_close(head: &str) -> String {
+    match head.split_once("\r\n") {
+        Some((line, rest)) => format!("{}\r\nConnection: close\r\n{}", line, rest),
+        None => head.to_owned(),
+    }
+}
+
+/// `addr` with an IPv4-mapped IPv6 address, as dual-stack sockets show IPv4
+/// clients, turned back into the IPv4 one.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// `example.com:80` => `example.com`, `[::1]:80` => `::1`
+pub fn host_name(uri: &str) -> &str {
+    split_host_port(uri).0
+}
+
+/// `host:port` => (`host`, Some(`port`)), the brackets of IPv6 literals taken
+/// off: `[::1]:80` => (`::1`, Some(`80`)), a bare `::1` has no port.
+pub fn split_host_port(uri: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = uri.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            None => (rest, None),
         };
     }
-    Ok(())
+    match uri.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => (uri, None),
+        Some((host, port)) => (host, Some(port)),
+        None => (uri, None),
+    }
+}
+
+/// The server name of a TLS ClientHello, if `data` starts with one.
+fn sni(data: &[u8]) -> Option<&str> {
+    // take `n` bytes off the front of `data`
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        let (head, rest) = (data.get(..n)?, data.get(n..)?);
+        *data = rest;
+        Some(head)
+    }
+    fn length(data: &mut &[u8], n: usize) -> Option<usize> {
+        Some(take(data, n)?.iter().fold(0, |x, b| x << 8 | *b as usize))
+    }
+    let mut data = data;
+    // record header: handshake, version, length
+    if *take(&mut data, 5)?.first()? != 0x16 {
+        return None;
+    }
+    // handshake header: client hello, length
+    if *take(&mut data, 4)?.first()? != 0x01 {
+        return None;
+    }
+    // version and random
+    take(&mut data, 34)?;
+    let session = length(&mut data, 1)?;
+    take(&mut data, session)?;
+    let ciphers = length(&mut data, 2)?;
+    take(&mut data, ciphers)?;
+    let compression = length(&mut data, 1)?;
+    take(&mut data, compression)?;
+    // the hello may go on in the next segment, look at what is there
+    let extensions = length(&mut data, 2)?.min(data.len());
+    let mut extensions = take(&mut data, extensions)?;
+    while !extensions.is_empty() {
+        let kind = length(&mut extensions, 2)?;
+        let len = length(&mut extensions, 2)?;
+        let mut extension = take(&mut extensions, len)?;
+        // server_name: list length, host_name type, name length, name
+        if kind == 0 {
+            take(&mut extension, 2)?;
+            if *take(&mut extension, 1)?.first()? != 0 {
+                return None;
+            }
+            let len = length(&mut extension, 2)?;
+            return std::str::from_utf8(take(&mut extension, len)?).ok();
+        }
+    }
+    None
+}
+
+/// What a relay moved, and the error that cut it short if one did, with
+/// whether it was the upload. A failed upload leaves the download running, an
+/// error of the download is the one reported.
+struct Relayed {
+    bytes_up: u64,
+    bytes_down: u64,
+    failed: Option<(bool, crate::Error)>,
 }
-fn copy_up(
+
+/// A plain http request and its response over a remote that may be kept,
+/// `body` the bytes of the request body still to come from the client. The
+/// remote is given back when the response ended where its head said, nothing
+/// came after it and the remote didn't ask to close. The client is told to
+/// close, its next request may be for another host.
+fn exchange(
     id: usize,
-    mut from: TcpStream,
-    mut to: socket2::Socket,
-    reporter: mpsc::Sender<(usize, Event)>,
-) -> Result<()> {
-    #[allow(invalid_value)]
-    let mut buffer = unsafe { std::mem::MaybeUninit::<[u8; BUFFER_SIZE]>::uninit().assume_init() };
-    loop {
-        match from.read(&mut buffer) {
-            Ok(0) => {
-                return Ok(());
+    mut local: TcpStream,
+    remote: socket2::Socket,
+    body: u64,
+    to_head: bool,
+    reporter: &mpsc::Sender<(usize, Event)>,
+) -> (Relayed, Option<socket2::Socket>) {
+    let _relay = Relay::start();
+    let tally = Tally::start(id, reporter);
+    let mut buffer = Buffer::take();
+    let mut up = Leg::new(id, true, &tally);
+    let mut down = Leg::new(id, false, &tally);
+    let res = up
+        .copy(&mut local, &mut &remote, body, &mut buffer)
+        .map_err(|e| (true, e))
+        .and_then(|()| {
+            response(&mut local, &remote, to_head, &mut buffer, &mut down).map_err(|e| (false, e))
+        });
+    let _ = local.shutdown(Shutdown::Write);
+    let kept = matches!(res, Ok(true)).then_some(remote);
+    let relayed = Relayed {
+        bytes_up: up.moved,
+        bytes_down: down.moved,
+        failed: res.err().map(|(upload, e)| (upload, e.into())),
+    };
+    (relayed, kept)
+}
+
+/// Forward the response of `remote` to `local`, whether the remote can be
+/// kept after it.
+fn response(
+    local: &mut TcpStream,
+    remote: &socket2::Socket,
+    to_head: bool,
+    buffer: &mut [u8],
+    down: &mut Leg,
+) -> io::Result<bool> {
+    let mut n = 0;
+    // 100 Continue and the like come before the response itself
+    let (read, head_len) = loop {
+        let Some((read, head_len)) = read_head(&mut &*remote, buffer, n)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response head too large",
+            ));
+        };
+        if !buffer[..head_len].ends_with(b"\r\n\r\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "closed before the response",
+            ));
+        }
+        if keep_alive::status(&String::from_utf8_lossy(&buffer[..head_len])) / 100 != 1 {
+            break (read, head_len);
+        }
+        down.send(local, &buffer[..head_len])?;
+        buffer.copy_within(head_len..read, 0);
+        n = read - head_len;
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_len]).into_owned();
+    let (body, keep) = keep_alive::response(&head, to_head);
+    let names = ["Connection", "Proxy-Connection", "Keep-Alive"];
+    down.send(local, with_close(&strip_headers(&head, &names)).as_bytes())?;
+    // what came right behind the head
+    let (mut start, mut end) = (head_len, read);
+    match body {
+        keep_alive::Body::Length(length) => {
+            let first = length.min((end - start) as u64);
+            down.send(local, &buffer[start..start + first as usize])?;
+            let more = end - start > first as usize;
+            down.copy(&mut &*remote, local, length - first, buffer)?;
+            Ok(keep && !more)
+        }
+        keep_alive::Body::Chunked(mut chunks) => loop {
+            let data = &buffer[start..end];
+            if let Some(last) = chunks.feed(data)? {
+                down.send(local, &data[..last])?;
+                return Ok(keep && last == data.len());
             }
-            Ok(n) => {
-                reporter.send((id, Event::Upload(n)))?;
-                to.write_all(&buffer[..n])?;
+            down.send(local, data)?;
+            (start, end) = (0, down.read(&mut &*remote, buffer)?);
+            if end == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
-            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e)
-                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
-            {
-                // reporter.send((id, Event::Error("IO timeout".into())))?;
-                return Ok(());
+        },
+        keep_alive::Body::Close => {
+            while end > start {
+                down.send(local, &buffer[start..end])?;
+                (start, end) = (0, down.read(&mut &*remote, buffer)?);
             }
-            Err(e) => {
-                return Err(e.into());
+            Ok(false)
+        }
+    }
+}
+
+/// One direction of an exchange, what it moved counted and captured.
+struct Leg<'a> {
+    id: usize,
+    upload: bool,
+    tally: &'a Tally,
+    moved: u64,
+}
+impl<'a> Leg<'a> {
+    fn new(id: usize, upload: bool, tally: &'a Tally) -> Self {
+        Leg {
+            id,
+            upload,
+            tally,
+            moved: 0,
+        }
+    }
+
+    fn send(&mut self, to: &mut impl Write, data: &[u8]) -> io::Result<()> {
+        to.write_all(data)?;
+        capture::record(self.id, self.upload, data);
+        self.tally.add(self.upload, data.len());
+        self.moved += data.len() as u64;
+        Ok(())
+    }
+
+    /// One read into `buffer`, 0 when `from` has nothing more.
+    fn read(&self, from: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match from.read(buffer) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// Move `n` bytes from `from` to `to`.
+    fn copy(
+        &mut self,
+        from: &mut impl Read,
+        to: &mut impl Write,
+        mut n: u64,
+        buffer: &mut [u8],
+    ) -> io::Result<()> {
+        while n > 0 {
+            let want = buffer.len().min(usize::try_from(n).unwrap_or(usize::MAX));
+            let read = self.read(from, &mut buffer[..want])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
+            self.send(to, &buffer[..read])?;
+            n -= read as u64;
         }
+        Ok(())
     }
 }
 
-fn copy_down(
+/// Both directions of the tunnel on this one thread, each moving on whenever
+/// its end is ready, so a slow reader only holds up its own direction.
+#[cfg(unix)]
+fn relay(
     id: usize,
-    mut from: socket2::Socket,
-    mut to: TcpStream,
-    reporter: mpsc::Sender<(usize, Event)>,
-) -> Result<()> {
-    #[allow(invalid_value)]
-    let mut buffer = unsafe { std::mem::MaybeUninit::<[u8; BUFFER_SIZE]>::uninit().assume_init() };
-    loop {
-        match from.read(&mut buffer) {
-            Ok(0) => {
+    local: TcpStream,
+    remote: socket2::Socket,
+    io_ttl: std::time::Duration,
+    reporter: &mpsc::Sender<(usize, Event)>,
+    mut sniff: bool,
+) -> Relayed {
+    use std::os::unix::io::AsRawFd;
+    let _relay = Relay::start();
+    let tally = Tally::start(id, reporter);
+    let (mut up, mut down) = (Flow::new(true), Flow::new(false));
+    let mut failed = None;
+    if let Err(e) = local
+        .set_nonblocking(true)
+        .and_then(|()| remote.set_nonblocking(true))
+    {
+        failed = Some((false, e.into()));
+        up.stop();
+        down.stop();
+    }
+    let (local_fd, remote_fd) = (local.as_raw_fd(), remote.as_raw_fd());
+    while !up.done() || !down.done() {
+        let wanted = [
+            up.interest(local_fd, remote_fd),
+            down.interest(remote_fd, local_fd),
+        ];
+        let [up_ready, down_ready] = match poll::ready(wanted, io_ttl) {
+            Ok(Some(ready)) => ready,
+            // a client keeping its side open after the response isn't an error
+            Ok(None) if down.done() => break,
+            Ok(None) => {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "IO timeout");
+                failed = Some((false, e.into()));
+                break;
+            }
+            Err(e) => {
+                failed = Some((false, e.into()));
+                break;
+            }
+        };
+        if up_ready {
+            let sniff = Some(&mut sniff);
+            // a failed upload leaves the response coming
+            if let Err(e) = up.step(id, &local, &remote, sniff, &tally, reporter) {
+                failed = Some((true, e));
+                up.stop();
+            }
+            // half-closed, the response may still be coming
+            if up.done() {
+                let _ = remote.shutdown(Shutdown::Write);
+            }
+        }
+        if down_ready {
+            if let Err(e) = down.step(id, &remote, &local, None, &tally, reporter) {
+                failed = Some((false, e));
+                break;
+            }
+            if down.done() {
+                let _ = local.shutdown(Shutdown::Write);
+            }
+        }
+    }
+    Relayed {
+        bytes_up: up.bytes,
+        bytes_down: down.bytes,
+        failed,
+    }
+}
+
+/// One direction of a tunnel. What it read but couldn't write yet waits in
+/// its buffer or pipe, nothing more is read until that is written.
+#[cfg(unix)]
+struct Flow {
+    upload: bool,
+    /// Until the reading end has nothing more.
+    open: bool,
+    buffer: Buffer,
+    pipe: Option<splice::Pipe>,
+    pending: Pending,
+    bytes: u64,
+}
+
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+enum Pending {
+    Nothing,
+    /// `buffer[start..end]`
+    Buffer(usize, usize),
+    /// This many bytes in the pipe.
+    Pipe(usize),
+}
+
+#[cfg(unix)]
+impl Flow {
+    fn new(upload: bool) -> Self {
+        Flow {
+            upload,
+            open: true,
+            buffer: Buffer::take(),
+            pipe: splice::Pipe::new().ok(),
+            pending: Pending::Nothing,
+            bytes: 0,
+        }
+    }
+
+    fn done(&self) -> bool {
+        !self.open && matches!(self.pending, Pending::Nothing)
+    }
+
+    /// Gives up on what is left.
+    fn stop(&mut self) {
+        self.open = false;
+        self.pending = Pending::Nothing;
+    }
+
+    /// What to wait for before the next `step`: `to` taking the pending
+    /// bytes, else more from `from`.
+    fn interest(
+        &self,
+        from: std::os::unix::io::RawFd,
+        to: std::os::unix::io::RawFd,
+    ) -> (std::os::unix::io::RawFd, poll::Interest) {
+        match self.pending {
+            Pending::Nothing if self.open => (from, poll::Interest::Read),
+            Pending::Nothing => (-1, poll::Interest::Read),
+            _ => (to, poll::Interest::Write),
+        }
+    }
+
+    /// Reads once unless something is still pending, then writes as much as
+    /// `to` takes. Neither blocks, what would is left for the next step.
+    /// `sniff` is only there for the upload, true until its first read.
+    fn step(
+        &mut self,
+        id: usize,
+        mut from: impl Read + std::os::fd::AsFd,
+        to: impl Write + std::os::fd::AsFd,
+        sniff: Option<&mut bool>,
+        tally: &Tally,
+        reporter: &mpsc::Sender<(usize, Event)>,
+    ) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        if let Pending::Nothing = self.pending {
+            // the kernel moves the bytes unless they have to be looked at
+            let in_kernel = !capture::capturing(id) && sniff.as_deref() != Some(&true);
+            let read = match &self.pipe {
+                Some(pipe) if in_kernel => pipe
+                    .fill(from.as_fd().as_raw_fd(), BUFFER_SIZE)
+                    .map(Pending::Pipe),
+                _ => from.read(&mut self.buffer).map(|n| Pending::Buffer(0, n)),
+            };
+            let pending = match read {
+                Ok(pending) => pending,
+                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let n = match pending {
+                Pending::Buffer(_, n) | Pending::Pipe(n) => n,
+                Pending::Nothing => 0,
+            };
+            if n == 0 {
+                self.open = false;
                 return Ok(());
             }
-            Ok(n) => {
-                reporter.send((id, Event::Download(n)))?;
-                to.write_all(&buffer[..n])?;
+            match pending {
+                Pending::Buffer(..) => received(id, &self.buffer[..n], sniff, tally, reporter),
+                _ => tally.add(self.upload, n),
             }
+            self.pending = pending;
+            self.bytes += n as u64;
+        }
+        self.flush(to)
+    }
+
+    fn flush(&mut self, mut to: impl Write + std::os::fd::AsFd) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+        loop {
+            let sent = match (self.pending, &self.pipe) {
+                (Pending::Buffer(start, end), _) => to.write(&self.buffer[start..end]),
+                (Pending::Pipe(left), Some(pipe)) => pipe.drain(to.as_fd().as_raw_fd(), left),
+                _ => return Ok(()),
+            };
+            let n = match sent {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => n,
+                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            self.pending = match self.pending {
+                Pending::Buffer(start, end) if start + n < end => Pending::Buffer(start + n, end),
+                Pending::Pipe(left) if n < left => Pending::Pipe(left - n),
+                _ => Pending::Nothing,
+            };
+        }
+    }
+}
+
+/// Nothing to do right now, poll says when to try again.
+#[cfg(unix)]
+fn would_block(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+/// Without poll(2) the upload gets a thread of its own.
+#[cfg(not(unix))]
+fn relay(
+    id: usize,
+    local: TcpStream,
+    remote: socket2::Socket,
+    _io_ttl: std::time::Duration,
+    reporter: &mpsc::Sender<(usize, Event)>,
+    sniff: bool,
+) -> Relayed {
+    /// The bytes copied, and the error that stopped it early.
+    fn copy(
+        id: usize,
+        mut from: impl Read,
+        mut to: impl Write,
+        mut sniff: Option<bool>,
+        tally: &Tally,
+        reporter: &mpsc::Sender<(usize, Event)>,
+    ) -> (u64, Option<crate::Error>) {
+        let _relay = Relay::start();
+        let mut buffer = Buffer::take();
+        let mut total = 0;
+        loop {
+            match forward(
+                id,
+                &mut from,
+                &mut to,
+                sniff.as_mut(),
+                &mut buffer,
+                tally,
+                reporter,
+            ) {
+                Ok(0) => return (total, None),
+                Ok(n) => total += n as u64,
+                // a client keeping its side open after the response isn't an error
+                Err(crate::Error::IoError(e))
+                    if sniff.is_some()
+                        && (e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::WouldBlock) =>
+                {
+                    return (total, None);
+                }
+                Err(e) => return (total, Some(e)),
+            }
+        }
+    }
+    let tally = Tally::start(id, reporter);
+    std::thread::scope(|scope| {
+        // each direction is half-closed on its own, the other keeps flowing
+        let up = scope.spawn(|| {
+            let sent = copy(id, &local, &remote, Some(sniff), &tally, reporter);
+            let _ = remote.shutdown(Shutdown::Write);
+            sent
+        });
+        let (bytes_down, failed) = copy(id, &remote, &local, None, &tally, reporter);
+        let _ = local.shutdown(Shutdown::Write);
+        let (bytes_up, up_failed) = up.join().unwrap();
+        Relayed {
+            bytes_up,
+            bytes_down,
+            failed: failed.map(|e| (false, e)).or(up_failed.map(|e| (true, e))),
+        }
+    })
+}
+
+/// Moves one read's worth from `from` to `to`, 0 once `from` has nothing more.
+/// `sniff` is only there for the upload, true until its first read.
+#[cfg(not(unix))]
+fn forward(
+    id: usize,
+    mut from: impl Read,
+    mut to: impl Write,
+    sniff: Option<&mut bool>,
+    buffer: &mut [u8],
+    tally: &Tally,
+    reporter: &mpsc::Sender<(usize, Event)>,
+) -> Result<usize> {
+    let n = loop {
+        match from.read(buffer) {
             Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e)
-                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
-            {
-                reporter.send((id, Event::Error("IO timeout".into())))?;
-                return Ok(());
+            n => break n?,
+        }
+    };
+    if n == 0 {
+        return Ok(0);
+    }
+    received(id, &buffer[..n], sniff, tally, reporter);
+    to.write_all(&buffer[..n])?;
+    Ok(n)
+}
+
+/// Counts, captures and, on the upload, sniffs `data` just read.
+fn received(
+    id: usize,
+    data: &[u8],
+    sniff: Option<&mut bool>,
+    tally: &Tally,
+    reporter: &mpsc::Sender<(usize, Event)>,
+) {
+    let upload = sniff.is_some();
+    // the ClientHello is the first thing of a TLS connection
+    if let Some(sniff) = sniff {
+        if std::mem::take(sniff) {
+            if let Some(name) = sni(data) {
+                report(reporter, id, Event::Sni(name.to_owned()));
             }
-            Err(e) => {
-                return Err(e.into());
+        }
+    }
+    tally.add(upload, data.len());
+    capture::record(id, upload, data);
+}
+
+/// splice(2) through a pipe, so the relayed bytes never leave the kernel.
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::{
+        ffi::{c_int, c_uint},
+        io,
+        os::unix::io::RawFd,
+        ptr,
+    };
+
+    const SPLICE_F_MOVE: c_uint = 1;
+    const SPLICE_F_NONBLOCK: c_uint = 2;
+
+    extern "C" {
+        fn pipe(fds: *mut c_int) -> c_int;
+        fn close(fd: c_int) -> c_int;
+        fn splice(
+            fd_in: c_int,
+            off_in: *mut i64,
+            fd_out: c_int,
+            off_out: *mut i64,
+            len: usize,
+            flags: c_uint,
+        ) -> isize;
+    }
+
+    pub struct Pipe {
+        read: c_int,
+        write: c_int,
+    }
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: pipe(2) fills in the two fds
+            if unsafe { pipe(fds.as_mut_ptr()) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                read: fds[0],
+                write: fds[1],
+            })
+        }
+
+        /// Moves up to `len` bytes `from` has into the pipe, 0 once `from` has
+        /// nothing more. Only called on an empty pipe, it holds more than `len`.
+        pub fn fill(&self, from: RawFd, len: usize) -> io::Result<usize> {
+            spliced(from, self.write, len)
+        }
+
+        /// Moves up to `len` bytes out of the pipe to `to`.
+        pub fn drain(&self, to: RawFd, len: usize) -> io::Result<usize> {
+            spliced(self.read, to, len)
+        }
+    }
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            // SAFETY: both fds are ours and closed only here
+            unsafe {
+                close(self.read);
+                close(self.write);
             }
         }
     }
+
+    /// One splice(2) that never waits, the pipe's side included.
+    fn spliced(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = SPLICE_F_MOVE | SPLICE_F_NONBLOCK;
+        // SAFETY: both fds are open, no offsets for pipes and sockets
+        retry(|| unsafe { splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) })
+    }
+
+    fn retry(mut call: impl FnMut() -> isize) -> io::Result<usize> {
+        loop {
+            match call() {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                n => return Ok(n as usize),
+            }
+        }
+    }
+}
+
+/// Other systems have no splice(2), their bytes go through the buffer.
+#[cfg(all(unix, not(target_os = "linux")))]
+mod splice {
+    use std::{io, os::unix::io::RawFd};
+
+    pub enum Pipe {}
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn fill(&self, _from: RawFd, _len: usize) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn drain(&self, _to: RawFd, _len: usize) -> io::Result<usize> {
+            match *self {}
+        }
+    }
+}
+
+/// poll(2), so one thread can wait on both directions of a tunnel.
+#[cfg(unix)]
+mod poll {
+    use std::{
+        ffi::{c_int, c_short},
+        io,
+        os::unix::io::RawFd,
+        time::Duration,
+    };
+
+    const POLLIN: c_short = 0x1;
+    const POLLOUT: c_short = 0x4;
+    #[cfg(target_os = "linux")]
+    type Nfds = std::ffi::c_ulong;
+    #[cfg(not(target_os = "linux"))]
+    type Nfds = std::ffi::c_uint;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: Nfds, timeout: c_int) -> c_int;
+    }
+
+    pub enum Interest {
+        Read,
+        Write,
+    }
+
+    /// Which of `fds` are ready for what they wait for (or closed or failed),
+    /// None once `timeout` passed with neither. A negative fd is never ready.
+    pub fn ready(fds: [(RawFd, Interest); 2], timeout: Duration) -> io::Result<Option<[bool; 2]>> {
+        let mut polled = fds.map(|(fd, interest)| PollFd {
+            fd,
+            events: match interest {
+                Interest::Read => POLLIN,
+                Interest::Write => POLLOUT,
+            },
+            revents: 0,
+        });
+        let timeout = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        loop {
+            // SAFETY: `polled` is a live array of `polled.len()` pollfds
+            match unsafe { poll(polled.as_mut_ptr(), polled.len() as Nfds, timeout) } {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                0 => return Ok(None),
+                _ => return Ok(Some(polled.map(|x| x.revents != 0))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Documentation addresses, no interface has them.
+    fn unbindable() -> Vec<IpAddr> {
+        vec![
+            IpAddr::from([192, 0, 2, 1]),
+            IpAddr::from([198, 51, 100, 1]),
+        ]
+    }
+
+    #[test]
+    fn unbindable_pool_never_connects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let pool = config::IpPool::new(unbindable());
+        let mut failed = Vec::new();
+        let socket = bind_pool(&pool, remote, |ip| failed.push(ip)).unwrap();
+        assert!(socket.is_none());
+        assert_eq!(failed, unbindable());
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn pool_skips_unbindable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let pool = config::IpPool::new(vec![unbindable()[0], localhost]);
+        let mut failed = Vec::new();
+        let socket = bind_pool(&pool, remote, |ip| failed.push(ip))
+            .unwrap()
+            .unwrap();
+        socket.connect(&remote.into()).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), localhost);
+        assert_eq!(failed, [unbindable()[0]]);
+    }
+
+    #[test]
+    fn pool_of_other_family_leaves_source_to_os() {
+        let pool = config::IpPool::new(vec![IpAddr::from(std::net::Ipv6Addr::LOCALHOST)]);
+        let remote = SocketAddr::from(([127, 0, 0, 1], 80));
+        let socket = bind_pool(&pool, remote, |_| panic!("nothing to bind")).unwrap();
+        assert!(socket.is_some());
+    }
+
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn connect_failure_keeps_its_kind() {
+        let kind = |e: io::ErrorKind| connect_failure(Some(e.into())).0;
+        assert_eq!(
+            kind(io::ErrorKind::ConnectionRefused),
+            ErrorKind::ConnectRefused
+        );
+        assert_eq!(kind(io::ErrorKind::TimedOut), ErrorKind::ConnectTimeout);
+        assert_eq!(kind(io::ErrorKind::HostUnreachable), ErrorKind::IoError);
+    }
+
+    #[test]
+    fn stalled_upload_leaves_the_download_moving() {
+        // more than the socket buffers on the way hold
+        const LEN: usize = 16 << 20;
+        let (mut client, local) = connected();
+        let (remote, mut server) = connected();
+        let (reporter, _events) = mpsc::channel();
+        let ttl = std::time::Duration::from_secs(10);
+        // fail instead of hanging if the relay waits on the upload
+        client.set_read_timeout(Some(ttl)).unwrap();
+        let relayed =
+            std::thread::spawn(move || relay(1, local, remote.into(), ttl, &reporter, false));
+        let mut writer = client.try_clone().unwrap();
+        let upload = std::thread::spawn(move || writer.write_all(&vec![1; LEN]));
+        let mut writer = server.try_clone().unwrap();
+        std::thread::spawn(move || {
+            writer.write_all(&vec![2; LEN]).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+        // the server reads nothing before the download is through
+        let mut download = Vec::new();
+        client.read_to_end(&mut download).unwrap();
+        assert_eq!(download.len(), LEN);
+        let mut uploaded = vec![0; LEN];
+        server.read_exact(&mut uploaded).unwrap();
+        upload.join().unwrap().unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let relayed = relayed.join().unwrap();
+        assert!(relayed.failed.is_none());
+        assert_eq!(
+            (relayed.bytes_up, relayed.bytes_down),
+            (LEN as u64, LEN as u64)
+        );
+    }
 }
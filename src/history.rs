@@ -0,0 +1,430 @@
+use crate::event::Event;
+use crate::sqlite::Database;
+use crate::{json, logger};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+const FIELDS: [&str; 11] = [
+    "id",
+    "client",
+    "destination",
+    "bind",
+    "remote",
+    "start",
+    "duration_ms",
+    "upload",
+    "download",
+    "retries",
+    "result",
+];
+
+/// How many finished connections are kept unless the config says otherwise.
+pub const DEFAULT_KEEP: usize = 10000;
+
+/// How long the database writer collects finished connections for one transaction.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The connections of this session, for exporting.
+pub static HISTORY: Mutex<History> = Mutex::new(History::new());
+
+#[derive(Clone)]
+pub struct Record {
+    pub id: usize,
+    pub client: IpAddr,
+    pub destination: Option<String>,
+    pub bind: Option<IpAddr>,
+    pub remote: Option<IpAddr>,
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,
+    pub upload: u64,
+    pub download: u64,
+    pub retries: usize,
+    /// `done`, or the error
+    pub result: Option<String>,
+}
+
+pub struct History {
+    /// the connections without a result yet
+    open: BTreeMap<usize, Record>,
+    /// the last `keep` finished ones, oldest first
+    finished: VecDeque<Record>,
+    keep: usize,
+    file: Option<PathBuf>,
+    database: Option<Writer>,
+}
+
+/// Stores finished connections on a thread of its own, so a slow disk doesn't
+/// hold up the event loop.
+struct Writer {
+    tx: mpsc::Sender<Job>,
+    thread: thread::JoinHandle<()>,
+}
+
+enum Job {
+    Store(Record),
+    /// Store what is pending now and answer once it is.
+    Flush(mpsc::Sender<()>),
+}
+
+fn write_batches(mut database: Database, rx: mpsc::Receiver<Job>) {
+    while let Ok(job) = rx.recv() {
+        let mut jobs = vec![job];
+        // whatever finishes meanwhile goes into the same transaction, unless
+        // someone waits for it
+        let deadline = Instant::now() + BATCH_INTERVAL;
+        while !matches!(jobs.last(), Some(Job::Flush(_))) {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(job) => jobs.push(job),
+                Err(_) => break,
+            }
+        }
+        let (mut batch, mut waiting) = (Vec::new(), Vec::new());
+        for job in jobs {
+            match job {
+                Job::Store(record) => batch.push(record),
+                Job::Flush(ack) => waiting.push(ack),
+            }
+        }
+        if !batch.is_empty() {
+            if let Err(e) = database.insert(&batch) {
+                logger::warn(
+                    "failed to store connections",
+                    &[("count", &batch.len()), ("error", &e)],
+                );
+            }
+        }
+        for ack in waiting {
+            let _ = ack.send(());
+        }
+    }
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            open: BTreeMap::new(),
+            finished: VecDeque::new(),
+            keep: DEFAULT_KEEP,
+            file: None,
+            database: None,
+        }
+    }
+
+    /// Keep no more than `keep` finished connections in memory.
+    pub fn set_keep(&mut self, keep: usize) {
+        self.keep = keep;
+    }
+
+    pub fn set_file(&mut self, file: PathBuf) {
+        self.file = Some(file);
+    }
+
+    /// Also append every finished connection to `database`.
+    pub fn set_database(&mut self, database: Database) {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || write_batches(database, rx));
+        self.database = Some(Writer { tx, thread });
+    }
+
+    pub fn update(&mut self, id: usize, event: &Event) {
+        if id == 0 {
+            return;
+        }
+        if let Event::Received(addr) = event {
+            self.open.insert(
+                id,
+                Record {
+                    id,
+                    client: addr.ip(),
+                    destination: None,
+                    bind: None,
+                    remote: None,
+                    start: SystemTime::now(),
+                    end: None,
+                    upload: 0,
+                    download: 0,
+                    retries: 0,
+                    result: None,
+                },
+            );
+            return;
+        }
+        // the first result wins, an error may be followed by done
+        let Some(record) = self.open.get_mut(&id) else {
+            return;
+        };
+        match event {
+            Event::Resolved(uri) => record.destination = Some(uri.clone()),
+            Event::Connected(bind, remote) => {
+                record.bind = Some(*bind);
+                record.remote = Some(*remote);
+            }
+            Event::Upload(n) => record.upload += *n as u64,
+            Event::Download(n) => record.download += *n as u64,
+            Event::Retry(_) => record.retries += 1,
+            Event::Done { .. } | Event::Error(..) | Event::RelayFailed { .. } => {
+                record.end = Some(SystemTime::now());
+                if let Some((bytes_up, bytes_down)) = event.totals() {
+                    record.upload = bytes_up;
+                    record.download = bytes_down;
+                }
+                record.result = Some(match event.failure() {
+                    Some((_, e)) => e.into_owned(),
+                    None => "done".to_owned(),
+                });
+                if let Some(record) = self.open.remove(&id) {
+                    self.finish(record);
+                }
+            }
+            Event::Received(_)
+            | Event::Grouped(_)
+            | Event::Sni(_)
+            | Event::PoolBindFailed(_)
+            | Event::LookedUp() => {}
+        }
+    }
+
+    /// Store `record` and keep it for the export and the errors, the oldest
+    /// go past `keep`.
+    fn finish(&mut self, record: Record) {
+        if let Some(database) = &self.database {
+            let _ = database.tx.send(Job::Store(record.clone()));
+        }
+        // with nothing to export, memory only needs the failures for /errors
+        let failed = record.result.as_deref() != Some("done");
+        if self.file.is_none() && self.database.is_some() && !failed {
+            return;
+        }
+        self.finished.push_back(record);
+        while self.finished.len() > self.keep {
+            self.finished.pop_front();
+        }
+    }
+
+    /// The finished connections that are kept, then the open ones.
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        self.finished.iter().chain(self.open.values())
+    }
+
+    /// The connections without a result yet, as a JSON array.
+    pub fn open_json(&self) -> String {
+        let records: Vec<_> = self.open.values().map(Self::record_json).collect();
+        format!("[{}]", records.join(","))
+    }
+
+    /// The last `n` connections that failed, newest first.
+    pub fn errors_json(&self, n: usize) -> String {
+        let records: Vec<_> = self
+            .finished
+            .iter()
+            .rev()
+            .filter(|r| r.result.as_deref().is_some_and(|x| x != "done"))
+            .take(n)
+            .map(Self::record_json)
+            .collect();
+        format!("[{}]", records.join(","))
+    }
+
+    fn record_json(record: &Record) -> String {
+        let fields: Vec<_> = FIELDS
+            .iter()
+            .zip(Self::fields(record))
+            .map(|(k, v)| format!("{}:{}", json::string(k), json::string(&v)))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// Write to the configured file, as JSON if it ends with `.json`, CSV otherwise.
+    pub fn export(&self) -> io::Result<Option<&PathBuf>> {
+        let Some(path) = &self.file else {
+            return Ok(None);
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        if path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("json"))
+        {
+            self.write_json(&mut out)?;
+        } else {
+            self.write_csv(&mut out)?;
+        }
+        out.flush()?;
+        Ok(Some(path))
+    }
+
+    fn fields(r: &Record) -> [String; 11] {
+        let opt = |x: Option<String>| x.unwrap_or_default();
+        let duration = r
+            .end
+            .and_then(|end| end.duration_since(r.start).ok())
+            .map(|d| d.as_millis().to_string());
+        [
+            r.id.to_string(),
+            r.client.to_string(),
+            opt(r.destination.clone()),
+            opt(r.bind.map(|x| x.to_string())),
+            opt(r.remote.map(|x| x.to_string())),
+            logger::rfc3339(r.start),
+            opt(duration),
+            r.upload.to_string(),
+            r.download.to_string(),
+            r.retries.to_string(),
+            opt(r.result.clone()),
+        ]
+    }
+
+    fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", FIELDS.join(","))?;
+        for record in self.records() {
+            writeln!(out, "{}", csv_line(&Self::fields(record)))?;
+        }
+        Ok(())
+    }
+
+    fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "[")?;
+        for (i, record) in self.records().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\n{}", sep, Self::record_json(record))?;
+        }
+        writeln!(out, "\n]")
+    }
+}
+
+/// The values as a CSV line, quoted where needed.
+pub fn csv_line(values: &[String]) -> String {
+    let quote = |s: &str| {
+        if s.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_owned()
+        }
+    };
+    values
+        .iter()
+        .map(|v| quote(v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Wait for the database writer to store what it holds, export the history
+/// and log where it went.
+pub fn export() {
+    let tx = HISTORY
+        .lock()
+        .unwrap()
+        .database
+        .as_ref()
+        .map(|x| x.tx.clone());
+    if let Some(tx) = tx {
+        let (ack, done) = mpsc::channel();
+        if tx.send(Job::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+    match HISTORY.lock().unwrap().export() {
+        Ok(Some(path)) => logger::info("exported history", &[("file", &path.display())]),
+        Ok(None) => {}
+        Err(e) => logger::warn("failed to export history", &[("error", &e)]),
+    }
+}
+
+/// Store what the database writer still holds and stop it, once multi3 exits.
+pub fn close() {
+    let writer = HISTORY.lock().unwrap().database.take();
+    if let Some(Writer { tx, thread }) = writer {
+        drop(tx);
+        let _ = thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done() -> Event {
+        Event::Done {
+            bytes_up: 1,
+            bytes_down: 2,
+            duration: Default::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_finished() {
+        let mut history = History::new();
+        history.set_keep(2);
+        for id in 1..=5 {
+            history.update(id, &Event::Received(([127, 0, 0, 1], 1000).into()));
+        }
+        for id in 1..=4 {
+            history.update(id, &done());
+        }
+        let kept: Vec<_> = history.records().map(|r| r.id).collect();
+        assert_eq!(kept, [3, 4, 5]);
+        assert_eq!(history.open.len(), 1);
+        // a result after the first one changes nothing
+        history.update(
+            4,
+            &Event::Error(crate::event::ErrorKind::IoError, "late".into()),
+        );
+        assert_eq!(history.errors_json(10), "[]");
+    }
+
+    #[test]
+    fn failed_relay_keeps_its_totals() {
+        let mut history = History::new();
+        history.update(1, &Event::Received(([127, 0, 0, 1], 1000).into()));
+        history.update(
+            1,
+            &Event::RelayFailed {
+                upload: true,
+                error: "reset".into(),
+                bytes_up: 3,
+                bytes_down: 4,
+                duration: Default::default(),
+            },
+        );
+        let record = history.records().next().unwrap();
+        assert_eq!((record.upload, record.download), (3, 4));
+        assert_eq!(record.result.as_deref(), Some("upload failed: reset"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn flush_leaves_the_writer_running() {
+        let database = Database::open(std::path::Path::new(":memory:")).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::spawn(move || write_batches(database, rx));
+        let record = Record {
+            id: 1,
+            client: [127, 0, 0, 1].into(),
+            destination: None,
+            bind: None,
+            remote: None,
+            start: SystemTime::now(),
+            end: None,
+            upload: 0,
+            download: 0,
+            retries: 0,
+            result: None,
+        };
+        for _ in 0..2 {
+            tx.send(Job::Store(record.clone())).unwrap();
+            let (ack, done) = mpsc::channel();
+            tx.send(Job::Flush(ack)).unwrap();
+            // answered before the batch interval is over
+            done.recv_timeout(BATCH_INTERVAL / 2).unwrap();
+        }
+        drop(tx);
+        writer.join().unwrap();
+    }
+}
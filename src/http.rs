@@ -0,0 +1,94 @@
+use crate::{Error, Result};
+use std::{
+    io::{self, prelude::*, BufReader},
+    net::TcpStream,
+    str::FromStr,
+    time::Duration,
+};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `http://host:port/path`, there is no TLS.
+pub struct Url {
+    /// `host:port`
+    pub authority: String,
+    /// empty or `/path` without a trailing slash
+    pub path: String,
+}
+impl FromStr for Url {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (authority, path) = s
+            .strip_prefix("http://")
+            .map(|x| x.split_once('/').unwrap_or((x, "")))
+            .ok_or_else(|| Error::ConfigError(format!("{} isn't http://", s)))?;
+        Ok(Url {
+            authority: authority.to_owned(),
+            path: match path.trim_end_matches('/') {
+                "" => String::new(),
+                path => format!("/{}", path),
+            },
+        })
+    }
+}
+
+/// POST a JSON `body` to `path` below `url`, anything but a 2xx answer is an error.
+pub fn post(url: &Url, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(&url.authority)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let target = format!("{}{}", url.path, path);
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        if target.is_empty() { "/" } else { &target },
+        url.authority,
+        body.len(),
+        body
+    )?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    match line.split_ascii_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{} answered {}",
+            url.authority,
+            line.trim()
+        ))),
+    }
+}
+
+/// `method` on `path` of the admin listener at `authority`, the body of a 2xx
+/// answer. Anything else is an error with the answer in it.
+pub fn call(
+    authority: &str,
+    token: &str,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> io::Result<String> {
+    let mut stream = TcpStream::connect(authority)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        authority,
+        token,
+        body.len(),
+        body
+    )?;
+    let mut answer = String::new();
+    BufReader::new(&stream).read_to_string(&mut answer)?;
+    let (head, body) = answer.split_once("\r\n\r\n").unwrap_or((&answer, ""));
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_ascii_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(body.to_owned()),
+        _ => Err(io::Error::other(format!(
+            "{} answered {} {}",
+            authority, status, body
+        ))),
+    }
+}
@@ -0,0 +1,211 @@
+use std::borrow::Cow;
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+const PREFIX: &str = "xn--";
+
+/// `host` as DNS knows it, the labels with more than ASCII in punycode.
+pub fn to_ascii(host: &str) -> Cow<'_, str> {
+    if host.is_ascii() {
+        return host.into();
+    }
+    let labels: Option<Vec<String>> = host
+        .split('.')
+        .map(|label| match label.is_ascii() {
+            true => Some(label.to_owned()),
+            false => Some(format!("{}{}", PREFIX, encode(&label.to_lowercase())?)),
+        })
+        .collect();
+    labels.map_or(host.into(), |labels| labels.join(".").into())
+}
+
+/// `host` as people write it, the punycode labels decoded.
+pub fn to_unicode(host: &str) -> Cow<'_, str> {
+    let punycode = |label: &str| {
+        label
+            .get(..PREFIX.len())
+            .is_some_and(|x| x.eq_ignore_ascii_case(PREFIX))
+    };
+    if !host.split('.').any(punycode) {
+        return host.into();
+    }
+    let labels: Vec<_> = host
+        .split('.')
+        .map(|label| match punycode(label) {
+            true => decode(&label[PREFIX.len()..]).unwrap_or_else(|| label.to_owned()),
+            false => label.to_owned(),
+        })
+        .collect();
+    labels.join(".").into()
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        TMIN
+    } else if k >= bias + TMAX {
+        TMAX
+    } else {
+        k - bias
+    }
+}
+
+fn adapt(mut delta: u32, points: u32, first: bool) -> u32 {
+    delta /= if first { DAMP } else { 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+/// The punycode of one label, without the prefix. None on overflow.
+fn encode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+    let (mut n, mut delta, mut bias, mut handled) = (INITIAL_N, 0u32, INITIAL_BIAS, basic);
+    while (handled as usize) < input.len() {
+        let m = *input.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    Some(output)
+}
+
+/// One label from its punycode, without the prefix. None if it isn't punycode.
+fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut w = 1u32;
+        let mut k = BASE;
+        loop {
+            let d = match digits.next()? {
+                c @ b'a'..=b'z' => c - b'a',
+                c @ b'A'..=b'Z' => c - b'A',
+                c @ b'0'..=b'9' => c - b'0' + 26,
+                _ => return None,
+            } as u32;
+            i = i.checked_add(d.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if d < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    // all ASCII was never encoded
+    output
+        .iter()
+        .any(|c| !c.is_ascii())
+        .then(|| output.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 3492 section 7.1, lowercased like `to_ascii` does
+    const SAMPLES: [(&str, &str); 5] = [
+        ("bücher", "bcher-kva"),
+        ("münchen", "mnchen-3ya"),
+        ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+        ("3年b組金八先生", "3b-ww4c5e180e575a65lsy2b"),
+        ("pročprostěnemluvíčesky", "proprostnemluvesky-uyb24dma41a"),
+    ];
+
+    #[test]
+    fn samples() {
+        for (unicode, punycode) in SAMPLES {
+            assert_eq!(encode(unicode).as_deref(), Some(punycode), "{unicode}");
+            assert_eq!(decode(punycode).as_deref(), Some(unicode), "{punycode}");
+        }
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(to_ascii("bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("www.MÜNCHEN.de"), "www.xn--mnchen-3ya.de");
+        assert!(matches!(to_ascii("example.com"), Cow::Borrowed(_)));
+        assert_eq!(to_unicode("xn--bcher-kva.example"), "bücher.example");
+        assert_eq!(to_unicode("XN--bcher-KVA.example"), "bücher.example");
+        assert!(matches!(to_unicode("example.com"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn bad_punycode() {
+        for label in [
+            "",
+            "-",
+            "abc-",
+            "bcher-kv!",
+            "ab-ü",
+            "99999999999",
+            "zzzzzzzzzzzzzzzzzzzz",
+        ] {
+            assert_eq!(decode(label), None, "{label:?}");
+        }
+        // what can't be decoded is shown as it came
+        assert_eq!(
+            to_unicode("xn--.xn--bcher-kv!.example"),
+            "xn--.xn--bcher-kv!.example"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt::Write;
+
+/// `s` as a quoted JSON string.
+pub fn string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\n' => res += "\\n",
+            '\r' => res += "\\r",
+            '\t' => res += "\\t",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(res, "\\u{:04x}", c as u32);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings() {
+        assert_eq!(string(""), r#""""#);
+        assert_eq!(string("plain"), r#""plain""#);
+        assert_eq!(string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(string("\0\x1f\x7f"), "\"\\u0000\\u001f\x7f\"");
+        assert_eq!(string("bücher ✓"), "\"bücher ✓\"");
+    }
+}
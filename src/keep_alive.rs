@@ -0,0 +1,328 @@
+use crate::config::Action;
+use crate::handle::header;
+use socket2::Socket;
+use std::{
+    collections::BTreeMap,
+    io,
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Idle connections kept for one destination, the oldest go first.
+const PER_DESTINATION: usize = 8;
+/// Idle connections kept for all of them together.
+const MAX_IDLE: usize = 256;
+
+/// The remote connections of plain http that are done with a request, by the
+/// `host:port` they go to.
+static KEPT: Mutex<Kept> = Mutex::new(Kept {
+    idle: BTreeMap::new(),
+    len: 0,
+});
+
+struct Kept {
+    /// the oldest first
+    idle: BTreeMap<String, Vec<Idle>>,
+    len: usize,
+}
+
+struct Idle {
+    socket: Socket,
+    peer: SocketAddr,
+    bind: IpAddr,
+    /// the source address came from the OS, not from a pool
+    direct: bool,
+    since: Instant,
+}
+impl Idle {
+    /// Whether a connection `action` makes could have been this one.
+    fn fits(&self, action: &Action) -> bool {
+        match action {
+            Action::Direct => self.direct,
+            Action::Pool(pool) => !self.direct && pool.contains(self.bind),
+            Action::Upstream(_) => false,
+        }
+    }
+}
+
+/// A connection to `uri` `action` could have made, idle for less than `ttl`,
+/// the last one kept first.
+pub fn take(uri: &str, action: &Action, ttl: Duration) -> Option<(Socket, SocketAddr)> {
+    loop {
+        let idle = {
+            let mut kept = KEPT.lock().unwrap();
+            let Kept { idle, len } = &mut *kept;
+            let sockets = idle.get_mut(uri)?;
+            let before = sockets.len();
+            sockets.retain(|x| x.since.elapsed() < ttl);
+            let found = sockets.iter().rposition(|x| x.fits(action));
+            let idle_one = found.map(|i| sockets.remove(i));
+            *len -= before - sockets.len();
+            if sockets.is_empty() {
+                idle.remove(uri);
+            }
+            idle_one?
+        };
+        // the remote may have closed it meanwhile
+        if quiet(&idle.socket) {
+            return Some((idle.socket, idle.peer));
+        }
+    }
+}
+
+/// Keep `socket`, done with a request to `uri`, for the next one.
+pub fn put(uri: &str, direct: bool, socket: Socket, peer: SocketAddr) {
+    let Some(bind) = socket.local_addr().ok().and_then(|x| x.as_socket()) else {
+        return;
+    };
+    let mut kept = KEPT.lock().unwrap();
+    let Kept { idle, len } = &mut *kept;
+    if *len >= MAX_IDLE {
+        // the destination whose oldest one waited the longest gives it up
+        let oldest = idle
+            .iter()
+            .min_by_key(|(_, sockets)| sockets[0].since)
+            .map(|(uri, _)| uri.clone());
+        if let Some(oldest) = oldest {
+            let sockets = idle.get_mut(&oldest).unwrap();
+            sockets.remove(0);
+            if sockets.is_empty() {
+                idle.remove(&oldest);
+            }
+            *len -= 1;
+        }
+    }
+    let sockets = idle.entry(uri.to_owned()).or_default();
+    if sockets.len() >= PER_DESTINATION {
+        sockets.remove(0);
+        *len -= 1;
+    }
+    sockets.push(Idle {
+        socket,
+        peer,
+        bind: bind.ip().to_canonical(),
+        direct,
+        since: Instant::now(),
+    });
+    *len += 1;
+}
+
+/// Whether nothing came on the idle `socket`, neither its end nor bytes
+/// nobody asked for.
+fn quiet(socket: &Socket) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [MaybeUninit::uninit()];
+    let peeked = socket.peek(&mut byte);
+    socket.set_nonblocking(false).is_ok()
+        && matches!(peeked, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Whether the comma separated header `value` has `token`.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|x| x.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+}
+
+/// What is left of the body of the request with `head` after the `read`
+/// bytes that came with it. None for a request that can't go over a kept
+/// connection: not HTTP/1.1, sent in chunks, waiting for 100 Continue, or
+/// followed by another one already.
+pub fn request(head: &str, read: usize) -> Option<u64> {
+    let line = head.split("\r\n").next()?;
+    if !line.ends_with(" HTTP/1.1")
+        || header(head, "Transfer-Encoding").is_some()
+        || header(head, "Expect").is_some()
+    {
+        return None;
+    }
+    let length: u64 = match header(head, "Content-Length") {
+        Some(length) => length.parse().ok()?,
+        None => 0,
+    };
+    length.checked_sub(read as u64)
+}
+
+/// The status code of the response with `head`, 0 if it has none.
+pub fn status(head: &str) -> u16 {
+    head.split_ascii_whitespace()
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0)
+}
+
+/// How the body of a message ends.
+pub enum Body {
+    /// after this many bytes
+    Length(u64),
+    /// after the last chunk and the trailer
+    Chunked(Chunks),
+    /// when the remote closes, the connection can't be kept then
+    Close,
+}
+
+/// Where the body of the response with `head` ends, to a HEAD request if
+/// `to_head`, and whether the remote may be kept after it.
+pub fn response(head: &str, to_head: bool) -> (Body, bool) {
+    let keep = head.starts_with("HTTP/1.1 ") && !has_token(header(head, "Connection"), "close");
+    let status = status(head);
+    if to_head || status / 100 == 1 || status == 204 || status == 304 {
+        return (Body::Length(0), keep);
+    }
+    if let Some(coding) = header(head, "Transfer-Encoding") {
+        // chunked is the last coding whenever the length isn't up to the close
+        return match coding.rsplit(',').next().map(str::trim) {
+            Some(x) if x.eq_ignore_ascii_case("chunked") => {
+                (Body::Chunked(Chunks::default()), keep)
+            }
+            _ => (Body::Close, false),
+        };
+    }
+    match header(head, "Content-Length").map(str::parse) {
+        Some(Ok(length)) => (Body::Length(length), keep),
+        _ => (Body::Close, false),
+    }
+}
+
+/// How far a chunked body is, fed its bytes as they come.
+pub struct Chunks(Chunk);
+
+enum Chunk {
+    /// the size line, with the size so far and whether the extensions began
+    Size(u64, bool),
+    SizeEnd(u64),
+    Data(u64),
+    DataEnd,
+    DataEndLf,
+    /// a line of the trailer, with its length so far
+    Trailer(usize),
+    TrailerEnd(usize),
+    Done,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        Chunks(Chunk::Size(0, false))
+    }
+}
+impl Chunks {
+    /// Where in `data` the body ends, None while it goes on after it.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<Option<usize>> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad chunked body");
+        let mut i = 0;
+        while i < data.len() {
+            let byte = data[i];
+            self.0 = match self.0 {
+                Chunk::Size(size, false) if byte.is_ascii_hexdigit() => {
+                    let digit = (byte as char).to_digit(16).unwrap() as u64;
+                    let size = size.checked_mul(16).ok_or_else(bad)? + digit;
+                    Chunk::Size(size, false)
+                }
+                Chunk::Size(size, _) if byte == b'\r' => Chunk::SizeEnd(size),
+                Chunk::Size(size, _) if byte == b';' => Chunk::Size(size, true),
+                Chunk::Size(size, true) => Chunk::Size(size, true),
+                Chunk::SizeEnd(0) if byte == b'\n' => Chunk::Trailer(0),
+                Chunk::SizeEnd(size) if byte == b'\n' => Chunk::Data(size),
+                Chunk::Data(left) => {
+                    let n = left.min((data.len() - i) as u64);
+                    i += n as usize;
+                    self.0 = match left - n {
+                        0 => Chunk::DataEnd,
+                        left => Chunk::Data(left),
+                    };
+                    continue;
+                }
+                Chunk::DataEnd if byte == b'\r' => Chunk::DataEndLf,
+                Chunk::DataEndLf if byte == b'\n' => Chunk::Size(0, false),
+                Chunk::Trailer(len) if byte == b'\r' => Chunk::TrailerEnd(len),
+                Chunk::Trailer(len) => Chunk::Trailer(len + 1),
+                Chunk::TrailerEnd(0) if byte == b'\n' => Chunk::Done,
+                Chunk::TrailerEnd(_) if byte == b'\n' => Chunk::Trailer(0),
+                _ => return Err(bad()),
+            };
+            i += 1;
+            if let Chunk::Done = self.0 {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_end_after_the_trailer() {
+        let body = b"4;name=x\r\nwiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nnext";
+        // fed in pieces as small as they come
+        let mut chunks = Chunks::default();
+        let mut end = None;
+        for (i, piece) in body.chunks(3).enumerate() {
+            if let Some(n) = chunks.feed(piece).unwrap() {
+                end = Some(i * 3 + n);
+                break;
+            }
+        }
+        assert_eq!(end, Some(body.len() - 4));
+        assert!(Chunks::default().feed(b"x\r\n").is_err());
+    }
+
+    #[test]
+    fn responses_are_framed() {
+        let frame = |head: &str, to_head| match response(head, to_head) {
+            (Body::Length(n), keep) => (Some(n), keep),
+            (Body::Chunked(_), keep) => (None, keep),
+            (Body::Close, keep) => (Some(u64::MAX), keep),
+        };
+        assert_eq!(
+            frame("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", false),
+            (Some(5), true)
+        );
+        assert_eq!(
+            frame("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true),
+            (Some(0), true)
+        );
+        assert_eq!(
+            frame(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                false
+            ),
+            (None, true)
+        );
+        assert_eq!(
+            frame("HTTP/1.1 304 Not Modified\r\n\r\n", false),
+            (Some(0), true)
+        );
+        assert_eq!(
+            frame("HTTP/1.1 200 OK\r\n\r\n", false),
+            (Some(u64::MAX), false)
+        );
+        assert_eq!(
+            frame(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                false
+            ),
+            (Some(0), false)
+        );
+        assert_eq!(
+            frame("HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n", false),
+            (Some(0), false)
+        );
+    }
+
+    #[test]
+    fn requests_that_can_be_kept() {
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: a\r\n\r\n", 0), Some(0));
+        let post = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(request(post, 4), Some(6));
+        // the next request came right behind it
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: a\r\n\r\n", 3), None);
+        assert_eq!(request("GET / HTTP/1.0\r\nHost: a\r\n\r\n", 0), None);
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(request(chunked, 0), None);
+    }
+}
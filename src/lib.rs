@@ -0,0 +1,471 @@
+mod admin;
+mod capture;
+mod config;
+mod control;
+mod daemon;
+mod dns;
+mod drawer;
+mod error;
+mod event;
+mod fastopen;
+mod geoip;
+mod handle;
+mod history;
+mod http;
+mod idn;
+mod json;
+mod keep_alive;
+mod logger;
+mod matcher;
+mod otel;
+mod output;
+mod service;
+mod shutdown;
+mod signals;
+mod sqlite;
+mod summary;
+mod systemd;
+mod timeline;
+mod upstream;
+mod webhook;
+mod worker;
+pub use error::*;
+use std::{
+    fmt::Display,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Where a [`ProxyServer`] reads its settings from.
+pub struct Builder {
+    config_file: PathBuf,
+    daemon: bool,
+    service: bool,
+}
+impl Builder {
+    /// The file to read instead of `multi3.toml`, rules are reloaded from it too.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = path.into();
+        self
+    }
+    /// Fork to the background on `run` whatever the config says, without the tui.
+    pub fn daemon(mut self) -> Self {
+        self.daemon = true;
+        self
+    }
+    /// Without a console, the log goes to the event log unless the config
+    /// gives it another sink.
+    #[cfg(windows)]
+    fn service(mut self) -> Self {
+        self.service = true;
+        self
+    }
+    /// Read the config file, nothing is bound yet.
+    pub fn build(self) -> Result<ProxyServer> {
+        let (mut cfg, routings) = config::read_config(&self.config_file)?;
+        if self.daemon {
+            cfg.daemon = true;
+            cfg.tui = false;
+        }
+        if self.service {
+            cfg.tui = false;
+            if matches!(cfg.log_sink, config::LogSink::Stdout) {
+                cfg.log_sink = config::LogSink::EventLog;
+            }
+        }
+        Ok(ProxyServer {
+            config_file: self.config_file,
+            cfg,
+            routings: routings.into_iter().map(Arc::new).collect(),
+        })
+    }
+}
+
+/// The proxy spreading connections over the pool addresses, as the binary runs
+/// it: `ProxyServer::builder().build()?.run()`. Its state is global, so one per
+/// process.
+pub struct ProxyServer {
+    config_file: PathBuf,
+    cfg: config::Config,
+    routings: Vec<Arc<config::Routing>>,
+}
+impl ProxyServer {
+    pub fn builder() -> Builder {
+        Builder {
+            config_file: PathBuf::from("multi3.toml"),
+            daemon: false,
+            service: false,
+        }
+    }
+
+    /// Listen on the addresses of the config and relay until a signal, the tui
+    /// or [`stop`] ends it, after the open connections drained.
+    pub fn run(self) -> Result<()> {
+        let ProxyServer {
+            config_file,
+            cfg,
+            routings,
+        } = self;
+        run(config_file, cfg, routings)
+    }
+}
+
+/// Run as the Windows service `multi3` until the service manager stops it.
+pub fn run_service(builder: Builder) -> Result<()> {
+    service::run(builder)
+}
+
+/// Register this executable as the Windows service `multi3`.
+pub fn install_service() -> Result<()> {
+    Ok(service::install()?)
+}
+
+/// Send `args`, like `kill 12` or `pool list`, to the admin listener of the
+/// instance running with `config_file` and print its answer.
+pub fn control(config_file: impl AsRef<Path>, args: &[String]) -> Result<()> {
+    control::run(config_file.as_ref(), args)
+}
+
+/// Make a running [`ProxyServer`] stop accepting and return from `run`.
+pub fn stop() {
+    shutdown::request();
+}
+
+/// `e` with what failed on which path, for the files and sockets the config names.
+fn context<'a>(what: &'a str, path: &'a dyn Display) -> impl FnOnce(std::io::Error) -> Error + 'a {
+    move |e| Error::ConfigError(format!("{} {}: {}", what, path, e))
+}
+
+fn run(
+    config_file: PathBuf,
+    cfg: config::Config,
+    routings: Vec<Arc<config::Routing>>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+
+    let config_file: &'static Path = Box::leak(config_file.into_boxed_path());
+    let cfg = &*Box::leak(Box::new(cfg));
+    match &cfg.log_sink {
+        config::LogSink::File(f) => {
+            let file = logger::RotatingFile::open(&f.path, f.max_size, f.rotate, f.keep)
+                .map_err(context("failed to open the log file", &f.path.display()))?;
+            logger::init(cfg.log_format, Box::new(file));
+        }
+        config::LogSink::Syslog => {
+            let socket = logger::datagram(logger::SYSLOG_SOCKET).map_err(context(
+                "failed to connect to syslog at",
+                &logger::SYSLOG_SOCKET,
+            ))?;
+            logger::init(logger::Format::Syslog, socket);
+        }
+        config::LogSink::Journald => {
+            let socket = logger::datagram(logger::JOURNALD_SOCKET).map_err(context(
+                "failed to connect to journald at",
+                &logger::JOURNALD_SOCKET,
+            ))?;
+            logger::init(logger::Format::Journald, socket);
+        }
+        config::LogSink::EventLog => {
+            let log =
+                service::event_log().map_err(context("failed to open the event log", &"multi3"))?;
+            logger::init(cfg.log_format, log);
+        }
+        config::LogSink::Stdout if cfg.output != config::Output::Text => {
+            logger::init(cfg.log_format, logger::stderr())
+        }
+        config::LogSink::Stdout if !cfg.tui => logger::init(cfg.log_format, logger::stdout()),
+        config::LogSink::Stdout => {}
+    }
+    // taken before a fork changes the pid they are meant for
+    let activated = systemd::listeners();
+    if cfg.daemon {
+        if matches!(cfg.log_sink, config::LogSink::Stdout) {
+            logger::warn(
+                "logging to stdout, in the background that goes nowhere",
+                &[],
+            );
+        }
+        // before any thread, only the calling one goes along into the child
+        daemon::detach()?;
+    }
+    if let Some(path) = &cfg.pidfile {
+        daemon::write_pidfile(path)
+            .map_err(context("failed to write the pidfile", &path.display()))?;
+    }
+    history::HISTORY.lock().unwrap().set_keep(cfg.history_keep);
+    if let Some(file) = &cfg.history_file {
+        history::HISTORY.lock().unwrap().set_file(file.clone());
+    }
+    if let Some(path) = &cfg.capture_file {
+        capture::open(path).map_err(context("failed to open the capture file", &path.display()))?;
+    }
+    if let Some(dir) = &cfg.connection_log {
+        std::fs::create_dir_all(dir).map_err(context(
+            "failed to create the connection log directory",
+            &dir.display(),
+        ))?;
+        let mut timelines = timeline::TIMELINES.lock().unwrap();
+        timelines.set_dir(dir.clone(), cfg.connection_log_reads);
+    }
+    if let Some(path) = &cfg.stats_file {
+        match summary::SUMMARY.lock().unwrap().load(path) {
+            Ok(()) => logger::info("loaded statistics", &[("file", &path.display())]),
+            Err(e) => logger::warn("failed to load statistics", &[("error", &e)]),
+        }
+        thread::spawn(move || summary::checkpoint(path, cfg.stats_interval));
+    }
+    if let Some(hook) = &cfg.webhook {
+        webhook::NOTIFIER.lock().unwrap().set_webhook(hook);
+    }
+    if let Some(path) = &cfg.history_database {
+        let database = sqlite::Database::open(path).map_err(context(
+            "failed to open the history database",
+            &path.display(),
+        ))?;
+        history::HISTORY.lock().unwrap().set_database(database);
+    }
+    config::check(cfg, &routings);
+    signals::install();
+    {
+        let routings = routings.clone();
+        thread::spawn(move || signals::watch(config_file, &routings));
+    }
+    {
+        // the relays only count their bytes, they are reported once per frame
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            thread::sleep(drawer::frame_interval());
+            if handle::flush_transfers(&tx).is_err() {
+                return;
+            }
+        });
+    }
+    let id = Arc::new(Mutex::new(0));
+    let queue = cfg.workers.as_ref().map(|workers| {
+        let tx = tx.clone();
+        worker::Queue::start(workers, move |(id, stream, routing, open)| {
+            handle::handle(id, stream, cfg, routing, tx.clone(), open)
+        })
+    });
+    // every address that can be bound is listened on, the others are skipped
+    let mut listening = 0;
+    for routing in routings.iter() {
+        for &socket in routing.host.iter() {
+            for n in 0..cfg.acceptors {
+                // systemd may have bound it already, for a privileged port
+                let listener = match activated.get(&socket) {
+                    Some(listener) => listener.try_clone(),
+                    None => listen(socket, cfg),
+                };
+                // so the acceptor sees a shutdown without waiting for a client
+                let listener = listener.and_then(|x| x.set_nonblocking(true).map(|()| x));
+                let listener = match listener {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        logger::warn("failed to bind", &[("addr", &socket), ("error", &e)]);
+                        break;
+                    }
+                };
+                if n == 0 {
+                    let addr = listener.local_addr().unwrap_or(socket);
+                    logger::info("listening", &[("addr", &addr)]);
+                    listening += 1;
+                }
+                let routing = routing.clone();
+                let tx = tx.clone();
+                let id = id.clone();
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    // the listener is dropped with this thread on shutdown
+                    while let Some(stream) = handle::accept(&listener) {
+                        let routing = routing.clone();
+                        let tx = tx.clone();
+                        if let Ok(stream) = stream {
+                            summary::accepted();
+                            // released before anything that can block
+                            let id = {
+                                let mut id = id.lock().unwrap();
+                                *id += 1;
+                                *id
+                            };
+                            // counted from here, so the limit covers the queued ones too
+                            let open = handle::Open::count();
+                            if cfg.max_connections.is_some_and(|max| handle::open() > max) {
+                                handle::overloaded(id, stream, &tx);
+                                continue;
+                            }
+                            match &queue {
+                                Some(queue) => {
+                                    let job = (id, stream, routing, open);
+                                    if let Err((id, stream, ..)) = queue.push(job) {
+                                        handle::overloaded(id, stream, &tx);
+                                    }
+                                }
+                                None => {
+                                    thread::spawn(move || {
+                                        handle::handle(id, stream, cfg, routing, tx, open)
+                                    });
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+    if listening == 0 {
+        logger::log(logger::Level::Error, "no address to listen on", &[]);
+        logger::flush();
+        return Err(Error::ConfigError("no address to listen on".into()));
+    }
+    summary::started();
+    systemd::notify("READY=1");
+    systemd::watchdog();
+    if cfg.admin.is_some() {
+        let routings = routings.clone();
+        thread::spawn(move || admin::serve(cfg, config_file, routings));
+    }
+    if cfg.watch {
+        let routings = routings.clone();
+        thread::spawn(move || config::watch(config_file, &routings));
+    }
+    if let Some(otel) = &cfg.otel {
+        thread::spawn(move || otel::export(otel));
+    }
+    if let Some(addr) = cfg.summary {
+        thread::spawn(move || summary::serve(addr));
+    }
+    if let config::Output::Console(interval) = cfg.output {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            output::console_totals();
+        });
+    }
+    let mut screen = None;
+    let mut drawer = if cfg.tui {
+        let (feed, drawer_rx) = drawer::Feed::new();
+        drawer::set_frame_interval(cfg.tui_refresh);
+        drawer::set_theme(cfg.tui_theme);
+        screen = Some(thread::spawn(move || {
+            drawer::drawer(drawer_rx, cfg, routings)
+        }));
+        thread::spawn(move || {
+            let tick = || event::Event::Done {
+                bytes_up: 0,
+                bytes_down: 0,
+                duration: Default::default(),
+            };
+            while !shutdown::requested() && tx.send((0, tick())).is_ok() {
+                thread::sleep(drawer::frame_interval())
+            }
+        });
+        Some(feed)
+    } else {
+        None
+    };
+    let mut finished = false;
+    loop {
+        let next = if finished {
+            // what the finished connections sent before they were done
+            rx.try_recv().ok()
+        } else {
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(x) => Some(x),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    finished = shutdown::finished(cfg.drain_ttl);
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => None,
+            }
+        };
+        let Some((id, x)) = next else {
+            break;
+        };
+        summary::SUMMARY.lock().unwrap().update(id, &x);
+        history::HISTORY.lock().unwrap().update(id, &x);
+        otel::TRACER.lock().unwrap().update(id, &x);
+        webhook::NOTIFIER.lock().unwrap().update(id, &x);
+        timeline::TIMELINES.lock().unwrap().update(id, &x);
+        match cfg.output {
+            config::Output::Text => logger::event(id, &x),
+            config::Output::Jsonl => {
+                logger::event(id, &x);
+                output::jsonl(id, &x);
+            }
+            config::Output::Console(_) => output::console(id, &x),
+        }
+        if let Some(feed) = &mut drawer {
+            if !feed.send(id, x) {
+                // the tui quit, the connections get to finish first
+                drawer = None;
+                shutdown::request();
+            }
+        }
+        finished = finished || shutdown::finished(cfg.drain_ttl);
+    }
+    // the drawer sees its feed gone and gives the terminal back
+    drop(drawer);
+    if let Some(screen) = screen {
+        let _ = screen.join();
+    }
+    history::close();
+    history::export();
+    if let Some(path) = &cfg.stats_file {
+        if let Err(e) = summary::SUMMARY.lock().unwrap().save(path) {
+            logger::warn("failed to save statistics", &[("error", &e)]);
+        }
+    }
+    if let Some(path) = &cfg.pidfile {
+        let _ = std::fs::remove_file(path);
+    }
+    logger::info("shutting down", &[]);
+    logger::flush();
+    Ok(())
+}
+
+/// A listener on `addr` with the socket options of `cfg`, those that fail
+/// are only warned about.
+fn listen(addr: SocketAddr, cfg: &config::Config) -> std::io::Result<TcpListener> {
+    let listener = if cfg.acceptors > 1 {
+        bind_shared(addr)?
+    } else {
+        TcpListener::bind(addr)?
+    };
+    // the accepted connections start with the buffers of the listener
+    if let Err(e) = cfg.socket_buffers.apply(socket2::SockRef::from(&listener)) {
+        logger::warn(
+            "failed to set the socket buffers",
+            &[("addr", &addr), ("error", &e)],
+        );
+    }
+    if cfg.fast_open {
+        if let Err(e) = fastopen::listen(&listener) {
+            logger::warn(
+                "failed to enable fast open",
+                &[("addr", &addr), ("error", &e)],
+            );
+        }
+    }
+    Ok(listener)
+}
+
+/// One of the `acceptors` listeners on `addr`, SO_REUSEPORT lets the kernel
+/// spread the connections over them.
+#[cfg(unix)]
+fn bind_shared(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_shared(_addr: SocketAddr) -> std::io::Result<TcpListener> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,434 @@
+use crate::event::{self, ErrorKind, Event};
+use crate::json;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+impl Level {
+    fn severity(self) -> u8 {
+        match self {
+            Level::Info => 6,
+            Level::Warn => 4,
+            Level::Error => 3,
+        }
+    }
+}
+impl From<Level> for &str {
+    fn from(val: Level) -> Self {
+        match val {
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+    /// RFC 5424 with the fields as structured data
+    Syslog,
+    /// the native journald protocol, one field per line
+    Journald,
+}
+
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+// private enterprise number reserved for examples, see RFC 5612
+const SYSLOG_SD_ID: &str = "multi3@32473";
+
+/// What is known about a connection so far, attached to each of its records.
+#[derive(Default)]
+struct Span {
+    client: Option<IpAddr>,
+    group: Option<String>,
+    destination: Option<String>,
+    sni: Option<String>,
+    retries: usize,
+}
+
+pub struct Logger {
+    format: Format,
+    out: Box<dyn Write + Send>,
+    spans: HashMap<usize, Span>,
+}
+impl Logger {
+    fn write(&mut self, level: Level, message: &str, fields: &[(&str, &dyn Display)]) {
+        let severity = level.severity();
+        let level: &str = level.into();
+        let line = match self.format {
+            Format::Text => {
+                let mut line = format!("{} {:<5} {}", timestamp(), level, message);
+                for (key, value) in fields {
+                    let value = value.to_string();
+                    if value.contains([' ', '"', '=']) || value.is_empty() {
+                        line += &format!(" {}={:?}", key, value);
+                    } else {
+                        line += &format!(" {}={}", key, value);
+                    }
+                }
+                line
+            }
+            Format::Json => {
+                let mut line = format!(
+                    "{{\"time\":{},\"level\":{},\"message\":{}",
+                    json::string(&timestamp()),
+                    json::string(level),
+                    json::string(message)
+                );
+                for (key, value) in fields {
+                    line += &format!(
+                        ",{}:{}",
+                        json::string(key),
+                        json::string(&value.to_string())
+                    );
+                }
+                line + "}"
+            }
+            Format::Syslog => {
+                let mut data = format!("[{}", SYSLOG_SD_ID);
+                for (key, value) in fields {
+                    let value = value
+                        .to_string()
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace(']', "\\]");
+                    data += &format!(" {}=\"{}\"", key, value);
+                }
+                format!(
+                    "<{}>1 {} - multi3 {} - {}] {}",
+                    SYSLOG_FACILITY_DAEMON * 8 + severity,
+                    timestamp(),
+                    std::process::id(),
+                    data,
+                    message
+                )
+            }
+            Format::Journald => {
+                let one_line = |s: String| s.replace('\n', " ");
+                let mut line = format!(
+                    "MESSAGE={}\nPRIORITY={}\nSYSLOG_IDENTIFIER=multi3",
+                    one_line(message.to_owned()),
+                    severity
+                );
+                for (key, value) in fields {
+                    line += &format!(
+                        "\nMULTI3_{}={}",
+                        key.to_ascii_uppercase(),
+                        one_line(value.to_string())
+                    );
+                }
+                line
+            }
+        };
+        // one write per record, so a rotation never splits a line
+        let _ = self.out.write_all((line + "\n").as_bytes());
+    }
+
+    fn event(&mut self, id: usize, event: &Event) {
+        let span = self.spans.entry(id).or_default();
+        let (level, message, mut extra): (_, _, Vec<(&str, String)>) = match event {
+            Event::Upload(_) | Event::Download(_) | Event::LookedUp() => return,
+            Event::Received(addr) => {
+                span.client = Some(addr.ip());
+                (Level::Info, "received", vec![])
+            }
+            Event::Grouped(group) => {
+                span.group = Some(group.clone());
+                return;
+            }
+            Event::Sni(name) => {
+                span.sni = Some(name.clone());
+                (Level::Info, "sni", vec![])
+            }
+            Event::Resolved(uri) => {
+                span.destination = Some(uri.clone());
+                (Level::Info, "resolved", vec![])
+            }
+            Event::Connected(bind, remote) => (
+                Level::Info,
+                "connected",
+                vec![("bind", bind.to_string()), ("remote", remote.to_string())],
+            ),
+            Event::Retry(addr) => {
+                span.retries += 1;
+                (Level::Warn, "retry", vec![("address", addr.to_string())])
+            }
+            Event::PoolBindFailed(ip) => (
+                Level::Warn,
+                "pool_bind_failed",
+                vec![("address", ip.to_string())],
+            ),
+            Event::Done {
+                bytes_up,
+                bytes_down,
+                duration,
+            } => (
+                Level::Info,
+                "done",
+                vec![
+                    ("upload", bytes_up.to_string()),
+                    ("download", bytes_down.to_string()),
+                    ("duration_ms", duration.as_millis().to_string()),
+                ],
+            ),
+            Event::Error(failure, e) => (
+                Level::Error,
+                "error",
+                vec![
+                    ("kind", failure.name().to_owned()),
+                    ("error", e.to_string()),
+                ],
+            ),
+            Event::RelayFailed {
+                upload,
+                error,
+                bytes_up,
+                bytes_down,
+                duration,
+            } => (
+                Level::Error,
+                "error",
+                vec![
+                    ("kind", ErrorKind::IoError.name().to_owned()),
+                    ("error", error.to_string()),
+                    ("direction", event::direction(*upload).to_owned()),
+                    ("upload", bytes_up.to_string()),
+                    ("download", bytes_down.to_string()),
+                    ("duration_ms", duration.as_millis().to_string()),
+                ],
+            ),
+        };
+        let done = event.ends();
+        if done && span.retries > 0 {
+            extra.push(("retries", span.retries.to_string()));
+        }
+        let id_ = id.to_string();
+        let client = span.client.map(|x| x.to_string());
+        let group = span.group.clone();
+        let destination = span.destination.clone();
+        let sni = span.sni.clone();
+        let mut fields: Vec<(&str, &dyn Display)> = vec![("id", &id_)];
+        if let Some(client) = &client {
+            fields.push(("client", client));
+        }
+        if let Some(group) = &group {
+            fields.push(("group", group));
+        }
+        if let Some(destination) = &destination {
+            fields.push(("destination", destination));
+        }
+        if let Some(sni) = &sni {
+            fields.push(("sni", sni));
+        }
+        fields.extend(extra.iter().map(|(k, v)| (*k, v as &dyn Display)));
+        if done {
+            self.spans.remove(&id);
+        }
+        self.write(level, message, &fields);
+    }
+}
+
+/// Start logging to `out`, nothing is written before this.
+pub fn init(format: Format, out: Box<dyn Write + Send>) {
+    *LOGGER.lock().unwrap() = Some(Logger {
+        format,
+        out,
+        spans: HashMap::new(),
+    });
+}
+
+pub fn stdout() -> Box<dyn Write + Send> {
+    Box::new(io::stdout())
+}
+
+pub fn stderr() -> Box<dyn Write + Send> {
+    Box::new(io::stderr())
+}
+
+/// Each record is sent as one datagram to the unix socket at `path`.
+#[cfg(unix)]
+pub fn datagram(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    struct Datagram(std::os::unix::net::UnixDatagram);
+    impl Write for Datagram {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(Box::new(Datagram(socket)))
+}
+
+#[cfg(not(unix))]
+pub fn datagram(_path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "syslog and journald need unix sockets",
+    ))
+}
+
+pub fn log(level: Level, message: &str, fields: &[(&str, &dyn Display)]) {
+    if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
+        logger.write(level, message, fields);
+    }
+}
+
+pub fn info(message: &str, fields: &[(&str, &dyn Display)]) {
+    log(Level::Info, message, fields)
+}
+
+pub fn warn(message: &str, fields: &[(&str, &dyn Display)]) {
+    log(Level::Warn, message, fields)
+}
+
+/// Write out what the sink still holds, before exiting.
+pub fn flush() {
+    if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
+        let _ = logger.out.flush();
+    }
+}
+
+/// Log a connection event together with the fields of its span.
+pub fn event(id: usize, event: &Event) {
+    if id == 0 {
+        return;
+    }
+    if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
+        logger.event(id, event);
+    }
+}
+
+pub fn timestamp() -> String {
+    rfc3339(SystemTime::now())
+}
+
+/// RFC 3339 in UTC, e.g. `2024-05-01T12:34:56.789Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotate {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// A log file renamed to `<path>.1`, `<path>.2`, … once it grows past
+/// `max_size` or the period changes, keeping at most `keep` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    rotate: Rotate,
+    period: String,
+    keep: usize,
+}
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, rotate: Rotate, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            rotate,
+            period: period(rotate),
+            keep,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let old = |i: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", i));
+            PathBuf::from(name)
+        };
+        let _ = fs::remove_file(old(self.keep));
+        for i in (1..self.keep).rev() {
+            let _ = fs::rename(old(i), old(i + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, old(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = period(self.rotate);
+        let full =
+            self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size;
+        if full || period != self.period {
+            self.period = period;
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The part of the timestamp that changes when a new file is due.
+fn period(rotate: Rotate) -> String {
+    let now = timestamp();
+    match rotate {
+        Rotate::Never => String::new(),
+        Rotate::Hourly => now[..13].to_owned(),
+        Rotate::Daily => now[..10].to_owned(),
+    }
+}
@@ -1,63 +1,34 @@
-mod config;
-mod drawer;
-mod error;
-mod event;
-mod handle;
-pub use error::*;
-use std::{
-    net::TcpListener,
-    sync::{mpsc, Arc, Mutex},
-    thread,
-};
 fn main() {
-    let (cfg, routings) = config::read_config("multi3.toml").unwrap();
-
-    let (tx, rx) = mpsc::channel();
-
-    let cfg = &*Box::leak(Box::new(cfg));
-    let id = Arc::new(Mutex::new(0));
-    for config::Routing { host, pool } in routings {
-        let pool = Arc::new(pool);
-        for socket in host {
-            let pool = pool.clone();
-            let tx = tx.clone();
-            let id = id.clone();
-            thread::spawn(move || {
-                println!("Listening on: {}", socket);
-                let listener = match TcpListener::bind(&socket) {
-                    Ok(listener) => listener,
-                    Err(e) => {
-                        println!("Failed to bind to {}: {}", socket, e);
-                        return;
-                    }
-                };
-                for stream in listener.incoming() {
-                    let pool = pool.clone();
-                    let tx = tx.clone();
-                    if let Ok(stream) = stream {
-                        let mut id = id.lock().unwrap();
-                        *id += 1;
-                        let id = id.clone();
-                        thread::spawn(move || handle::handle(id, stream, cfg, pool, tx));
-                    }
-                }
-            });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // a command for the running instance
+    if args.first().is_some_and(|x| !x.starts_with("--")) {
+        if let Err(e) = multi3::control("multi3.toml", &args) {
+            exit(e);
         }
+        return;
     }
-    if cfg.tui {
-        thread::spawn(move || drawer::drawer(rx));
-        while tx.send((0, event::Event::Done())).is_ok() {
-            thread::sleep(drawer::FRAME_INTERVAL)
+    let mut builder = multi3::ProxyServer::builder();
+    if args.iter().any(|x| x == "--install-service") {
+        if let Err(e) = multi3::install_service() {
+            exit(e);
         }
-    } else {
-        while let Ok((id, x)) = rx.recv() {
-            match x {
-                event::Event::Upload(_) | event::Event::Download(_) => continue,
-                _ => {
-                    println!("[{:<4}] {:?}", id, x);
-                }
-            }
+        return;
+    }
+    if args.iter().any(|x| x == "--daemon") {
+        builder = builder.daemon();
+    }
+    if args.iter().any(|x| x == "--service") {
+        if let Err(e) = multi3::run_service(builder) {
+            exit(e);
         }
+        return;
     }
-    println!("Shutting down");
+    if let Err(e) = builder.build().and_then(|server| server.run()) {
+        exit(e);
+    }
+}
+
+fn exit(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
 }
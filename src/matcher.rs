@@ -0,0 +1,607 @@
+use crate::{Error, Result};
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+/// An address block like `192.168.0.0/16` or `fd00::/8`; a bare address is a single host.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let bits = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => u32::from(ip) as u128,
+            IpAddr::V6(ip) => u128::from(ip),
+        };
+        // `::ffff:10.0.0.1` is in `10.0.0.0/8`
+        let ip = ip.to_canonical();
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        bits(self.addr) & mask == bits(ip) & mask
+    }
+}
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || Error::ConfigError(format!("bad address block {}", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(err)?,
+            None => max,
+        };
+        // keep the prefix in the 128-bit space used by `contains`
+        let prefix = prefix + (128 - max);
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// The longest DNS name, RFC 1035 without the trailing dot.
+const MAX_HOST_LEN: usize = 253;
+
+/// A set of domain patterns answering "does this host match any of them".
+///
+/// * `example.com` matches exactly that host
+/// * `*.example.com` matches every subdomain of example.com
+/// * anything else containing `*` or `?` is a glob over the whole host
+/// * `regex:<re>` is a (small) regular expression searched in the host
+///
+/// Exact and suffix patterns live in a trie of reversed labels,
+/// so their cost does not grow with the number of patterns.
+#[derive(Default)]
+pub struct DomainMatcher {
+    trie: Node,
+    globs: Vec<Box<[char]>>,
+    regexes: Vec<Regex>,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    exact: bool,
+    subdomains: bool,
+}
+
+impl DomainMatcher {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut matcher = Self::default();
+        for pattern in patterns {
+            matcher.insert(pattern.as_ref())?;
+        }
+        Ok(matcher)
+    }
+
+    pub fn insert(&mut self, pattern: &str) -> Result<()> {
+        if let Some(re) = pattern.strip_prefix("regex:") {
+            self.regexes.push(Regex::new(re)?);
+            return Ok(());
+        }
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let (subdomains, rest) = match pattern.strip_prefix("*.") {
+            Some(rest) => (true, rest),
+            None => (false, pattern.as_str()),
+        };
+        if rest.contains(['*', '?']) {
+            self.globs.push(pattern.chars().collect());
+            return Ok(());
+        }
+        let mut node = &mut self.trie;
+        for label in rest.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if subdomains {
+            node.subdomains = true;
+        } else {
+            node.exact = true;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trie.children.is_empty() && self.globs.is_empty() && self.regexes.is_empty()
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        // no DNS name is longer, whatever else came in the Host header isn't matched
+        if host.len() > MAX_HOST_LEN {
+            return false;
+        }
+        let mut node = &self.trie;
+        let mut labels = host.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            match node.children.get(label) {
+                Some(next) => node = next,
+                None => break,
+            }
+            if labels.peek().is_none() && node.exact {
+                return true;
+            }
+            if labels.peek().is_some() && node.subdomains {
+                return true;
+            }
+        }
+        let chars: Vec<char> = host.chars().collect();
+        self.globs.iter().any(|g| glob(g, &chars)) || self.regexes.iter().any(|r| r.find(&chars))
+    }
+}
+
+/// `*` is any run of characters and `?` any single one. The last `*` is the
+/// only backtracking point, so this is linear in practice and never recursive.
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the last `*` take one more character
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The most instructions a regex may compile to, `{m,n}` copies its atom.
+const MAX_PROGRAM: usize = 4096;
+
+/// A regex supporting `. [] [^] ^ $ | () * + ? {m,n}` and the `\d \w \s`
+/// classes, which is plenty for host names. It is compiled to an NFA and run
+/// over all states at once, so matching is linear in the host and never
+/// backtracks.
+struct Regex {
+    prog: Vec<Inst>,
+}
+
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: usize,
+}
+
+enum Atom {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Vec<Vec<Piece>>),
+}
+
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+impl Regex {
+    fn new(re: &str) -> Result<Self> {
+        let chars: Vec<char> = re.chars().collect();
+        let mut i = 0;
+        let alts = parse_alt(&chars, &mut i)?;
+        if i != chars.len() {
+            return Err(Error::ConfigError(format!(
+                "unbalanced ')' in regex {}",
+                re
+            )));
+        }
+        let mut prog = Vec::new();
+        compile_alt(&alts, &mut prog)?;
+        prog.push(Inst::Match);
+        Ok(Self { prog })
+    }
+
+    fn find(&self, text: &[char]) -> bool {
+        // the generation, that is the text position, each state was last added at
+        let mut seen = vec![usize::MAX; self.prog.len()];
+        let mut current = Vec::new();
+        let mut next = Vec::new();
+        for pos in 0..=text.len() {
+            // a match may start anywhere
+            if self.add(&mut current, &mut seen, 0, text, pos) {
+                return true;
+            }
+            let Some(&c) = text.get(pos) else {
+                break;
+            };
+            next.clear();
+            for &pc in &current {
+                let step = match &self.prog[pc] {
+                    Inst::Char(x) => *x == c,
+                    Inst::Any => true,
+                    Inst::Class(ranges, negate) => {
+                        ranges.iter().any(|(a, b)| *a <= c && c <= *b) != *negate
+                    }
+                    _ => false,
+                };
+                if step && self.add(&mut next, &mut seen, pc + 1, text, pos + 1) {
+                    return true;
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+
+    /// Put `pc` and everything reachable from it without reading into `list`,
+    /// whether `Match` is among them.
+    fn add(
+        &self,
+        list: &mut Vec<usize>,
+        seen: &mut [usize],
+        pc: usize,
+        text: &[char],
+        pos: usize,
+    ) -> bool {
+        let mut stack = vec![pc];
+        let mut matched = false;
+        while let Some(pc) = stack.pop() {
+            if seen[pc] == pos {
+                continue;
+            }
+            seen[pc] = pos;
+            match &self.prog[pc] {
+                Inst::Split(a, b) => stack.extend([*b, *a]),
+                Inst::Jmp(a) => stack.push(*a),
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == text.len() => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Match => matched = true,
+                _ => list.push(pc),
+            }
+        }
+        matched
+    }
+}
+
+fn compile_alt(alts: &[Vec<Piece>], prog: &mut Vec<Inst>) -> Result<()> {
+    let mut jumps = Vec::new();
+    for (n, seq) in alts.iter().enumerate() {
+        let split = prog.len();
+        let last = n + 1 == alts.len();
+        if !last {
+            prog.push(Inst::Split(0, 0));
+        }
+        for piece in seq {
+            compile_piece(piece, prog)?;
+        }
+        if !last {
+            jumps.push(prog.len());
+            prog.push(Inst::Jmp(0));
+            prog[split] = Inst::Split(split + 1, prog.len());
+        }
+    }
+    for jump in jumps {
+        prog[jump] = Inst::Jmp(prog.len());
+    }
+    Ok(())
+}
+
+fn compile_piece(piece: &Piece, prog: &mut Vec<Inst>) -> Result<()> {
+    for _ in 0..piece.min {
+        compile_atom(&piece.atom, prog)?;
+    }
+    if piece.max == usize::MAX {
+        let split = prog.len();
+        prog.push(Inst::Split(0, 0));
+        compile_atom(&piece.atom, prog)?;
+        prog.push(Inst::Jmp(split));
+        prog[split] = Inst::Split(split + 1, prog.len());
+    } else {
+        for _ in piece.min..piece.max {
+            let split = prog.len();
+            prog.push(Inst::Split(0, 0));
+            compile_atom(&piece.atom, prog)?;
+            prog[split] = Inst::Split(split + 1, prog.len());
+        }
+    }
+    Ok(())
+}
+
+fn compile_atom(atom: &Atom, prog: &mut Vec<Inst>) -> Result<()> {
+    if prog.len() > MAX_PROGRAM {
+        return Err(Error::ConfigError("regex too large".into()));
+    }
+    match atom {
+        Atom::Char(c) => prog.push(Inst::Char(*c)),
+        Atom::Any => prog.push(Inst::Any),
+        Atom::Class(ranges, negate) => prog.push(Inst::Class(ranges.clone(), *negate)),
+        Atom::Start => prog.push(Inst::Start),
+        Atom::End => prog.push(Inst::End),
+        Atom::Group(alts) => compile_alt(alts, prog)?,
+    }
+    Ok(())
+}
+fn parse_alt(re: &[char], i: &mut usize) -> Result<Vec<Vec<Piece>>> {
+    let mut alts = vec![Vec::new()];
+    while let Some(&c) = re.get(*i) {
+        *i += 1;
+        let atom = match c {
+            ')' => {
+                *i -= 1;
+                break;
+            }
+            '|' => {
+                alts.push(Vec::new());
+                continue;
+            }
+            '(' => {
+                if re[*i..].starts_with(&['?', ':']) {
+                    *i += 2;
+                }
+                let group = parse_alt(re, i)?;
+                if re.get(*i) != Some(&')') {
+                    return Err(Error::ConfigError("unclosed '(' in regex".into()));
+                }
+                *i += 1;
+                Atom::Group(group)
+            }
+            '[' => parse_class(re, i)?,
+            '.' => Atom::Any,
+            '^' => Atom::Start,
+            '$' => Atom::End,
+            '\\' => {
+                let c = *re
+                    .get(*i)
+                    .ok_or_else(|| Error::ConfigError("trailing '\\' in regex".into()))?;
+                *i += 1;
+                escape(c)
+            }
+            c => Atom::Char(c),
+        };
+        let (min, max) = parse_quantifier(re, i)?;
+        alts.last_mut().unwrap().push(Piece { atom, min, max });
+    }
+    Ok(alts)
+}
+
+fn escape(c: char) -> Atom {
+    match c {
+        'd' => Atom::Class(vec![('0', '9')], false),
+        'D' => Atom::Class(vec![('0', '9')], true),
+        'w' => Atom::Class(vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')], false),
+        'W' => Atom::Class(vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')], true),
+        's' => Atom::Class(vec![(' ', ' '), ('\t', '\r')], false),
+        c => Atom::Char(c),
+    }
+}
+
+fn parse_class(re: &[char], i: &mut usize) -> Result<Atom> {
+    let negate = re.get(*i) == Some(&'^');
+    if negate {
+        *i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let c = *re
+            .get(*i)
+            .ok_or_else(|| Error::ConfigError("unclosed '[' in regex".into()))?;
+        *i += 1;
+        let c = match c {
+            ']' if !first => break,
+            '\\' => {
+                let e = *re.get(*i).unwrap_or(&'\\');
+                *i += 1;
+                match escape(e) {
+                    Atom::Class(r, false) => {
+                        ranges.extend(r);
+                        continue;
+                    }
+                    _ => e,
+                }
+            }
+            c => c,
+        };
+        first = false;
+        if re.get(*i) == Some(&'-') && re.get(*i + 1).is_some_and(|x| *x != ']') {
+            ranges.push((c, re[*i + 1]));
+            *i += 2;
+        } else {
+            ranges.push((c, c));
+        }
+    }
+    Ok(Atom::Class(ranges, negate))
+}
+
+fn parse_quantifier(re: &[char], i: &mut usize) -> Result<(usize, usize)> {
+    let range = match re.get(*i) {
+        Some('*') => (0, usize::MAX),
+        Some('+') => (1, usize::MAX),
+        Some('?') => (0, 1),
+        Some('{') => {
+            let end = re[*i..]
+                .iter()
+                .position(|c| *c == '}')
+                .ok_or_else(|| Error::ConfigError("unclosed '{' in regex".into()))?;
+            let body: String = re[*i + 1..*i + end].iter().collect();
+            let num = |s: &str| {
+                s.trim()
+                    .parse::<usize>()
+                    .map_err(|_| Error::ConfigError(format!("bad repetition {{{}}}", body)))
+            };
+            let range = match body.split_once(',') {
+                None => (num(&body)?, num(&body)?),
+                Some((min, "")) => (num(min)?, usize::MAX),
+                Some((min, max)) => (num(min)?, num(max)?),
+            };
+            if range.0 > range.1 || range.0 > MAX_PROGRAM {
+                return Err(Error::ConfigError(format!("bad repetition {{{}}}", body)));
+            }
+            *i += end;
+            range
+        }
+        _ => return Ok((1, 1)),
+    };
+    *i += 1;
+    // lazy quantifiers match the same set of strings
+    if re.get(*i) == Some(&'?') {
+        *i += 1;
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_str(pattern: &str, text: &str) -> bool {
+        let p: Vec<char> = pattern.chars().collect();
+        let t: Vec<char> = text.chars().collect();
+        glob(&p, &t)
+    }
+
+    fn find(re: &str, text: &str) -> bool {
+        let t: Vec<char> = text.chars().collect();
+        Regex::new(re).unwrap().find(&t)
+    }
+
+    #[test]
+    fn trie() {
+        let m = DomainMatcher::new(&["example.com", "*.internal.example.com"]).unwrap();
+        assert!(m.matches("example.com"));
+        assert!(m.matches("EXAMPLE.com."));
+        assert!(!m.matches("www.example.com"));
+        assert!(m.matches("db.internal.example.com"));
+        assert!(!m.matches("internal.example.com"));
+        assert!(!m.matches("example.org"));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_str("*.cdn-?.net", "img.cdn-1.net"));
+        assert!(glob_str("a*b*c", "aXXbYYc"));
+        assert!(glob_str("a*b*c", "abc"));
+        assert!(glob_str("*", ""));
+        assert!(!glob_str("a*b*c", "aXXbYY"));
+        assert!(!glob_str("?", ""));
+        assert!(!glob_str("abc", "abcd"));
+    }
+
+    #[test]
+    fn glob_pathological() {
+        let text: Vec<char> = "a".repeat(40 * 1024).chars().collect();
+        let pattern: Vec<char> = "*a".repeat(30).chars().chain(['b']).collect();
+        assert!(!glob(&pattern, &text));
+    }
+
+    #[test]
+    fn regexes() {
+        assert!(find(r"^api\d+\.example\.com$", "api12.example.com"));
+        assert!(!find(r"^api\d+\.example\.com$", "api.example.com"));
+        assert!(find("(foo|bar)baz", "xxbarbaz"));
+        assert!(find("^a{2,3}$", "aaa"));
+        assert!(!find("^a{2,3}$", "aaaa"));
+        assert!(find("^[^.]+$", "localhost"));
+        assert!(!find("^[^.]+$", "local.host"));
+        assert!(find("^(a*)*$", ""));
+        assert!(find("x?", ""));
+    }
+
+    #[test]
+    fn regex_pathological() {
+        let text: Vec<char> = "a".repeat(40 * 1024).chars().collect();
+        assert!(!Regex::new("^(a+)+$b").unwrap().find(&text));
+        assert!(!Regex::new("(a|aa)*c").unwrap().find(&text));
+    }
+
+    #[test]
+    fn bad_regexes() {
+        for re in [
+            "(a",
+            "a)",
+            "[ab",
+            "a{2",
+            "a{3,1}",
+            "a{x}",
+            "\\",
+            "(a{1000}){1000}",
+        ] {
+            assert!(Regex::new(re).is_err(), "{}", re);
+        }
+    }
+
+    #[test]
+    fn long_host() {
+        let m = DomainMatcher::new(&["regex:a"]).unwrap();
+        assert!(m.matches(&"a".repeat(253)));
+        assert!(!m.matches(&"a".repeat(254)));
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs() {
+        let lan = cidr("192.168.0.0/16");
+        assert!(lan.contains(ip("192.168.0.1")));
+        assert!(lan.contains(ip("192.168.255.255")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.1")));
+        assert!(!lan.contains(ip("fd00::1")));
+        // the host bits of the block don't matter
+        assert!(cidr(" 10.1.2.3 / 8 ").contains(ip("10.200.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("fd00::/8").contains(ip("fdff::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+        // a bare address is the host alone
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn bad_cidrs() {
+        for s in [
+            "",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "example.com/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?}", s);
+        }
+    }
+}
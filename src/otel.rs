@@ -0,0 +1,463 @@
+use crate::config::Otel;
+use crate::event::{ErrorKind, Event};
+use crate::http;
+use crate::json;
+use crate::logger;
+use crate::summary::{Stats, SUMMARY};
+use std::{
+    collections::BTreeMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    net::IpAddr,
+    sync::Mutex,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Finished spans are dropped beyond this many if the collector can't keep up,
+/// `multi3.spans.dropped` counts them.
+const MAX_PENDING: usize = 10000;
+
+/// Spans of the connections, collected by the event loop and sent by [`export`].
+pub static TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+
+/// What is known about an open connection, the phases are ended by the next event.
+struct Trace {
+    trace_id: String,
+    span_id: String,
+    client: IpAddr,
+    destination: Option<String>,
+    sni: Option<String>,
+    start: SystemTime,
+    resolved: Option<SystemTime>,
+    looked_up: Option<SystemTime>,
+    connected: Option<SystemTime>,
+    remote: Option<IpAddr>,
+    retries: usize,
+    upload: u64,
+    download: u64,
+}
+
+pub struct Tracer {
+    enabled: bool,
+    open: BTreeMap<usize, Trace>,
+    /// finished spans, already as OTLP JSON
+    pending: Vec<String>,
+    /// spans that didn't fit into `pending`
+    dropped: u64,
+}
+impl Tracer {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            open: BTreeMap::new(),
+            pending: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn update(&mut self, id: usize, event: &Event) {
+        if !self.enabled || id == 0 {
+            return;
+        }
+        let now = SystemTime::now();
+        if let Event::Received(addr) = event {
+            self.open.insert(
+                id,
+                Trace {
+                    trace_id: random_id(16),
+                    span_id: random_id(8),
+                    client: addr.ip(),
+                    destination: None,
+                    sni: None,
+                    start: now,
+                    resolved: None,
+                    looked_up: None,
+                    connected: None,
+                    remote: None,
+                    retries: 0,
+                    upload: 0,
+                    download: 0,
+                },
+            );
+            return;
+        }
+        let Some(trace) = self.open.get_mut(&id) else {
+            return;
+        };
+        match event {
+            Event::Resolved(uri) => {
+                trace.destination = Some(uri.clone());
+                trace.resolved = Some(now);
+            }
+            Event::LookedUp() => trace.looked_up = Some(now),
+            Event::Sni(name) => trace.sni = Some(name.clone()),
+            Event::Connected(_, remote) => {
+                trace.connected = Some(now);
+                trace.remote = Some(*remote);
+            }
+            Event::Retry(_) => trace.retries += 1,
+            Event::Upload(n) => trace.upload += *n as u64,
+            Event::Download(n) => trace.download += *n as u64,
+            Event::Done { .. } | Event::Error(..) | Event::RelayFailed { .. } => {
+                let mut trace = self.open.remove(&id).unwrap();
+                if let Some((bytes_up, bytes_down)) = event.totals() {
+                    trace.upload = bytes_up;
+                    trace.download = bytes_down;
+                }
+                let failure = event.failure();
+                let error = failure.as_ref().map(|(failure, e)| (*failure, e.as_ref()));
+                let spans = trace.spans(now, error);
+                if self.pending.len() < MAX_PENDING {
+                    self.pending.extend(spans);
+                } else {
+                    self.dropped += spans.len() as u64;
+                }
+            }
+            Event::Received(_) | Event::Grouped(_) | Event::PoolBindFailed(_) => {}
+        }
+    }
+}
+impl Trace {
+    /// The connection, with a child span for each phase it got to.
+    fn spans(&self, end: SystemTime, error: Option<(ErrorKind, &str)>) -> Vec<String> {
+        let mut attributes = vec![
+            attribute("client.address", &self.client.to_string()),
+            int_attribute("multi3.retries", self.retries as u64),
+            int_attribute("multi3.upload", self.upload),
+            int_attribute("multi3.download", self.download),
+        ];
+        if let Some(destination) = &self.destination {
+            attributes.push(attribute("server.address", destination));
+        }
+        if let Some(sni) = &self.sni {
+            attributes.push(attribute("tls.server.name", sni));
+        }
+        if let Some(remote) = &self.remote {
+            attributes.push(attribute("network.peer.address", &remote.to_string()));
+        }
+        if let Some((failure, _)) = error {
+            attributes.push(attribute("error.type", failure.name()));
+        }
+        let error = error.map(|(_, e)| e);
+        let mut spans = vec![self.span(
+            &self.span_id,
+            None,
+            "connection",
+            (self.start, end),
+            &attributes,
+            error,
+        )];
+        // each phase ends where the next one starts, the failing one ends with the error
+        let phases = [
+            ("dns", self.resolved, self.looked_up),
+            ("connect", self.looked_up, self.connected),
+            ("relay", self.connected, None),
+        ];
+        for (name, start, next) in phases {
+            let Some(start) = start else {
+                continue;
+            };
+            let failed = next.is_none() && error.is_some();
+            spans.push(self.span(
+                &random_id(8),
+                Some(&self.span_id),
+                name,
+                (start, next.unwrap_or(end)),
+                &[],
+                if failed { error } else { None },
+            ));
+        }
+        spans
+    }
+
+    fn span(
+        &self,
+        span_id: &str,
+        parent: Option<&str>,
+        name: &str,
+        (start, end): (SystemTime, SystemTime),
+        attributes: &[String],
+        error: Option<&str>,
+    ) -> String {
+        // SPAN_KIND_INTERNAL and SPAN_KIND_SERVER, STATUS_CODE_OK and STATUS_CODE_ERROR
+        let kind = if parent.is_some() { 1 } else { 2 };
+        let status = match error {
+            None => format!("{{{}:1}}", json::string("code")),
+            Some(e) => format!(
+                "{{{}:2,{}:{}}}",
+                json::string("code"),
+                json::string("message"),
+                json::string(e)
+            ),
+        };
+        format!(
+            "{{{}:{},{}:{},{}:{},{}:{},{}:{},{}:{},{}:{},{}:[{}],{}:{}}}",
+            json::string("traceId"),
+            json::string(&self.trace_id),
+            json::string("spanId"),
+            json::string(span_id),
+            json::string("parentSpanId"),
+            json::string(parent.unwrap_or_default()),
+            json::string("name"),
+            json::string(name),
+            json::string("kind"),
+            kind,
+            json::string("startTimeUnixNano"),
+            nanos(start),
+            json::string("endTimeUnixNano"),
+            nanos(end),
+            json::string("attributes"),
+            attributes.join(","),
+            json::string("status"),
+            status,
+        )
+    }
+}
+
+fn attribute(key: &str, value: &str) -> String {
+    format!(
+        "{{{}:{},{}:{{{}:{}}}}}",
+        json::string("key"),
+        json::string(key),
+        json::string("value"),
+        json::string("stringValue"),
+        json::string(value)
+    )
+}
+
+/// OTLP JSON wants 64 bit integers as strings.
+fn int_attribute(key: &str, value: u64) -> String {
+    format!(
+        "{{{}:{},{}:{{{}:{}}}}}",
+        json::string("key"),
+        json::string(key),
+        json::string("value"),
+        json::string("intValue"),
+        json::string(&value.to_string())
+    )
+}
+
+fn nanos(time: SystemTime) -> String {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    json::string(&nanos.to_string())
+}
+
+/// `len` random bytes as hex, good enough for trace and span ids.
+fn random_id(len: usize) -> String {
+    let mut id = String::with_capacity(len * 2);
+    while id.len() < len * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        id += &format!("{:016x}", hasher.finish());
+    }
+    id.truncate(len * 2);
+    id
+}
+
+/// Wrap spans or metrics into an OTLP request for the `multi3` service.
+fn request(resource: &str, scope: &str, items: &str, list: &[String]) -> String {
+    let scope_name = format!("{{{}:{}}}", json::string("name"), json::string("multi3"));
+    format!(
+        "{{{}:[{{{}:{{{}:[{}]}},{}:[{{{}:{},{}:[{}]}}]}}]}}",
+        json::string(resource),
+        json::string("resource"),
+        json::string("attributes"),
+        attribute("service.name", "multi3"),
+        json::string(scope),
+        json::string("scope"),
+        scope_name,
+        json::string(items),
+        list.join(",")
+    )
+}
+
+/// The counters of the summary, and the spans `dropped` so far, as OTLP metrics.
+fn metrics(stats: &Stats, start: &str, dropped: u64) -> Vec<String> {
+    let now = nanos(SystemTime::now());
+    let point = |value: u64, attributes: &[String]| {
+        format!(
+            "{{{}:{},{}:{},{}:{},{}:[{}]}}",
+            json::string("asInt"),
+            json::string(&value.to_string()),
+            json::string("startTimeUnixNano"),
+            start,
+            json::string("timeUnixNano"),
+            now,
+            json::string("attributes"),
+            attributes.join(",")
+        )
+    };
+    // AGGREGATION_TEMPORALITY_CUMULATIVE
+    let sum = |name: &str, unit: &str, points: String| {
+        format!(
+            "{{{}:{},{}:{},{}:{{{}:2,{}:true,{}:[{}]}}}}",
+            json::string("name"),
+            json::string(name),
+            json::string("unit"),
+            json::string(unit),
+            json::string("sum"),
+            json::string("aggregationTemporality"),
+            json::string("isMonotonic"),
+            json::string("dataPoints"),
+            points
+        )
+    };
+    let gauge = |name: &str, points: String| {
+        format!(
+            "{{{}:{},{}:{},{}:{{{}:[{}]}}}}",
+            json::string("name"),
+            json::string(name),
+            json::string("unit"),
+            json::string("1"),
+            json::string("gauge"),
+            json::string("dataPoints"),
+            points
+        )
+    };
+    // one series per kind of failure
+    let errors: Vec<_> = stats
+        .failures
+        .iter()
+        .map(|(failure, n)| point(*n as u64, &[attribute("error.type", failure.name())]))
+        .collect();
+    let mut metrics = vec![
+        sum(
+            "multi3.connections",
+            "1",
+            point(stats.connections as u64, &[]),
+        ),
+        sum("multi3.retries", "1", point(stats.retries as u64, &[])),
+        sum("multi3.upload", "By", point(stats.upload, &[])),
+        sum("multi3.download", "By", point(stats.download, &[])),
+        gauge("multi3.open", point(stats.open() as u64, &[])),
+        gauge("multi3.pending", point(stats.pending() as u64, &[])),
+        gauge("multi3.relays", point(crate::handle::relays() as u64, &[])),
+        sum("multi3.spans.dropped", "1", point(dropped, &[])),
+    ];
+    // a metric needs at least one data point, collectors reject the request otherwise
+    if !errors.is_empty() {
+        metrics.push(sum("multi3.errors", "1", errors.join(",")));
+    }
+    metrics
+}
+
+/// Send the finished spans and the counters to the collector every `otel.interval`.
+pub fn export(otel: &Otel) {
+    TRACER.lock().unwrap().enabled = true;
+    let start = nanos(SystemTime::now());
+    loop {
+        thread::sleep(otel.interval);
+        let (spans, dropped) = {
+            let mut tracer = TRACER.lock().unwrap();
+            (std::mem::take(&mut tracer.pending), tracer.dropped)
+        };
+        if !spans.is_empty() {
+            let body = request("resourceSpans", "scopeSpans", "spans", &spans);
+            if let Err(e) = http::post(&otel.endpoint, "/v1/traces", &body) {
+                logger::warn("failed to export traces", &[("error", &e)]);
+            }
+        }
+        let body = request(
+            "resourceMetrics",
+            "scopeMetrics",
+            "metrics",
+            &metrics(&SUMMARY.lock().unwrap(), &start, dropped),
+        );
+        if let Err(e) = http::post(&otel.endpoint, "/v1/metrics", &body) {
+            logger::warn("failed to export metrics", &[("error", &e)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracer() -> Tracer {
+        let mut tracer = Tracer::new();
+        tracer.enabled = true;
+        tracer
+    }
+
+    #[test]
+    fn failed_phase_carries_the_error() {
+        let mut tracer = tracer();
+        tracer.update(1, &Event::Received(([127, 0, 0, 1], 1000).into()));
+        tracer.update(1, &Event::Resolved("example.com:443".into()));
+        tracer.update(1, &Event::LookedUp());
+        tracer.update(
+            1,
+            &Event::Error(ErrorKind::ConnectRefused, "refused".into()),
+        );
+        assert!(tracer.open.is_empty());
+        let [connection, dns, connect] = &tracer.pending[..] else {
+            panic!("{:?}", tracer.pending);
+        };
+        assert!(connection.contains(r#""parentSpanId":"""#));
+        assert!(connection
+            .contains(r#"{"key":"error.type","value":{"stringValue":"connect_refused"}}"#));
+        assert!(connection.contains(r#""status":{"code":2,"message":"refused"}"#));
+        assert!(dns.contains(r#""name":"dns""#));
+        assert!(dns.contains(r#""status":{"code":1}"#));
+        assert!(connect.contains(r#""name":"connect""#));
+        assert!(connect.contains(r#""status":{"code":2,"message":"refused"}"#));
+    }
+
+    #[test]
+    fn dropped_spans_are_counted() {
+        let mut tracer = tracer();
+        for id in 1..=MAX_PENDING + 2 {
+            tracer.update(id, &Event::Received(([127, 0, 0, 1], 1000).into()));
+            tracer.update(id, &Event::Error(ErrorKind::Forbidden, "no".into()));
+        }
+        assert_eq!(tracer.pending.len(), MAX_PENDING);
+        assert_eq!(tracer.dropped, 2);
+        let metrics = metrics(&Stats::new(), &nanos(UNIX_EPOCH), tracer.dropped).join(",");
+        assert!(metrics.contains(r#""name":"multi3.spans.dropped""#));
+        assert!(metrics.contains(r#""dataPoints":[{"asInt":"2","#));
+    }
+
+    #[test]
+    fn no_metric_without_points() {
+        let mut stats = Stats::new();
+        let without = metrics(&stats, &nanos(UNIX_EPOCH), 0);
+        assert!(without.iter().all(|m| !m.contains(r#""dataPoints":[]"#)));
+        assert!(without.iter().all(|m| !m.contains("multi3.errors")));
+
+        stats.update(1, &Event::Received(([127, 0, 0, 1], 1000).into()));
+        stats.update(1, &Event::Error(ErrorKind::Forbidden, "no".into()));
+        let with = metrics(&stats, &nanos(UNIX_EPOCH), 0);
+        let errors = with.iter().find(|m| m.contains("multi3.errors")).unwrap();
+        assert!(errors.contains(r#""dataPoints":[{"asInt":"1","#));
+        assert!(errors.contains(r#""stringValue":"forbidden""#));
+    }
+
+    #[test]
+    fn request_wraps_the_items() {
+        let body = request(
+            "resourceSpans",
+            "scopeSpans",
+            "spans",
+            &["{}".into(), "{}".into()],
+        );
+        assert_eq!(
+            body,
+            r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"multi3"}}]},"scopeSpans":[{"scope":{"name":"multi3"},"spans":[{},{}]}]}]}"#
+        );
+        assert_eq!(
+            int_attribute("n", u64::MAX),
+            r#"{"key":"n","value":{"intValue":"18446744073709551615"}}"#
+        );
+        assert_eq!(nanos(UNIX_EPOCH), r#""0""#);
+        assert_eq!(random_id(16).len(), 32);
+        assert!(random_id(8).bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::drawer::human_bytes;
+use crate::event::{self, ErrorKind, Event};
+use crate::json;
+use crate::logger;
+use crate::summary::SUMMARY;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+/// The connections the console has no line for yet.
+static CONSOLE: Mutex<BTreeMap<usize, Connection>> = Mutex::new(BTreeMap::new());
+
+/// What the console line of a connection shows, gathered from its events.
+struct Connection {
+    start: Instant,
+    client: Option<IpAddr>,
+    destination: Option<String>,
+    /// a TLS ClientHello was seen
+    tls: bool,
+    upload: u64,
+    download: u64,
+}
+impl Connection {
+    fn protocol(&self) -> &'static str {
+        let port = self.destination.as_deref().and_then(|x| x.rsplit_once(':'));
+        match port.map(|(_, port)| port) {
+            _ if self.tls => "tls",
+            Some("443") => "https",
+            Some("80") => "http",
+            _ => "tcp",
+        }
+    }
+}
+
+/// Print the event as one JSON object on a line of its own.
+pub fn jsonl(id: usize, event: &Event) {
+    if id == 0 {
+        return;
+    }
+    // a closed pipe isn't worth a crash
+    let _ = io::stdout().lock().write_all(line(id, event).as_bytes());
+}
+
+/// The event as a JSON object, ending with a newline.
+pub fn line(id: usize, event: &Event) -> String {
+    let string = |x: &dyn ToString| json::string(&x.to_string());
+    let (name, fields): (_, Vec<(&str, String)>) = match event {
+        Event::Received(addr) => ("received", vec![("client", string(&addr.ip()))]),
+        Event::Grouped(group) => ("grouped", vec![("group", string(group))]),
+        Event::Resolved(uri) => ("resolved", vec![("destination", string(uri))]),
+        Event::LookedUp() => ("looked_up", vec![]),
+        Event::Sni(name) => ("sni", vec![("sni", string(name))]),
+        Event::Connected(bind, remote) => (
+            "connected",
+            vec![("bind", string(bind)), ("remote", string(remote))],
+        ),
+        Event::Retry(addr) => ("retry", vec![("address", string(addr))]),
+        Event::PoolBindFailed(ip) => ("pool_bind_failed", vec![("address", string(ip))]),
+        Event::Upload(n) => ("upload", vec![("bytes", n.to_string())]),
+        Event::Download(n) => ("download", vec![("bytes", n.to_string())]),
+        Event::Done {
+            bytes_up,
+            bytes_down,
+            duration,
+        } => (
+            "done",
+            vec![
+                ("bytes_up", bytes_up.to_string()),
+                ("bytes_down", bytes_down.to_string()),
+                ("duration_ms", duration.as_millis().to_string()),
+            ],
+        ),
+        Event::Error(kind, e) => (
+            "error",
+            vec![("kind", string(&kind.name())), ("error", string(e))],
+        ),
+        Event::RelayFailed {
+            upload,
+            error,
+            bytes_up,
+            bytes_down,
+            duration,
+        } => (
+            "error",
+            vec![
+                ("kind", string(&ErrorKind::IoError.name())),
+                ("error", string(error)),
+                ("direction", string(&event::direction(*upload))),
+                ("bytes_up", bytes_up.to_string()),
+                ("bytes_down", bytes_down.to_string()),
+                ("duration_ms", duration.as_millis().to_string()),
+            ],
+        ),
+    };
+    let mut line = format!(
+        "{{{}:{},{}:{},{}:{}",
+        json::string("time"),
+        json::string(&logger::timestamp()),
+        json::string("id"),
+        id,
+        json::string("event"),
+        json::string(name)
+    );
+    for (key, value) in fields {
+        line += &format!(",{}:{}", json::string(key), value);
+    }
+    line += "}\n";
+    line
+}
+
+/// Print a line for connection `id` once it is done or failed.
+pub fn console(id: usize, event: &Event) {
+    if id == 0 {
+        return;
+    }
+    let mut connections = CONSOLE.lock().unwrap();
+    let connection = connections.entry(id).or_insert_with(|| Connection {
+        start: Instant::now(),
+        client: None,
+        destination: None,
+        tls: false,
+        upload: 0,
+        download: 0,
+    });
+    let result = match event {
+        Event::Received(addr) => {
+            connection.client = Some(addr.ip());
+            return;
+        }
+        Event::Resolved(uri) => {
+            connection.destination = Some(uri.clone());
+            return;
+        }
+        Event::Sni(_) => {
+            connection.tls = true;
+            return;
+        }
+        Event::Upload(n) => {
+            connection.upload += *n as u64;
+            return;
+        }
+        Event::Download(n) => {
+            connection.download += *n as u64;
+            return;
+        }
+        Event::Done {
+            bytes_up,
+            bytes_down,
+            ..
+        } => {
+            (connection.upload, connection.download) = (*bytes_up, *bytes_down);
+            "ok".to_owned()
+        }
+        Event::RelayFailed {
+            bytes_up,
+            bytes_down,
+            ..
+        } => {
+            (connection.upload, connection.download) = (*bytes_up, *bytes_down);
+            let (kind, e) = event.failure().unwrap();
+            format!("{}: {}", kind.name(), e)
+        }
+        Event::Error(kind, e) => format!("{}: {}", kind.name(), e),
+        _ => return,
+    };
+    let Some(connection) = connections.remove(&id) else {
+        return;
+    };
+    let client = connection.client.map_or("?".to_owned(), |x| x.to_string());
+    let line = format!(
+        "{} #{} {} -> {} {} up {} down {} {:.1}s {}\n",
+        logger::timestamp(),
+        id,
+        client,
+        connection.destination.as_deref().unwrap_or("?"),
+        connection.protocol(),
+        human_bytes(connection.upload),
+        human_bytes(connection.download),
+        connection.start.elapsed().as_secs_f64(),
+        result
+    );
+    let _ = io::stdout().lock().write_all(line.as_bytes());
+}
+
+/// Print what was served so far on one line.
+pub fn console_totals() {
+    let line = {
+        let summary = SUMMARY.lock().unwrap();
+        format!(
+            "{} total {} connections, {} open, {} errors, up {} down {}\n",
+            logger::timestamp(),
+            summary.connections,
+            summary.open(),
+            summary.errors,
+            human_bytes(summary.upload),
+            human_bytes(summary.download)
+        )
+    };
+    let _ = io::stdout().lock().write_all(line.as_bytes());
+}
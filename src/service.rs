@@ -0,0 +1,304 @@
+use crate::{Builder, Result};
+use std::io::{self, Write};
+
+/// The name in the service manager and the event log.
+#[cfg(windows)]
+const NAME: &str = "multi3";
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+
+    pub type Handle = *mut c_void;
+
+    pub const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+    pub const SERVICE_STOPPED: u32 = 1;
+    pub const SERVICE_STOP_PENDING: u32 = 3;
+    pub const SERVICE_RUNNING: u32 = 4;
+    pub const SERVICE_ACCEPT_STOP: u32 = 1;
+    pub const SERVICE_ACCEPT_SHUTDOWN: u32 = 4;
+    pub const SERVICE_CONTROL_STOP: u32 = 1;
+    pub const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+    pub const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+    pub const SERVICE_AUTO_START: u32 = 2;
+    pub const SERVICE_ERROR_NORMAL: u32 = 1;
+    pub const SERVICE_QUERY_STATUS: u32 = 4;
+    pub const SC_MANAGER_CREATE_SERVICE: u32 = 2;
+    pub const NO_ERROR: u32 = 0;
+    pub const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+    pub const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+    pub const EVENTLOG_INFORMATION_TYPE: u16 = 4;
+
+    #[repr(C)]
+    pub struct ServiceStatus {
+        pub service_type: u32,
+        pub current_state: u32,
+        pub controls_accepted: u32,
+        pub win32_exit_code: u32,
+        pub service_specific_exit_code: u32,
+        pub check_point: u32,
+        pub wait_hint: u32,
+    }
+
+    #[repr(C)]
+    pub struct ServiceTableEntry {
+        pub name: *const u16,
+        pub main: Option<extern "system" fn(u32, *mut *mut u16)>,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        pub fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+        pub fn RegisterServiceCtrlHandlerExW(
+            name: *const u16,
+            handler: extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32,
+            context: *mut c_void,
+        ) -> Handle;
+        pub fn SetServiceStatus(handle: Handle, status: *const ServiceStatus) -> i32;
+        pub fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> Handle;
+        pub fn CreateServiceW(
+            manager: Handle,
+            name: *const u16,
+            display_name: *const u16,
+            access: u32,
+            service_type: u32,
+            start_type: u32,
+            error_control: u32,
+            command: *const u16,
+            load_order_group: *const u16,
+            tag_id: *mut u32,
+            dependencies: *const u16,
+            user: *const u16,
+            password: *const u16,
+        ) -> Handle;
+        pub fn CloseServiceHandle(handle: Handle) -> i32;
+        pub fn RegisterEventSourceW(server: *const u16, source: *const u16) -> Handle;
+        pub fn ReportEventW(
+            log: Handle,
+            kind: u16,
+            category: u16,
+            event_id: u32,
+            user_sid: *mut c_void,
+            num_strings: u16,
+            data_size: u32,
+            strings: *const *const u16,
+            data: *mut c_void,
+        ) -> i32;
+    }
+}
+
+/// How long stopping may take before the service manager gives up on us,
+/// enough for the connections to drain.
+#[cfg(windows)]
+const STOP_WAIT_HINT: u32 = 30_000;
+
+/// What the service thread runs, handed over by `run`.
+#[cfg(windows)]
+static BUILDER: std::sync::Mutex<Option<Builder>> = std::sync::Mutex::new(None);
+/// How the service thread ended, for `run` to return.
+#[cfg(windows)]
+static RESULT: std::sync::Mutex<Option<Result<()>>> = std::sync::Mutex::new(None);
+/// The status handle of the service, a pointer kept as a number.
+#[cfg(windows)]
+static STATUS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(windows)]
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain([0]).collect()
+}
+
+/// Run under the service manager until it stops the service.
+#[cfg(windows)]
+pub fn run(builder: Builder) -> Result<()> {
+    // services start in system32, the config is next to the executable
+    if let Some(dir) = std::env::current_exe()?.parent() {
+        std::env::set_current_dir(dir)?;
+    }
+    *BUILDER.lock().unwrap() = Some(builder.service());
+    let name = wide(NAME);
+    let table = [
+        sys::ServiceTableEntry {
+            name: name.as_ptr(),
+            main: Some(service_main),
+        },
+        sys::ServiceTableEntry {
+            name: std::ptr::null(),
+            main: None,
+        },
+    ];
+    // SAFETY: the table ends with the empty entry and outlives the call,
+    // which returns once the service stopped
+    if unsafe { sys::StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    RESULT.lock().unwrap().take().unwrap_or(Ok(()))
+}
+
+#[cfg(windows)]
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(NAME);
+    // SAFETY: `handler` is a plain function, it lives as long as the program
+    let handle =
+        unsafe { sys::RegisterServiceCtrlHandlerExW(name.as_ptr(), handler, std::ptr::null_mut()) };
+    if handle.is_null() {
+        return;
+    }
+    STATUS.store(handle as usize, std::sync::atomic::Ordering::Relaxed);
+    set_status(sys::SERVICE_RUNNING, 0);
+    let res = match BUILDER.lock().unwrap().take() {
+        Some(builder) => builder.build().and_then(|server| server.run()),
+        None => Ok(()),
+    };
+    set_status(sys::SERVICE_STOPPED, res.is_err().into());
+    *RESULT.lock().unwrap() = Some(res);
+}
+
+#[cfg(windows)]
+extern "system" fn handler(
+    control: u32,
+    _event: u32,
+    _data: *mut std::ffi::c_void,
+    _context: *mut std::ffi::c_void,
+) -> u32 {
+    match control {
+        sys::SERVICE_CONTROL_STOP | sys::SERVICE_CONTROL_SHUTDOWN => {
+            set_status(sys::SERVICE_STOP_PENDING, 0);
+            crate::stop();
+            sys::NO_ERROR
+        }
+        sys::SERVICE_CONTROL_INTERROGATE => sys::NO_ERROR,
+        _ => sys::ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Tell the service manager about `state`, `exit_code` 0 for success.
+#[cfg(windows)]
+fn set_status(state: u32, exit_code: u32) {
+    let status = sys::ServiceStatus {
+        service_type: sys::SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            sys::SERVICE_RUNNING => sys::SERVICE_ACCEPT_STOP | sys::SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        win32_exit_code: match exit_code {
+            0 => sys::NO_ERROR,
+            _ => sys::ERROR_SERVICE_SPECIFIC_ERROR,
+        },
+        service_specific_exit_code: exit_code,
+        check_point: 0,
+        wait_hint: match state {
+            sys::SERVICE_STOP_PENDING => STOP_WAIT_HINT,
+            _ => 0,
+        },
+    };
+    let handle = STATUS.load(std::sync::atomic::Ordering::Relaxed) as sys::Handle;
+    // SAFETY: the handle came from RegisterServiceCtrlHandlerExW and is never closed
+    unsafe { sys::SetServiceStatus(handle, &status) };
+}
+
+/// Register this executable as the service `multi3`, started with the system.
+#[cfg(windows)]
+pub fn install() -> io::Result<()> {
+    let command = wide(&format!(
+        "\"{}\" --service",
+        std::env::current_exe()?.display()
+    ));
+    let name = wide(NAME);
+    let null = std::ptr::null();
+    // SAFETY: the strings are null terminated and outlive the calls, both
+    // handles are closed before returning
+    unsafe {
+        let manager = sys::OpenSCManagerW(null, null, sys::SC_MANAGER_CREATE_SERVICE);
+        if manager.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let service = sys::CreateServiceW(
+            manager,
+            name.as_ptr(),
+            name.as_ptr(),
+            sys::SERVICE_QUERY_STATUS,
+            sys::SERVICE_WIN32_OWN_PROCESS,
+            sys::SERVICE_AUTO_START,
+            sys::SERVICE_ERROR_NORMAL,
+            command.as_ptr(),
+            null,
+            std::ptr::null_mut(),
+            null,
+            null,
+            null,
+        );
+        let res = match service.is_null() {
+            true => Err(io::Error::last_os_error()),
+            false => {
+                sys::CloseServiceHandle(service);
+                Ok(())
+            }
+        };
+        sys::CloseServiceHandle(manager);
+        res
+    }
+}
+
+/// Each record is reported as one event of the source `multi3`.
+#[cfg(windows)]
+pub fn event_log() -> io::Result<Box<dyn Write + Send>> {
+    struct EventLog(sys::Handle);
+    // SAFETY: an event source handle may be used from any thread
+    unsafe impl Send for EventLog {}
+    impl Write for EventLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let text = wide(String::from_utf8_lossy(buf).trim_end());
+            let strings = [text.as_ptr()];
+            // SAFETY: one null terminated string, it outlives the call
+            let res = unsafe {
+                sys::ReportEventW(
+                    self.0,
+                    sys::EVENTLOG_INFORMATION_TYPE,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null_mut(),
+                )
+            };
+            match res {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(buf.len()),
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let name = wide(NAME);
+    // SAFETY: the name is null terminated, the handle lives as long as the logger
+    let handle = unsafe { sys::RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+    match handle.is_null() {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(Box::new(EventLog(handle))),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn run(_builder: Builder) -> Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "services are only on windows").into())
+}
+
+#[cfg(not(windows))]
+pub fn install() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "services are only on windows",
+    ))
+}
+
+#[cfg(not(windows))]
+pub fn event_log() -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the event log is only on windows",
+    ))
+}
@@ -0,0 +1,43 @@
+use crate::{handle, logger, systemd};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Set by SIGINT, SIGTERM or quitting the tui, the listeners stop accepting then.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// When draining started, set on the first look after the request.
+static SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Whether the program can exit, the open connections are done or had `drain`
+/// to finish and are closed now.
+pub fn finished(drain: Duration) -> bool {
+    if !requested() {
+        return false;
+    }
+    let since = *SINCE.lock().unwrap().get_or_insert_with(|| {
+        logger::info("draining connections", &[("open", &handle::open())]);
+        systemd::notify("STOPPING=1");
+        Instant::now()
+    });
+    match handle::open() {
+        0 => true,
+        open if since.elapsed() >= drain => {
+            logger::warn("closing connections still open", &[("open", &open)]);
+            handle::kill_all();
+            true
+        }
+        _ => false,
+    }
+}
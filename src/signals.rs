@@ -0,0 +1,73 @@
+use crate::config::{self, Routing};
+use crate::{logger, summary};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often `watch` looks whether a signal came.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set by SIGHUP, the rules are read from the file again.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+/// Set by SIGUSR1, the statistics go to the log.
+static DUMP: AtomicBool = AtomicBool::new(false);
+
+/// SIGINT and SIGTERM drain the connections and quit, SIGHUP reloads the
+/// rules, SIGUSR1 logs the statistics.
+#[cfg(unix)]
+pub fn install() {
+    use std::ffi::c_int;
+    const SIGHUP: c_int = 1;
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: c_int = 10;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: c_int = 30;
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+    // only atomic stores, those are safe in a signal handler
+    extern "C" fn handler(signum: c_int) {
+        match signum {
+            SIGHUP => RELOAD.store(true, Ordering::Relaxed),
+            SIGUSR1 => DUMP.store(true, Ordering::Relaxed),
+            _ => crate::shutdown::request(),
+        }
+    }
+    // SAFETY: the handler touches nothing but atomics
+    unsafe {
+        for signum in [SIGHUP, SIGINT, SIGTERM, SIGUSR1] {
+            signal(signum, handler);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Do what the signals asked for, out of the handlers that only leave a flag.
+pub fn watch(file_name: &Path, routings: &[Arc<Routing>]) {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            match config::reload_rules(file_name, routings) {
+                Ok(()) => logger::info("reloaded rules", &[("file", &file_name.display())]),
+                Err(e) => logger::warn(
+                    "failed to reload",
+                    &[("file", &file_name.display()), ("error", &e)],
+                ),
+            }
+        }
+        if DUMP.swap(false, Ordering::Relaxed) {
+            let stats = summary::SUMMARY.lock().unwrap().to_json();
+            logger::info("statistics", &[("stats", &stats)]);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::handle::host_name;
+use crate::history::Record;
+use crate::logger;
+use std::{io, path::Path};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS connections (
+    session INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    destination TEXT,
+    host TEXT,
+    bind TEXT,
+    remote TEXT,
+    start TEXT NOT NULL,
+    duration_ms INTEGER,
+    upload INTEGER NOT NULL,
+    download INTEGER NOT NULL,
+    retries INTEGER NOT NULL,
+    result TEXT
+);
+CREATE INDEX IF NOT EXISTS connections_start ON connections (start);
+CREATE INDEX IF NOT EXISTS connections_host ON connections (host);
+CREATE INDEX IF NOT EXISTS connections_client ON connections (client);
+";
+
+/// A value bound to one `?` of a statement.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+enum Value {
+    Null,
+    Int(i64),
+    Text(String),
+}
+impl From<Option<String>> for Value {
+    fn from(x: Option<String>) -> Self {
+        x.map_or(Value::Null, Value::Text)
+    }
+}
+
+/// A SQLite database that finished connections are appended to,
+/// `session` tells the runs of multi3 apart since ids restart at 1.
+pub struct Database {
+    inner: ffi::Connection,
+    session: i64,
+}
+impl Database {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let inner = ffi::Connection::open(path)?;
+        inner.execute(SCHEMA)?;
+        let session = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        Ok(Self { inner, session })
+    }
+
+    /// Append `records` in one transaction, none of them when one fails.
+    pub fn insert(&mut self, records: &[Record]) -> io::Result<()> {
+        self.inner.execute("BEGIN")?;
+        let res = records.iter().try_for_each(|r| self.insert_one(r));
+        match res {
+            Ok(()) => self.inner.execute("COMMIT"),
+            Err(e) => {
+                let _ = self.inner.execute("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    fn insert_one(&mut self, r: &Record) -> io::Result<()> {
+        let duration = r
+            .end
+            .and_then(|end| end.duration_since(r.start).ok())
+            .map_or(Value::Null, |d| Value::Int(d.as_millis() as i64));
+        self.inner.insert(&[
+            Value::Int(self.session),
+            Value::Int(r.id as i64),
+            Value::Text(r.client.to_string()),
+            r.destination.clone().into(),
+            r.destination
+                .as_deref()
+                .map(|x| host_name(x).to_owned())
+                .into(),
+            r.bind.map(|x| x.to_string()).into(),
+            r.remote.map(|x| x.to_string()).into(),
+            Value::Text(logger::rfc3339(r.start)),
+            duration,
+            Value::Int(r.upload as i64),
+            Value::Int(r.download as i64),
+            Value::Int(r.retries as i64),
+            r.result.clone().into(),
+        ])
+    }
+}
+
+/// The few functions of the system libsqlite3 we need.
+#[cfg(feature = "sqlite")]
+mod ffi {
+    use super::Value;
+    use std::{
+        ffi::{c_char, c_int, c_void, CStr, CString},
+        io,
+        path::Path,
+        ptr,
+    };
+
+    const INSERT: &str = "INSERT INTO connections VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    const SQLITE_OK: c_int = 0;
+    const SQLITE_DONE: c_int = 101;
+    // makes sqlite copy the bound text
+    const SQLITE_TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        fn sqlite3_open(filename: *const c_char, db: *mut *mut c_void) -> c_int;
+        fn sqlite3_close(db: *mut c_void) -> c_int;
+        fn sqlite3_errmsg(db: *mut c_void) -> *const c_char;
+        fn sqlite3_exec(
+            db: *mut c_void,
+            sql: *const c_char,
+            callback: *const c_void,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        fn sqlite3_prepare_v2(
+            db: *mut c_void,
+            sql: *const c_char,
+            len: c_int,
+            stmt: *mut *mut c_void,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        fn sqlite3_bind_null(stmt: *mut c_void, i: c_int) -> c_int;
+        fn sqlite3_bind_int64(stmt: *mut c_void, i: c_int, value: i64) -> c_int;
+        fn sqlite3_bind_text(
+            stmt: *mut c_void,
+            i: c_int,
+            value: *const c_char,
+            len: c_int,
+            destructor: isize,
+        ) -> c_int;
+        fn sqlite3_step(stmt: *mut c_void) -> c_int;
+        fn sqlite3_reset(stmt: *mut c_void) -> c_int;
+        fn sqlite3_finalize(stmt: *mut c_void) -> c_int;
+    }
+
+    pub struct Connection {
+        db: *mut c_void,
+        insert: *mut c_void,
+    }
+    // only ever used by the thread writing the history
+    unsafe impl Send for Connection {}
+
+    impl Connection {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let path = CString::new(path.to_string_lossy().as_bytes())?;
+            let mut res = Self {
+                db: ptr::null_mut(),
+                insert: ptr::null_mut(),
+            };
+            // the handle is returned even on failure and has to be closed
+            if unsafe { sqlite3_open(path.as_ptr(), &mut res.db) } != SQLITE_OK {
+                return Err(res.error());
+            }
+            Ok(res)
+        }
+
+        fn error(&self) -> io::Error {
+            let msg = unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) };
+            io::Error::other(msg.to_string_lossy().into_owned())
+        }
+
+        pub fn execute(&self, sql: &str) -> io::Result<()> {
+            let sql = CString::new(sql)?;
+            let code = unsafe {
+                sqlite3_exec(
+                    self.db,
+                    sql.as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            match code {
+                SQLITE_OK => Ok(()),
+                _ => Err(self.error()),
+            }
+        }
+
+        pub fn insert(&mut self, values: &[Value]) -> io::Result<()> {
+            if self.insert.is_null() {
+                let sql = CString::new(INSERT)?;
+                let code = unsafe {
+                    sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut self.insert, ptr::null_mut())
+                };
+                if code != SQLITE_OK {
+                    return Err(self.error());
+                }
+            }
+            let stmt = self.insert;
+            for (i, value) in values.iter().enumerate() {
+                let i = i as c_int + 1;
+                let code = unsafe {
+                    match value {
+                        Value::Null => sqlite3_bind_null(stmt, i),
+                        Value::Int(x) => sqlite3_bind_int64(stmt, i, *x),
+                        Value::Text(x) => sqlite3_bind_text(
+                            stmt,
+                            i,
+                            x.as_ptr().cast(),
+                            x.len() as c_int,
+                            SQLITE_TRANSIENT,
+                        ),
+                    }
+                };
+                if code != SQLITE_OK {
+                    unsafe { sqlite3_reset(stmt) };
+                    return Err(self.error());
+                }
+            }
+            let code = unsafe { sqlite3_step(stmt) };
+            unsafe { sqlite3_reset(stmt) };
+            match code {
+                SQLITE_DONE => Ok(()),
+                _ => Err(self.error()),
+            }
+        }
+    }
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            unsafe {
+                sqlite3_finalize(self.insert);
+                sqlite3_close(self.db);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod ffi {
+    use super::Value;
+    use std::{io, path::Path};
+
+    pub enum Connection {}
+    impl Connection {
+        pub fn open(_path: &Path) -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "multi3 was built without the sqlite feature",
+            ))
+        }
+
+        pub fn execute(&self, _sql: &str) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn insert(&mut self, _values: &[Value]) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
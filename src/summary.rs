@@ -0,0 +1,538 @@
+use crate::drawer::human_bytes;
+use crate::event::{ErrorKind, Event};
+use crate::handle::host_name;
+use crate::json;
+use crate::logger;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Statistics since startup, shared by the event loop and the summary server.
+pub static SUMMARY: Mutex<Stats> = Mutex::new(Stats::new());
+/// When the listeners came up, for the uptime.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+/// When the last connection was accepted, seconds since the Unix epoch, 0 for never.
+static LAST_ACCEPT: AtomicU64 = AtomicU64::new(0);
+
+pub fn started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn accepted() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    LAST_ACCEPT.store(now.as_secs(), Ordering::Relaxed);
+}
+
+/// The answer to `GET /healthz`, 503 once shutting down so no new clients
+/// are sent this way.
+pub fn health() -> (&'static str, String) {
+    let status = match crate::shutdown::requested() {
+        false => ("200 OK", "ok"),
+        true => ("503 Service Unavailable", "draining"),
+    };
+    let uptime = STARTED.get().map_or(0, |x| x.elapsed().as_secs());
+    let last_accept = match LAST_ACCEPT.load(Ordering::Relaxed) {
+        0 => "null".to_owned(),
+        x => x.to_string(),
+    };
+    let body = format!(
+        "{{{}:{},{}:{},{}:{},{}:{}}}",
+        json::string("status"),
+        json::string(status.1),
+        json::string("uptime_s"),
+        uptime,
+        json::string("open"),
+        crate::handle::open(),
+        json::string("last_accept"),
+        last_accept,
+    );
+    (status.0, body)
+}
+
+/// Past this many destination hosts the quiet ones are counted together, the
+/// names come from the clients and could be anything.
+const MAX_HOSTS: usize = 1024;
+/// Where the folded hosts are counted, no host name has parentheses.
+const OTHER_HOSTS: &str = "(other)";
+
+pub struct Stats {
+    pub connections: usize,
+    pub done: usize,
+    pub errors: usize,
+    /// the errors by what went wrong
+    pub failures: BTreeMap<ErrorKind, usize>,
+    pub retries: usize,
+    pub upload: u64,
+    pub download: u64,
+    pub hosts: BTreeMap<String, HostStats>,
+    pub groups: BTreeMap<String, HostStats>,
+    /// by the source address the connections went out from
+    pub binds: BTreeMap<String, HostStats>,
+    open: BTreeMap<usize, Open>,
+}
+
+/// The counters that are kept across restarts.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    connections: usize,
+    done: usize,
+    errors: usize,
+    /// missing from the files of older versions
+    #[serde(default)]
+    failures: BTreeMap<ErrorKind, usize>,
+    retries: usize,
+    upload: u64,
+    download: u64,
+    hosts: BTreeMap<String, HostStats>,
+    groups: BTreeMap<String, HostStats>,
+    binds: BTreeMap<String, HostStats>,
+}
+
+/// Where the traffic of an open connection is counted besides the totals.
+#[derive(Default)]
+struct Open {
+    /// once resolved
+    host: Option<String>,
+    group: Option<String>,
+    /// once connected
+    bind: Option<String>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct HostStats {
+    pub connections: usize,
+    pub errors: usize,
+    pub upload: u64,
+    pub download: u64,
+}
+impl HostStats {
+    fn to_json(&self) -> String {
+        format!(
+            "{{{}:{},{}:{},{}:{},{}:{}}}",
+            json::string("connections"),
+            self.connections,
+            json::string("errors"),
+            self.errors,
+            json::string("upload"),
+            self.upload,
+            json::string("download"),
+            self.download,
+        )
+    }
+}
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            connections: 0,
+            done: 0,
+            errors: 0,
+            failures: BTreeMap::new(),
+            retries: 0,
+            upload: 0,
+            download: 0,
+            hosts: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            binds: BTreeMap::new(),
+            open: BTreeMap::new(),
+        }
+    }
+    /// Count for the host and the group of the connection.
+    fn account(&mut self, id: usize, f: impl Fn(&mut HostStats)) {
+        let Some(open) = self.open.get(&id) else {
+            return;
+        };
+        if let Some(host) = open.host.as_ref().and_then(|x| self.hosts.get_mut(x)) {
+            f(host);
+        }
+        if let Some(group) = open.group.as_ref().and_then(|x| self.groups.get_mut(x)) {
+            f(group);
+        }
+        if let Some(bind) = open.bind.as_ref().and_then(|x| self.binds.get_mut(x)) {
+            f(bind);
+        }
+    }
+    /// Hosts with the most traffic first.
+    pub fn top_hosts(&self, n: usize) -> Vec<(&String, &HostStats)> {
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by_key(|(_, h)| std::cmp::Reverse(h.upload + h.download));
+        hosts.truncate(n);
+        hosts
+    }
+    /// Keep the busiest half of the hosts, the others are added up as
+    /// `OTHER_HOSTS`, so there is room for new ones without growing.
+    fn fold_hosts(&mut self) {
+        let mut hosts: Vec<_> = std::mem::take(&mut self.hosts).into_iter().collect();
+        hosts.sort_by_key(|(_, h)| std::cmp::Reverse((h.upload + h.download, h.connections)));
+        let rest = hosts.split_off(hosts.len().min(MAX_HOSTS / 2));
+        self.hosts = hosts.into_iter().collect();
+        let other = self.hosts.entry(OTHER_HOSTS.to_owned()).or_default();
+        for (_, h) in rest {
+            other.connections += h.connections;
+            other.errors += h.errors;
+            other.upload += h.upload;
+            other.download += h.download;
+        }
+        // the open connections go on counting where their host went
+        for open in self.open.values_mut() {
+            if open
+                .host
+                .as_ref()
+                .is_some_and(|x| !self.hosts.contains_key(x))
+            {
+                open.host = Some(OTHER_HOSTS.to_owned());
+            }
+        }
+    }
+    pub fn open(&self) -> usize {
+        self.open.len()
+    }
+    /// Open connections by the address they went out from.
+    pub fn open_binds(&self) -> BTreeMap<&str, usize> {
+        let mut res = BTreeMap::new();
+        for bind in self.open.values().filter_map(|x| x.bind.as_deref()) {
+            *res.entry(bind).or_default() += 1;
+        }
+        res
+    }
+    /// Open connections still setting up, not relaying yet.
+    pub fn pending(&self) -> usize {
+        self.open.values().filter(|x| x.bind.is_none()).count()
+    }
+    pub fn update(&mut self, id: usize, event: &Event) {
+        if id == 0 {
+            return;
+        }
+        match event {
+            Event::Received(_) => {
+                self.connections += 1;
+                self.open.insert(id, Open::default());
+            }
+            Event::Grouped(group) => {
+                self.groups.entry(group.clone()).or_default().connections += 1;
+                if let Some(open) = self.open.get_mut(&id) {
+                    open.group = Some(group.clone());
+                }
+            }
+            Event::Resolved(uri) => {
+                let host = host_name(uri).to_owned();
+                if self.hosts.len() >= MAX_HOSTS && !self.hosts.contains_key(&host) {
+                    self.fold_hosts();
+                }
+                self.hosts.entry(host.clone()).or_default().connections += 1;
+                if let Some(open) = self.open.get_mut(&id) {
+                    open.host = Some(host);
+                }
+            }
+            Event::Done { .. } => {
+                self.done += 1;
+                self.open.remove(&id);
+            }
+            Event::Error(..) | Event::RelayFailed { .. } => {
+                let (failure, _) = event.failure().unwrap();
+                self.errors += 1;
+                *self.failures.entry(failure).or_default() += 1;
+                self.account(id, |x| x.errors += 1);
+                self.open.remove(&id);
+            }
+            Event::Retry(_) => self.retries += 1,
+            Event::Upload(n) => {
+                self.upload += *n as u64;
+                self.account(id, |x| x.upload += *n as u64);
+            }
+            Event::Download(n) => {
+                self.download += *n as u64;
+                self.account(id, |x| x.download += *n as u64);
+            }
+            Event::Connected(bind, _) => {
+                let bind = bind.to_string();
+                self.binds.entry(bind.clone()).or_default().connections += 1;
+                if let Some(open) = self.open.get_mut(&id) {
+                    open.bind = Some(bind);
+                }
+            }
+            Event::LookedUp() | Event::Sni(_) | Event::PoolBindFailed(_) => {}
+        }
+    }
+    pub fn to_json(&self) -> String {
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|(failure, n)| format!("{}:{}", json::string(failure.name()), n))
+            .collect();
+        format!(
+            "{{{}:{},{}:{},{}:{},{}:{},{}:{},{}:{},{}:{{{}}},{}:{},{}:{},{}:{}}}",
+            json::string("connections"),
+            self.connections,
+            json::string("open"),
+            self.open(),
+            json::string("pending"),
+            self.pending(),
+            json::string("relays"),
+            crate::handle::relays(),
+            json::string("done"),
+            self.done,
+            json::string("errors"),
+            self.errors,
+            json::string("failures"),
+            failures.join(","),
+            json::string("retries"),
+            self.retries,
+            json::string("upload"),
+            self.upload,
+            json::string("download"),
+            self.download,
+        )
+    }
+    /// The statistics for people, the traffic by source address below the totals.
+    pub fn to_text(&self) -> String {
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|(failure, n)| format!(", {} {}", failure.name(), n))
+            .collect();
+        let mut res = format!(
+            "connections  {} ({} open, {} pending)\n\
+             done         {}\n\
+             errors       {}{}\n\
+             retries      {}\n\
+             upload       {}\n\
+             download     {}\n",
+            self.connections,
+            self.open(),
+            self.pending(),
+            self.done,
+            self.errors,
+            failures.concat(),
+            self.retries,
+            human_bytes(self.upload),
+            human_bytes(self.download),
+        );
+        let row = |bind: &str, connections: &str, upload: &str, download: &str| {
+            format!(
+                "{:<40} {:>11} {:>10} {:>10}\n",
+                bind, connections, upload, download
+            )
+        };
+        if !self.binds.is_empty() {
+            res += "\n";
+            res += &row("source address", "connections", "upload", "download");
+        }
+        for (bind, stats) in &self.binds {
+            res += &row(
+                bind,
+                &stats.connections.to_string(),
+                &human_bytes(stats.upload),
+                &human_bytes(stats.download),
+            );
+        }
+        res
+    }
+    pub fn hosts_json(&self) -> String {
+        map_json(&self.hosts)
+    }
+    pub fn groups_json(&self) -> String {
+        map_json(&self.groups)
+    }
+    pub fn binds_json(&self) -> String {
+        map_json(&self.binds)
+    }
+
+    /// Write the counters to `path`, through a temporary file so a crash
+    /// never leaves half of it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let checkpoint = Checkpoint {
+            connections: self.connections,
+            done: self.done,
+            errors: self.errors,
+            failures: self.failures.clone(),
+            retries: self.retries,
+            upload: self.upload,
+            download: self.download,
+            hosts: self.hosts.clone(),
+            groups: self.groups.clone(),
+            binds: self.binds.clone(),
+        };
+        let text = toml::to_string(&checkpoint).map_err(io::Error::other)?;
+        let mut tmp = path.to_owned().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Continue from the counters saved in `path`, if there are any.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let checkpoint: Checkpoint = toml::from_str(&text)?;
+        self.connections = checkpoint.connections;
+        self.done = checkpoint.done;
+        self.errors = checkpoint.errors;
+        self.failures = checkpoint.failures;
+        self.retries = checkpoint.retries;
+        self.upload = checkpoint.upload;
+        self.download = checkpoint.download;
+        self.hosts = checkpoint.hosts;
+        self.groups = checkpoint.groups;
+        self.binds = checkpoint.binds;
+        if self.hosts.len() > MAX_HOSTS {
+            self.fold_hosts();
+        }
+        Ok(())
+    }
+}
+
+/// Save the statistics to `path` every `interval`.
+pub fn checkpoint(path: &Path, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        if let Err(e) = SUMMARY.lock().unwrap().save(path) {
+            logger::warn("failed to save statistics", &[("error", &e)]);
+        }
+    }
+}
+
+fn map_json(map: &BTreeMap<String, HostStats>) -> String {
+    let entries: Vec<_> = map
+        .iter()
+        .map(|(name, stats)| format!("{}:{}", json::string(name), stats.to_json()))
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
+
+/// Answer `GET /` (or `/summary`) with the statistics as JSON,
+/// `GET /hosts` with them per destination host, `GET /groups` per group
+/// and `GET /binds` per source address. `GET /healthz` is for probes.
+pub fn serve(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            logger::log(
+                logger::Level::Error,
+                "failed to bind summary",
+                &[("addr", &addr), ("error", &e)],
+            );
+            return;
+        }
+    };
+    for stream in listener.incoming().flatten() {
+        thread::spawn(move || answer(stream));
+    }
+}
+
+fn answer(mut stream: TcpStream) -> io::Result<()> {
+    let mut line = String::new();
+    read_head_line(&mut request_reader(&stream)?, &mut line)?;
+    let mut parts = line.split_ascii_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/" | "/summary")) => ("200 OK", SUMMARY.lock().unwrap().to_json()),
+        (Some("GET"), Some("/hosts")) => ("200 OK", SUMMARY.lock().unwrap().hosts_json()),
+        (Some("GET"), Some("/groups")) => ("200 OK", SUMMARY.lock().unwrap().groups_json()),
+        (Some("GET"), Some("/binds")) => ("200 OK", SUMMARY.lock().unwrap().binds_json()),
+        (Some("GET"), Some("/healthz")) => health(),
+        (Some("GET"), _) => ("404 Not Found", "{}".to_owned()),
+        _ => ("405 Method Not Allowed", "{}".to_owned()),
+    };
+    respond(&mut stream, status, &body)
+}
+
+/// How long a client of the summary or admin listener gets for its request.
+const REQUEST_TTL: Duration = Duration::from_secs(5);
+/// The most the request line and the headers may take together.
+pub const MAX_HEAD: u64 = 8192;
+
+/// Reads the request of `stream`, bounded in time and size, so a client that
+/// is slow or never ends its line only holds its own thread for a while.
+pub fn request_reader(stream: &TcpStream) -> io::Result<BufReader<io::Take<&TcpStream>>> {
+    stream.set_read_timeout(Some(REQUEST_TTL))?;
+    stream.set_write_timeout(Some(REQUEST_TTL))?;
+    Ok(BufReader::new(stream.take(MAX_HEAD)))
+}
+
+/// A line of the request head, an error when it doesn't end within the limit.
+pub fn read_head_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let n = reader.read_line(line)?;
+    if n > 0 && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request head too large",
+        ));
+    }
+    Ok(n)
+}
+
+/// Write a JSON answer and close.
+pub fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    respond_with(stream, status, "application/json", body)
+}
+
+pub fn respond_with(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_folded() {
+        let mut stats = Stats::new();
+        for id in 1..=MAX_HOSTS * 3 {
+            stats.update(id, &Event::Received(([127, 0, 0, 1], 1000).into()));
+            stats.update(id, &Event::Resolved(format!("host{}.example:443", id)));
+            stats.update(id, &Event::Download(id));
+        }
+        assert!(stats.hosts.len() <= MAX_HOSTS + 1);
+        let counted: usize = stats.hosts.values().map(|h| h.connections).sum();
+        assert_eq!(counted, MAX_HOSTS * 3);
+        let downloaded: u64 = stats.hosts.values().map(|h| h.download).sum();
+        assert_eq!(downloaded, stats.download);
+        // the busiest host is never folded
+        assert!(stats
+            .hosts
+            .contains_key(&format!("host{}.example", MAX_HOSTS * 3)));
+    }
+
+    #[test]
+    fn checkpoint_keeps_the_failures() {
+        let mut stats = Stats::new();
+        stats.update(1, &Event::Received(([127, 0, 0, 1], 1000).into()));
+        stats.update(1, &Event::Error(ErrorKind::ConnectTimeout, "".into()));
+        let path = std::env::temp_dir().join(format!("multi3-stats-{}.toml", std::process::id()));
+        stats.save(&path).unwrap();
+        let mut loaded = Stats::new();
+        loaded.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.failures, [(ErrorKind::ConnectTimeout, 1)].into());
+    }
+}
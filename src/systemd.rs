@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+};
+
+/// The first fd systemd passes, the others follow it.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listeners systemd bound for us by the address they are on, none
+/// without socket activation.
+#[cfg(unix)]
+pub fn listeners() -> HashMap<SocketAddr, TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    let ours = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok())
+        .unwrap_or(0);
+    // children started later aren't meant by them
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !ours {
+        return HashMap::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .filter_map(|fd| {
+            // SAFETY: systemd passed the fd to us and nothing else owns it
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            Some((listener.local_addr().ok()?, listener))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> HashMap<SocketAddr, TcpListener> {
+    HashMap::new()
+}
+
+/// Tell systemd about the state, `READY=1`, `RELOADING=1`, `STOPPING=1` or
+/// `WATCHDOG=1`. Nothing without `NOTIFY_SOCKET`.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    let _ = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &*path),
+    };
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// Keep the systemd watchdog fed at half its interval, when it has one.
+pub fn watchdog() {
+    let Some(usec) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+    else {
+        return;
+    };
+    let interval = std::time::Duration::from_micros(usec) / 2;
+    std::thread::spawn(move || loop {
+        notify("WATCHDOG=1");
+        std::thread::sleep(interval);
+    });
+}
@@ -0,0 +1,100 @@
+use crate::event::Event;
+use crate::logger;
+use crate::output;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One file of events per connection of the rules with `log = true`.
+pub static TIMELINES: Mutex<Timelines> = Mutex::new(Timelines::new());
+
+enum Timeline {
+    /// the rule isn't known yet, the events are kept until it is
+    Pending(Vec<String>),
+    Writing(File),
+}
+
+pub struct Timelines {
+    dir: Option<PathBuf>,
+    /// also write the bytes the relays report per frame
+    reads: bool,
+    /// connections whose rule asked for a file
+    wanted: BTreeSet<usize>,
+    open: BTreeMap<usize, Timeline>,
+}
+impl Timelines {
+    const fn new() -> Self {
+        Self {
+            dir: None,
+            reads: false,
+            wanted: BTreeSet::new(),
+            open: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_dir(&mut self, dir: PathBuf, reads: bool) {
+        self.dir = Some(dir);
+        self.reads = reads;
+    }
+
+    pub fn update(&mut self, id: usize, event: &Event) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if id == 0 {
+            return;
+        }
+        if let Event::Received(_) = event {
+            self.open.insert(id, Timeline::Pending(Vec::new()));
+        }
+        let Some(timeline) = self.open.get_mut(&id) else {
+            return;
+        };
+        if self.wanted.remove(&id) {
+            if let Timeline::Pending(lines) = timeline {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let path = dir.join(format!("connection-{}-{}.jsonl", secs, id));
+                match File::create(&path) {
+                    Ok(mut file) => {
+                        let _ = file.write_all(lines.concat().as_bytes());
+                        *timeline = Timeline::Writing(file);
+                    }
+                    Err(e) => {
+                        logger::warn("failed to create connection log", &[("error", &e)]);
+                        self.open.remove(&id);
+                        return;
+                    }
+                }
+            }
+        }
+        let done = event.ends();
+        if self.reads || !matches!(event, Event::Upload(_) | Event::Download(_)) {
+            let line = output::line(id, event);
+            match timeline {
+                Timeline::Pending(lines) => lines.push(line),
+                Timeline::Writing(file) => {
+                    let _ = file.write_all(line.as_bytes());
+                }
+            }
+        }
+        if done {
+            self.open.remove(&id);
+        }
+    }
+}
+
+/// Called by the handler once the rule of connection `id` is known.
+pub fn want(id: usize) {
+    let mut timelines = TIMELINES.lock().unwrap();
+    if timelines.dir.is_some() {
+        timelines.wanted.insert(id);
+    }
+}
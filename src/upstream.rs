@@ -0,0 +1,350 @@
+use crate::config::Pool;
+use crate::{handle::split_host_port, idn};
+use crate::{Error, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io::{self, prelude::*},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub enum Balance {
+    /// always the first healthy one
+    Failover,
+    RoundRobin,
+    LeastLatency,
+}
+impl Balance {
+    pub fn name(self) -> &'static str {
+        match self {
+            Balance::Failover => "failover",
+            Balance::RoundRobin => "round-robin",
+            Balance::LeastLatency => "least-latency",
+        }
+    }
+}
+impl FromStr for Balance {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "failover" => Ok(Balance::Failover),
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-latency" => Ok(Balance::LeastLatency),
+            _ => Err(Error::ConfigError(format!("unknown balance {}", s))),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    latency: Option<Duration>,
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+/// The upstreams of one rule, with what we learned about each of them.
+pub struct Upstreams {
+    list: Box<[Upstream]>,
+    health: Box<[Mutex<Health>]>,
+    balance: Balance,
+    start: Pool<usize>,
+}
+impl Upstreams {
+    pub fn new(list: Box<[Upstream]>, balance: Balance) -> Self {
+        Self {
+            health: list.iter().map(|_| Mutex::default()).collect(),
+            start: Pool::new((0..list.len()).collect()),
+            list,
+            balance,
+        }
+    }
+
+    /// The order to try the upstreams in for one connection,
+    /// the ones that failed recently go last.
+    pub fn order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.list.len()).collect();
+        match self.balance {
+            Balance::Failover => {}
+            Balance::RoundRobin => order.rotate_left(self.start.next().unwrap_or(0)),
+            // untried ones come first so they get measured
+            Balance::LeastLatency => {
+                order.sort_by_key(|i| self.health[*i].lock().unwrap().latency.unwrap_or_default())
+            }
+        }
+        let now = Instant::now();
+        order.sort_by_key(|i| {
+            let health = self.health[*i].lock().unwrap();
+            health.down_until.is_some_and(|t| t > now)
+        });
+        order
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    /// The addresses in the order of the config.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.list.iter().map(Upstream::addr).collect()
+    }
+
+    pub fn get(&self, i: usize) -> &Upstream {
+        &self.list[i]
+    }
+
+    pub fn succeeded(&self, i: usize, latency: Duration) {
+        let mut health = self.health[i].lock().unwrap();
+        // moving average, so one slow handshake doesn't decide everything
+        health.latency = Some(match health.latency {
+            Some(x) => (x * 3 + latency) / 4,
+            None => latency,
+        });
+        health.failures = 0;
+        health.down_until = None;
+    }
+
+    pub fn failed(&self, i: usize) {
+        let mut health = self.health[i].lock().unwrap();
+        health.failures += 1;
+        let backoff = Duration::from_secs(1 << health.failures.min(6)).min(MAX_BACKOFF);
+        health.down_until = Some(Instant::now() + backoff);
+        crate::webhook::upstream_failed(self.list[i].addr(), health.failures);
+    }
+}
+
+/// Another proxy to tunnel through, `http://host:port` or `socks5://host:port`.
+#[derive(Debug)]
+pub enum Upstream {
+    Http(SocketAddr),
+    Socks5(SocketAddr),
+}
+impl FromStr for Upstream {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || Error::ConfigError(format!("bad upstream {}", s));
+        let (scheme, addr) = s.split_once("://").ok_or_else(err)?;
+        let addr = addr.trim_end_matches('/').parse().map_err(|_| err())?;
+        match scheme {
+            "http" => Ok(Upstream::Http(addr)),
+            "socks5" => Ok(Upstream::Socks5(addr)),
+            _ => Err(err()),
+        }
+    }
+}
+impl Upstream {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Upstream::Http(addr) | Upstream::Socks5(addr) => *addr,
+        }
+    }
+
+    /// Open a tunnel to `uri` (`host:port`) through this proxy.
+    ///
+    /// A plain http request is forwarded to an http upstream as it is,
+    /// so there is nothing to negotiate in that case.
+    pub fn connect(&self, uri: &str, is_https: bool, ttl: Duration) -> io::Result<Socket> {
+        let addr = self.addr();
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.connect_timeout(&addr.into(), ttl)?;
+        socket.set_read_timeout(Some(ttl))?;
+        match self {
+            Upstream::Http(_) if is_https => http_connect(&socket, uri)?,
+            Upstream::Http(_) => {}
+            Upstream::Socks5(_) => socks5_connect(&socket, uri)?,
+        }
+        socket.set_read_timeout(None)?;
+        Ok(socket)
+    }
+}
+
+fn refused(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+fn http_connect(mut socket: &Socket, uri: &str) -> io::Result<()> {
+    write!(socket, "CONNECT {uri} HTTP/1.1\r\nHost: {uri}\r\n\r\n")?;
+    // read byte by byte so nothing after the header is swallowed
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if socket.read(&mut byte)? == 0 || head.len() > 8192 {
+            return Err(refused("bad answer from upstream"));
+        }
+        head.push(byte[0]);
+    }
+    let status = String::from_utf8_lossy(&head);
+    match status.split_ascii_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(refused("upstream refused CONNECT")),
+    }
+}
+
+fn socks5_connect(mut socket: &Socket, uri: &str) -> io::Result<()> {
+    let (host, port) = split_host_port(uri);
+    let host = &*idn::to_ascii(host);
+    let port = port
+        .and_then(|p| p.parse::<u16>().ok())
+        .ok_or_else(|| refused("bad destination"))?;
+    // no authentication
+    socket.write_all(&[5, 1, 0])?;
+    let mut answer = [0u8; 2];
+    socket.read_exact(&mut answer)?;
+    if answer != [5, 0] {
+        return Err(refused("upstream wants authentication"));
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            let host = host.as_bytes();
+            request.push(3);
+            request.push(u8::try_from(host.len()).map_err(|_| refused("host too long"))?);
+            request.extend(host);
+        }
+    }
+    request.extend(port.to_be_bytes());
+    socket.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    socket.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(refused("upstream failed to connect"));
+    }
+    // skip the bound address
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8];
+            socket.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(refused("bad answer from upstream")),
+    };
+    socket.read_exact(&mut vec![0u8; len + 2])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn upstreams() {
+        let addr: SocketAddr = "127.0.0.1:3128".parse().unwrap();
+        assert!(matches!("http://127.0.0.1:3128".parse(), Ok(Upstream::Http(x)) if x == addr));
+        assert!(matches!("socks5://127.0.0.1:3128/".parse(), Ok(Upstream::Socks5(x)) if x == addr));
+        let v6: Upstream = "http://[::1]:8080".parse().unwrap();
+        assert_eq!(v6.addr(), "[::1]:8080".parse().unwrap());
+        for s in [
+            "",
+            "127.0.0.1:3128",
+            "https://127.0.0.1:3128",
+            "http://127.0.0.1",
+            "http://127.0.0.1:65536",
+            "http://proxy.example:3128",
+            "socks5://",
+        ] {
+            assert!(s.parse::<Upstream>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn balances() {
+        for balance in [
+            Balance::Failover,
+            Balance::RoundRobin,
+            Balance::LeastLatency,
+        ] {
+            assert_eq!(
+                balance.name().parse::<Balance>().unwrap().name(),
+                balance.name()
+            );
+        }
+        assert!("random".parse::<Balance>().is_err());
+    }
+
+    #[test]
+    fn least_latency_tries_untried_first() {
+        let list: Vec<Upstream> = ["http://127.0.0.1:1", "http://127.0.0.1:2"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let upstreams = Upstreams::new(list.into(), Balance::LeastLatency);
+        upstreams.succeeded(0, Duration::from_millis(10));
+        assert_eq!(upstreams.order(), [1, 0]);
+        upstreams.succeeded(1, Duration::from_millis(50));
+        assert_eq!(upstreams.order(), [0, 1]);
+    }
+
+    /// An upstream that reads and answers each step of `script` in turn,
+    /// what it was sent comes back.
+    fn upstream(script: Vec<(usize, &'static [u8])>) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut sent = Vec::new();
+            for (len, answer) in script {
+                let mut read = vec![0; len];
+                stream.read_exact(&mut read).unwrap();
+                sent.extend(read);
+                stream.write_all(answer).unwrap();
+            }
+            sent
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn socks5_handshake() {
+        const REQUEST: &[u8] = b"\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+        let (addr, server) = upstream(vec![
+            (3, b"\x05\x00"),
+            (REQUEST.len(), b"\x05\x00\x00\x01\x7f\x00\x00\x01\x00\x50"),
+        ]);
+        Upstream::Socks5(addr)
+            .connect("example.com:443", true, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(server.join().unwrap(), [b"\x05\x01\x00", REQUEST].concat());
+
+        let (addr, _server) = upstream(vec![(3, b"\x05\xff")]);
+        let e = Upstream::Socks5(addr)
+            .connect("example.com:443", true, Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn http_handshake() {
+        const REQUEST: &[u8] = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        let (addr, server) = upstream(vec![(
+            REQUEST.len(),
+            b"HTTP/1.1 200 Connection established\r\n\r\n",
+        )]);
+        Upstream::Http(addr)
+            .connect("example.com:443", true, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(server.join().unwrap(), REQUEST);
+
+        let (addr, _server) = upstream(vec![(REQUEST.len(), b"HTTP/1.1 403 Forbidden\r\n\r\n")]);
+        let e = Upstream::Http(addr)
+            .connect("example.com:443", true, Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
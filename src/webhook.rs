@@ -0,0 +1,98 @@
+use crate::config::Webhook;
+use crate::event::Event;
+use crate::http;
+use crate::json;
+use crate::logger;
+use std::{collections::VecDeque, net::SocketAddr, sync::Mutex, thread, time::Instant};
+
+/// Watches the events for error bursts, see [`Webhook`].
+pub static NOTIFIER: Mutex<Notifier> = Mutex::new(Notifier::new());
+
+pub struct Notifier {
+    webhook: Option<&'static Webhook>,
+    /// when the errors within the window happened
+    errors: VecDeque<Instant>,
+    /// one notification per window is enough
+    quiet_until: Option<Instant>,
+}
+impl Notifier {
+    const fn new() -> Self {
+        Self {
+            webhook: None,
+            errors: VecDeque::new(),
+            quiet_until: None,
+        }
+    }
+
+    pub fn set_webhook(&mut self, webhook: &'static Webhook) {
+        self.webhook = Some(webhook);
+    }
+
+    pub fn update(&mut self, id: usize, event: &Event) {
+        let Some(webhook) = self.webhook else {
+            return;
+        };
+        if id == 0 || event.failure().is_none() {
+            return;
+        }
+        let now = Instant::now();
+        self.errors.push_back(now);
+        while self
+            .errors
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > webhook.window)
+        {
+            self.errors.pop_front();
+        }
+        if self.errors.len() < webhook.errors || self.quiet_until.is_some_and(|t| t > now) {
+            return;
+        }
+        self.quiet_until = Some(now + webhook.window);
+        send(
+            webhook,
+            "error_burst",
+            &[
+                ("errors", self.errors.len().to_string()),
+                ("window_ms", webhook.window.as_millis().to_string()),
+            ],
+        );
+    }
+}
+
+/// Tell the webhook once an upstream got taken out for failing again and again.
+pub fn upstream_failed(addr: SocketAddr, failures: u32) {
+    let Some(webhook) = NOTIFIER.lock().unwrap().webhook else {
+        return;
+    };
+    if failures == webhook.upstream_failures {
+        send(
+            webhook,
+            "upstream_down",
+            &[
+                ("upstream", addr.to_string()),
+                ("failures", failures.to_string()),
+            ],
+        );
+    }
+}
+
+/// POST `{"event": .., "time": .., fields..}` without holding up the caller.
+fn send(webhook: &'static Webhook, event: &str, fields: &[(&str, String)]) {
+    let mut body = format!(
+        "{{{}:{},{}:{}",
+        json::string("event"),
+        json::string(event),
+        json::string("time"),
+        json::string(&logger::timestamp())
+    );
+    for (key, value) in fields {
+        body += &format!(",{}:{}", json::string(key), json::string(value));
+    }
+    body += "}";
+    logger::warn("notifying", &[("event", &event)]);
+    thread::spawn(move || {
+        if let Err(e) = http::post(&webhook.url, "", &body) {
+            logger::warn("failed to notify", &[("error", &e)]);
+        }
+    });
+}
@@ -0,0 +1,75 @@
+use crate::config::Workers;
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+/// The accepted connections waiting for one of the worker threads.
+pub struct Queue<T> {
+    jobs: mpsc::SyncSender<T>,
+}
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+impl<T: Send + 'static> Queue<T> {
+    /// Start the threads running `handle` on each job, they run until the
+    /// program ends.
+    pub fn start(workers: &Workers, handle: impl Fn(T) + Send + Clone + 'static) -> Self {
+        let (jobs, rx) = mpsc::sync_channel::<T>(workers.queue);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..workers.threads {
+            let rx = rx.clone();
+            let handle = handle.clone();
+            thread::spawn(move || loop {
+                // the lock is held only while waiting, not while handling
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => handle(job),
+                    Err(_) => return,
+                }
+            });
+        }
+        Self { jobs }
+    }
+
+    /// Hand `job` to a worker, or give it back when the queue is full.
+    pub fn push(&self, job: T) -> Result<(), T> {
+        self.jobs.try_send(job).map_err(|e| match e {
+            mpsc::TrySendError::Full(job) | mpsc::TrySendError::Disconnected(job) => job,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_gives_the_job_back() {
+        let (done, rx) = mpsc::channel();
+        let (go, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let workers = Workers {
+            threads: 1,
+            queue: 1,
+        };
+        let queue = Queue::start(&workers, move |n: usize| {
+            let _ = wait.lock().unwrap().recv();
+            done.send(n).unwrap();
+        });
+        // one job runs, one waits and the third finds no room
+        queue.push(1).unwrap();
+        while queue.push(2).is_err() {
+            thread::yield_now();
+        }
+        assert_eq!(queue.push(3), Err(3));
+        go.send(()).unwrap();
+        go.send(()).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(rx.recv().unwrap(), 2);
+    }
+}